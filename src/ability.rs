@@ -0,0 +1,124 @@
+use entity::{Pos2, Vec2};
+use projectile::{ProjectileDef, OnHit};
+use status::StatusDef;
+use world::EntityId;
+
+/// What an ability has to be aimed at when it's cast.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Targeting {
+  NoTarget,
+  Point,
+  Unit,
+  Direction
+}
+
+/// What an ability does once its cast time is over.
+#[derive(Clone, Copy, Debug)]
+pub enum Effect {
+  Damage( f32 ),
+  Heal( f32 ),
+  Blink,
+  Dash( f32 ),
+  /// Puts a status on the target, or on the caster when it takes no target
+  Status( StatusDef ),
+  /// Homes in on unit targets, flies straight in the given direction
+  /// otherwise
+  Projectile( ProjectileDef )
+}
+
+impl Effect {
+  /// Whether this hurts whatever it lands on, so it may only be aimed at
+  /// enemies, everything else is for allies.
+  pub fn is_harmful( &self ) -> bool {
+    match *self {
+      Effect::Damage( _ ) => true,
+      Effect::Projectile( ref p ) => match p.on_hit {
+        OnHit::Damage( _ ) => true,
+        OnHit::Heal( _ ) => false
+      },
+      Effect::Status( ref s ) => s.status.is_harmful(),
+      Effect::Heal( _ ) | Effect::Blink | Effect::Dash( _ ) => false
+    }
+  }
+
+  /// The effect made `factor` times as strong, see `StatusDef::scaled`
+  /// for statuses. Movement stays the same.
+  pub fn scaled( &self, factor : f32 ) -> Effect {
+    match *self {
+      Effect::Damage( d ) => Effect::Damage( d * factor ),
+      Effect::Heal( h ) => Effect::Heal( h * factor ),
+      Effect::Blink => Effect::Blink,
+      Effect::Dash( d ) => Effect::Dash( d ),
+      Effect::Status( s ) => Effect::Status( s.scaled( factor ) ),
+      Effect::Projectile( p ) => {
+        let mut p = p;
+        p.on_hit = p.on_hit.scaled( factor );
+        p.status = p.status.map( |s| s.scaled( factor ) );
+        Effect::Projectile( p )
+      }
+    }
+  }
+
+  /// Whether it moves the caster, which roots prevent.
+  pub fn moves_caster( &self ) -> bool {
+    match *self {
+      Effect::Blink | Effect::Dash( _ ) => true,
+      _ => false
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct AbilityDef {
+  pub name       : String,
+  pub cost       : f32,
+  pub cooldown   : f32,
+  pub cast_range : f32,
+  pub targeting  : Targeting,
+  pub cast_time  : f32,
+  pub effect     : Effect
+}
+
+impl AbilityDef {
+  /// The ability at `rank`, the first rank is as it's defined and each one
+  /// after that makes it stronger.
+  pub fn at_rank( &self, rank : u32 ) -> AbilityDef {
+    let factor = 1.0 + RANK_POWER * ( rank.max( 1 ) - 1 ) as f32;
+    let mut def = self.clone();
+    def.effect = self.effect.scaled( factor );
+    def
+  }
+}
+
+/// What a cast was aimed at, has to match the ability's `Targeting`.
+#[derive(Clone, Copy, Debug)]
+pub enum CastTarget {
+  NoTarget,
+  Point( Pos2 ),
+  Unit( EntityId ),
+  Direction( Vec2 )
+}
+
+impl CastTarget {
+  pub fn targeting( &self ) -> Targeting {
+    match *self {
+      CastTarget::NoTarget        => Targeting::NoTarget,
+      CastTarget::Point( .. )     => Targeting::Point,
+      CastTarget::Unit( .. )      => Targeting::Unit,
+      CastTarget::Direction( .. ) => Targeting::Direction
+    }
+  }
+}
+
+/// An ability that's been started but whose cast time isn't over yet.
+#[derive(Clone, Debug)]
+pub struct Casting {
+  pub slot      : usize,
+  pub target    : CastTarget,
+  pub remaining : f32
+}
+
+/// Abilities are bound to Q, W, E and R in that order.
+pub const ABILITY_SLOTS : usize = 4;
+// How much stronger each rank past the first makes an ability
+pub const RANK_POWER : f32 = 0.25;
@@ -0,0 +1,324 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use entity::Attack;
+use ability::{AbilityDef, Targeting, Effect, ABILITY_SLOTS};
+use projectile::{ProjectileDef, OnHit};
+use status::{Status, StatusDef};
+use map::{hash_bytes, parse_f32};
+
+/// Everything about a kind of hero that doesn't change during a match.
+#[derive(Clone, Debug)]
+pub struct HeroDef {
+  // Index into the `HeroBook`, what goes over the wire
+  pub id         : u8,
+  pub name       : String,
+  pub color      : [f32; 4],
+  pub max_hp     : f32,
+  pub armor      : f32,
+  pub max_mana   : f32,
+  // Mana regained per second
+  pub mana_regen : f32,
+  pub speed      : f32,
+  pub radius     : f32,
+  // The attack every hero of this kind starts out with
+  pub attack     : Attack,
+  pub growth     : Growth,
+  // Bound to the ability slots in order
+  pub abilities  : Vec<AbilityDef>
+}
+
+/// How much a hero's stats go up with each level.
+#[derive(Clone, Copy, Debug)]
+pub struct Growth {
+  pub hp     : f32,
+  pub armor  : f32,
+  pub mana   : f32,
+  pub damage : f32
+}
+
+/// All the heroes there are to pick from. Everyone has to have the same
+/// ones, which the hash lets peers check.
+#[derive(Debug)]
+pub struct HeroBook {
+  pub heroes : Vec<Arc<HeroDef>>,
+  pub hash   : u64
+}
+
+fn parse_color( s : &str ) -> Result<[f32; 4], String> {
+  let parts = s.split( ',' ).collect::<Vec<_>>();
+
+  if parts.len() != 3 {
+    return Err( format!( "Invalid color, expected r,g,b: {}", s ) )
+  }
+
+  Ok( [ try!( parse_f32( parts[0] ) ), try!( parse_f32( parts[1] ) )
+      , try!( parse_f32( parts[2] ) ), 1.0 ] )
+}
+
+pub fn parse_targeting( s : &str ) -> Result<Targeting, String> {
+  match s {
+    "none"      => Ok( Targeting::NoTarget ),
+    "point"     => Ok( Targeting::Point ),
+    "unit"      => Ok( Targeting::Unit ),
+    "direction" => Ok( Targeting::Direction ),
+    _ => Err( format!( "Invalid targeting, expected none, point, unit or \
+                        direction: {}", s ) )
+  }
+}
+
+fn parse_on_hit( args : &[&str] ) -> Result<OnHit, String> {
+  match (args.len(), args.first().cloned()) {
+    (2, Some( "damage" )) => Ok( OnHit::Damage( try!( parse_f32( args[1] ) ) ) ),
+    (2, Some( "heal" )) => Ok( OnHit::Heal( try!( parse_f32( args[1] ) ) ) ),
+    _ => Err( format!( "Invalid hit effect: {}", args.join( " " ) ) )
+  }
+}
+
+fn parse_status( args : &[&str] ) -> Result<StatusDef, String> {
+  let (status, duration) = match (args.len(), args.first().cloned()) {
+    (2, Some( "stun" )) => ( Status::Stun, args[1] ),
+    (2, Some( "root" )) => ( Status::Root, args[1] ),
+    (3, Some( "slow" )) =>
+      ( Status::Slow( try!( parse_f32( args[1] ) ) ), args[2] ),
+    (3, Some( "burn" )) =>
+      ( Status::Burn( try!( parse_f32( args[1] ) ) ), args[2] ),
+    (3, Some( "shield" )) =>
+      ( Status::Shield( try!( parse_f32( args[1] ) ) ), args[2] ),
+    _ => return Err( format!( "Invalid status: {}", args.join( " " ) ) )
+  };
+
+  Ok( StatusDef { status : status, duration : try!( parse_f32( duration ) ) } )
+}
+
+pub fn parse_effect( args : &[&str] ) -> Result<Effect, String> {
+  match (args.len(), args.first().cloned()) {
+    (2, Some( "damage" )) => Ok( Effect::Damage( try!( parse_f32( args[1] ) ) ) ),
+    (2, Some( "heal" )) => Ok( Effect::Heal( try!( parse_f32( args[1] ) ) ) ),
+    (1, Some( "blink" )) => Ok( Effect::Blink ),
+    (2, Some( "dash" )) => Ok( Effect::Dash( try!( parse_f32( args[1] ) ) ) ),
+    (_, Some( "status" )) =>
+      Ok( Effect::Status( try!( parse_status( &args[1..] ) ) ) ),
+    (n, Some( "projectile" )) if n >= 6 => {
+      // Anything after what it does on hit is a status it puts on top
+      let status = match args.get( 6 ).cloned() {
+        None => None,
+        Some( "status" ) => Some( try!( parse_status( &args[7..] ) ) ),
+        _ => return Err( format!( "Invalid effect: {}", args.join( " " ) ) )
+      };
+
+      Ok( Effect::Projectile(
+            ProjectileDef { speed    : try!( parse_f32( args[1] ) )
+                          , radius   : try!( parse_f32( args[2] ) )
+                          , lifetime : try!( parse_f32( args[3] ) )
+                          , on_hit   : try!( parse_on_hit( &args[4..6] ) )
+                          , status   : status } ) )
+    },
+    _ => Err( format!( "Invalid effect: {}", args.join( " " ) ) )
+  }
+}
+
+fn parse_ability( args : &[&str] ) -> Result<AbilityDef, String> {
+  if args.len() < 7 {
+    return Err( "expected a name, targeting, range, cost, cooldown, cast \
+                 time and effect".to_string() )
+  }
+
+  Ok( AbilityDef { name       : args[0].to_string()
+                 , targeting  : try!( parse_targeting( args[1] ) )
+                 , cast_range : try!( parse_f32( args[2] ) )
+                 , cost       : try!( parse_f32( args[3] ) )
+                 , cooldown   : try!( parse_f32( args[4] ) )
+                 , cast_time  : try!( parse_f32( args[5] ) )
+                 , effect     : try!( parse_effect( &args[6..] ) ) } )
+}
+
+/// Reads every file with the given extension in `dir`, sorted by name so
+/// everyone numbers what's in them the same.
+pub fn read_sources( dir : &Path, extension : &str )
+  -> Result<Vec<(PathBuf, String)>, String> {
+  let read_err = |e : ::std::io::Error|
+    format!( "Couldn't read {}: {}", dir.display(), e );
+
+  let mut paths = Vec::new();
+  for entry in try!( fs::read_dir( dir ).map_err( &read_err ) ) {
+    let path = try!( entry.map_err( &read_err ) ).path();
+    if path.extension().map_or( false, |e| e == extension ) {
+      paths.push( path );
+    }
+  }
+  paths.sort();
+
+  let mut sources = Vec::new();
+  for path in paths {
+    let mut source = String::new();
+    try!( File::open( &path )
+            .and_then( |mut f| f.read_to_string( &mut source ) )
+            .map_err( |e| format!( "Couldn't read {}: {}"
+                                 , path.display(), e ) ) );
+    sources.push( ( path, source ) );
+  }
+
+  Ok( sources )
+}
+
+impl HeroDef {
+  /// Parses a hero, one definition per line with `#` starting comments:
+  ///
+  /// ```text
+  /// name <name>
+  /// color <r,g,b>
+  /// health <max hp> <armor>
+  /// mana <max mana> <regen per second>
+  /// speed <speed>
+  /// radius <radius>
+  /// attack <range> <cooldown> <damage> <projectile speed>
+  /// growth <hp> <armor> <mana> <damage>
+  /// ability <name> <targeting> <range> <cost> <cooldown> <cast time> <effect>
+  /// ```
+  ///
+  /// Growth is how much the stats go up per level, none if it's left out.
+  /// A projectile speed of 0 makes a melee attack. Targeting is `none`,
+  /// `point`, `unit` or `direction`, and the effect is one of `damage <n>`,
+  /// `heal <n>`, `blink`, `dash <distance>`, `status <status>` or
+  /// `projectile <speed> <radius> <lifetime> damage|heal <n> [status
+  /// <status>]`. A status is one of `stun <duration>`, `root <duration>`,
+  /// `slow <fraction> <duration>`, `burn <damage per second> <duration>` or
+  /// `shield <amount> <duration>`. Abilities are bound to Q, W, E and R in
+  /// the order they're listed.
+  pub fn parse( id : u8, source : &str ) -> Result<HeroDef, String> {
+    let mut def = HeroDef { id         : id
+                          , name       : String::new()
+                          , color      : [0.5, 0.5, 0.5, 1.0]
+                          , max_hp     : 0.0
+                          , armor      : 0.0
+                          , max_mana   : 0.0
+                          , mana_regen : 0.0
+                          , speed      : 0.0
+                          , radius     : 0.0
+                          , attack     : Attack::new( 0.0, 1.0, 0.0, 0.0 )
+                          , growth     : Growth { hp     : 0.0
+                                                , armor  : 0.0
+                                                , mana   : 0.0
+                                                , damage : 0.0 }
+                          , abilities  : Vec::new() };
+
+    for (n, line) in source.lines().enumerate() {
+      let line = line.split( '#' ).next().unwrap().trim();
+      let words = line.split_whitespace().collect::<Vec<_>>();
+
+      if words.is_empty() {
+        continue
+      }
+
+      let (kind, args) = ( words[0], &words[1..] );
+      let err = |e : String| format!( "line {}: {}", n + 1, e );
+      let num = |i : usize| parse_f32( args[i] ).map_err( &err );
+
+      match kind {
+        "name" if args.len() == 1 =>
+          def.name = args[0].to_string(),
+        "color" if args.len() == 1 =>
+          def.color = try!( parse_color( args[0] ).map_err( &err ) ),
+        "health" if args.len() == 2 => {
+          def.max_hp = try!( num( 0 ) );
+          def.armor = try!( num( 1 ) );
+        },
+        "mana" if args.len() == 2 => {
+          def.max_mana = try!( num( 0 ) );
+          def.mana_regen = try!( num( 1 ) );
+        },
+        "speed" if args.len() == 1 =>
+          def.speed = try!( num( 0 ) ),
+        "radius" if args.len() == 1 =>
+          def.radius = try!( num( 0 ) ),
+        "attack" if args.len() == 4 =>
+          def.attack = Attack::new( try!( num( 0 ) ), try!( num( 1 ) )
+                                  , try!( num( 2 ) ), try!( num( 3 ) ) ),
+        "growth" if args.len() == 4 =>
+          def.growth = Growth { hp     : try!( num( 0 ) )
+                              , armor  : try!( num( 1 ) )
+                              , mana   : try!( num( 2 ) )
+                              , damage : try!( num( 3 ) ) },
+        "ability" =>
+          def.abilities.push( try!( parse_ability( args ).map_err( &err ) ) ),
+        _ =>
+          return Err( err( format!( "Invalid definition: {}", line ) ) )
+      }
+    }
+
+    if def.name.is_empty() {
+      return Err( "The hero has no name".to_string() )
+    }
+
+    if def.max_hp <= 0.0 || def.speed <= 0.0 || def.radius <= 0.0 {
+      return Err( "The hero needs health, speed and a radius".to_string() )
+    }
+
+    if def.abilities.len() > ABILITY_SLOTS {
+      return Err( format!( "The hero has more than {} abilities"
+                         , ABILITY_SLOTS ) )
+    }
+
+    Ok( def )
+  }
+
+  pub fn ability( &self, slot : usize ) -> Option<&AbilityDef> {
+    self.abilities.get( slot )
+  }
+}
+
+impl HeroBook {
+  /// Loads every `.hero` file in `dir`, see `HeroDef::parse` for the
+  /// format.
+  pub fn load<P : AsRef<Path>>( dir : P ) -> Result<HeroBook, String> {
+    let dir = dir.as_ref();
+    let sources = try!( read_sources( dir, "hero" ) );
+
+    let mut heroes = Vec::new();
+    let mut all = String::new();
+
+    if sources.len() > 256 {
+      return Err( "There can't be more than 256 heroes".to_string() )
+    }
+
+    for (path, source) in sources {
+      let def = try!( HeroDef::parse( heroes.len() as u8, &source )
+                        .map_err( |e| format!( "{}: {}", path.display(), e ) ) );
+
+      if heroes.iter().any( |h : &Arc<HeroDef>| h.name == def.name ) {
+        return Err( format!( "{}: There's already a hero called {}"
+                           , path.display(), def.name ) )
+      }
+
+      all.push_str( &source );
+      heroes.push( Arc::new( def ) );
+    }
+
+    if heroes.is_empty() {
+      return Err( format!( "No heroes in {}", dir.display() ) )
+    }
+
+    Ok( HeroBook { heroes : heroes
+                 , hash   : hash_bytes( all.as_bytes() ) } )
+  }
+
+  pub fn get( &self, id : u8 ) -> Option<&Arc<HeroDef>> {
+    self.heroes.get( id as usize )
+  }
+
+  pub fn find( &self, name : &str ) -> Option<&Arc<HeroDef>> {
+    self.heroes.iter().find( |h| h.name == name )
+  }
+
+  /// The hero picked when nobody picked anything else.
+  pub fn default_hero( &self ) -> &Arc<HeroDef> {
+    &self.heroes[0]
+  }
+
+  /// The radius of the biggest hero, paths found for it fit everyone.
+  pub fn max_radius( &self ) -> f32 {
+    self.heroes.iter().fold( 0.0, |r, h| r.max( h.radius ) )
+  }
+}
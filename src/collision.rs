@@ -0,0 +1,613 @@
+use std::collections::HashMap;
+use cgmath::{Point, Vector, EuclideanVector};
+use entity::{Entity, Hitbox, Pos2, Vec2};
+use world::{World, Actor, EntityId};
+
+/// How two overlapping entities touch: `normal` points from the first one
+/// towards the second, and `depth` is how far they'd have to move apart
+/// along it to stop overlapping.
+#[derive(Clone, Copy, Debug)]
+pub struct Contact {
+  pub normal : Vec2,
+  pub depth  : f32
+}
+
+impl Contact {
+  /// The same contact seen from the other entity.
+  fn flipped( self ) -> Contact {
+    Contact { normal : self.normal.mul_s( -1.0 ), depth : self.depth }
+  }
+}
+
+const EPSILON : f32 = 1e-6;
+
+fn clamp( x : f32, lo : f32, hi : f32 ) -> f32 {
+  x.max( lo ).min( hi )
+}
+
+/// Finds the contact between two entities, if their hitboxes overlap.
+/// Entities without a hitbox never touch anything.
+pub fn contact( a : &Entity, b : &Entity ) -> Option<Contact> {
+  match (&a.hitbox, &b.hitbox) {
+    (&Hitbox::None, _) | (_, &Hitbox::None) => None,
+    (&Hitbox::Circle( ra ), &Hitbox::Circle( rb )) =>
+      circle_circle( a.pos, ra, b.pos, rb ),
+    (&Hitbox::Circle( r ), &Hitbox::Aabb( h )) =>
+      circle_aabb( a.pos, r, b.pos, h ),
+    (&Hitbox::Aabb( h ), &Hitbox::Circle( r )) =>
+      circle_aabb( b.pos, r, a.pos, h ).map( Contact::flipped ),
+    (&Hitbox::Aabb( ha ), &Hitbox::Aabb( hb )) =>
+      aabb_aabb( a.pos, ha, b.pos, hb ),
+    (&Hitbox::Circle( r ), &Hitbox::Capsule( h, rc )) =>
+      circle_circle( a.pos, r, closest_on_segment( b.pos, h, a.pos ), rc ),
+    (&Hitbox::Capsule( h, rc ), &Hitbox::Circle( r )) =>
+      circle_circle( closest_on_segment( a.pos, h, b.pos ), rc, b.pos, r ),
+    (&Hitbox::Capsule( ha, ra ), &Hitbox::Capsule( hb, rb )) => {
+      let (p, q) = closest_between_segments( a.pos, ha, b.pos, hb );
+      circle_circle( p, ra, q, rb )
+    },
+    (&Hitbox::Capsule( h, r ), &Hitbox::Aabb( hb )) =>
+      capsule_aabb( a.pos, h, r, b.pos, hb ),
+    (&Hitbox::Aabb( ha ), &Hitbox::Capsule( h, r )) =>
+      capsule_aabb( b.pos, h, r, a.pos, ha ).map( Contact::flipped )
+  }
+}
+
+fn circle_circle( pa : Pos2, ra : f32, pb : Pos2, rb : f32 )
+  -> Option<Contact> {
+  let d = pb.sub_p( &pa );
+  let dist = d.length();
+
+  if dist >= ra + rb {
+    return None
+  }
+
+  // Perfectly stacked circles have no direction between them, so just pick
+  // one every peer agrees on
+  let normal = if dist > 0.0 { d.div_s( dist ) } else { Vec2::new( 1.0, 0.0 ) };
+
+  Some( Contact { normal : normal, depth : ra + rb - dist } )
+}
+
+fn circle_aabb( c : Pos2, r : f32, pb : Pos2, h : Vec2 ) -> Option<Contact> {
+  let d = c.sub_p( &pb );
+
+  if d.x.abs() > h.x || d.y.abs() > h.y {
+    let closest = Vec2::new( clamp( d.x, -h.x, h.x ), clamp( d.y, -h.y, h.y ) );
+    let to_box = closest.sub_v( &d );
+    let dist = to_box.length();
+
+    if dist >= r {
+      return None
+    }
+
+    Some( Contact { normal : to_box.div_s( dist ), depth : r - dist } )
+  } else {
+    // The center is inside the box, so get out through the nearest side
+    let (px, py) = ( h.x - d.x.abs(), h.y - d.y.abs() );
+
+    if px < py {
+      let s = if d.x < 0.0 { 1.0 } else { -1.0 };
+      Some( Contact { normal : Vec2::new( s, 0.0 ), depth : px + r } )
+    } else {
+      let s = if d.y < 0.0 { 1.0 } else { -1.0 };
+      Some( Contact { normal : Vec2::new( 0.0, s ), depth : py + r } )
+    }
+  }
+}
+
+fn aabb_aabb( pa : Pos2, ha : Vec2, pb : Pos2, hb : Vec2 ) -> Option<Contact> {
+  let d = pb.sub_p( &pa );
+  let ox = ha.x + hb.x - d.x.abs();
+  let oy = ha.y + hb.y - d.y.abs();
+
+  if ox <= 0.0 || oy <= 0.0 {
+    return None
+  }
+
+  if ox < oy {
+    let s = if d.x < 0.0 { -1.0 } else { 1.0 };
+    Some( Contact { normal : Vec2::new( s, 0.0 ), depth : ox } )
+  } else {
+    let s = if d.y < 0.0 { -1.0 } else { 1.0 };
+    Some( Contact { normal : Vec2::new( 0.0, s ), depth : oy } )
+  }
+}
+
+/// Capsules are treated as the circle around the point of their segment
+/// that's deepest in (or closest to) the box. How deep a point is in a
+/// convex shape is convex along a line, so a ternary search finds it.
+fn capsule_aabb( c : Pos2, h : Vec2, r : f32, pb : Pos2, hb : Vec2 )
+  -> Option<Contact> {
+  let start = c.sub_v( &h );
+  let along = h.mul_s( 2.0 );
+  let depth_at = |t : f32| box_distance( start.add_v( &along.mul_s( t ) ), pb, hb );
+
+  let (mut lo, mut hi) = ( 0.0, 1.0 );
+  for _ in 0..32 {
+    let m1 = lo + ( hi - lo ) / 3.0;
+    let m2 = hi - ( hi - lo ) / 3.0;
+
+    if depth_at( m1 ) < depth_at( m2 ) {
+      hi = m2;
+    } else {
+      lo = m1;
+    }
+  }
+
+  let p = start.add_v( &along.mul_s( ( lo + hi ) / 2.0 ) );
+  circle_aabb( p, r, pb, hb )
+}
+
+/// Signed distance from `p` to the box, negative inside it.
+fn box_distance( p : Pos2, pb : Pos2, h : Vec2 ) -> f32 {
+  let d = p.sub_p( &pb );
+  let (qx, qy) = ( d.x.abs() - h.x, d.y.abs() - h.y );
+  let outside = Vec2::new( qx.max( 0.0 ), qy.max( 0.0 ) ).length();
+
+  outside + qx.max( qy ).min( 0.0 )
+}
+
+/// The point on the capsule segment centered on `c` closest to `p`.
+fn closest_on_segment( c : Pos2, h : Vec2, p : Pos2 ) -> Pos2 {
+  let start = c.sub_v( &h );
+  let along = h.mul_s( 2.0 );
+  let len2 = along.dot( &along );
+
+  if len2 <= EPSILON {
+    return c
+  }
+
+  let t = clamp( p.sub_p( &start ).dot( &along ) / len2, 0.0, 1.0 );
+  start.add_v( &along.mul_s( t ) )
+}
+
+/// The closest points between two capsule segments. Crossing segments give
+/// the same point twice, which `circle_circle` pushes apart sideways.
+fn closest_between_segments( ca : Pos2, ha : Vec2, cb : Pos2, hb : Vec2 )
+  -> (Pos2, Pos2) {
+  let (p1, d1) = ( ca.sub_v( &ha ), ha.mul_s( 2.0 ) );
+  let (p2, d2) = ( cb.sub_v( &hb ), hb.mul_s( 2.0 ) );
+  let r = p1.sub_p( &p2 );
+  let a = d1.dot( &d1 );
+  let e = d2.dot( &d2 );
+  let f = d2.dot( &r );
+
+  let (s, t) = if a <= EPSILON && e <= EPSILON {
+    ( 0.0, 0.0 )
+  } else if a <= EPSILON {
+    ( 0.0, clamp( f / e, 0.0, 1.0 ) )
+  } else {
+    let c = d1.dot( &r );
+
+    if e <= EPSILON {
+      ( clamp( -c / a, 0.0, 1.0 ), 0.0 )
+    } else {
+      let b = d1.dot( &d2 );
+      let denom = a * e - b * b;
+      let s = if denom > EPSILON {
+        clamp( ( b * f - c * e ) / denom, 0.0, 1.0 )
+      } else {
+        0.0
+      };
+      let t = ( b * s + f ) / e;
+
+      if t < 0.0 {
+        ( clamp( -c / a, 0.0, 1.0 ), 0.0 )
+      } else if t > 1.0 {
+        ( clamp( ( b - c ) / a, 0.0, 1.0 ), 1.0 )
+      } else {
+        ( s, t )
+      }
+    }
+  };
+
+  ( p1.add_v( &d1.mul_s( s ) ), p2.add_v( &d2.mul_s( t ) ) )
+}
+
+/// The radius of a circle around the entity's position that contains its
+/// whole hitbox, used to place it in the broadphase.
+pub fn bounding_radius( hitbox : &Hitbox ) -> f32 {
+  match *hitbox {
+    Hitbox::None => 0.0,
+    Hitbox::Circle( r ) => r,
+    Hitbox::Aabb( h ) => h.length(),
+    Hitbox::Capsule( h, r ) => h.length() + r
+  }
+}
+
+/// How far along a ray from `origin` in the direction `dir` (a unit vector)
+/// it first touches the entity's hitbox, if within `max_dist`. Rays
+/// starting inside a hitbox hit it right away.
+pub fn raycast( e : &Entity, origin : Pos2, dir : Vec2, max_dist : f32 )
+  -> Option<f32> {
+  let o = origin.sub_p( &e.pos );
+
+  let hit = match e.hitbox {
+    Hitbox::None => None,
+    Hitbox::Circle( r ) => ray_circle( o, dir, r ),
+    Hitbox::Aabb( h ) => ray_aabb( o, dir, h ),
+    Hitbox::Capsule( h, r ) => ray_capsule( o, dir, h, r )
+  };
+
+  hit.and_then( |t| if t <= max_dist { Some( t ) } else { None } )
+}
+
+/// Like `raycast` but along the segment from `from` to `to`, giving how far
+/// along it the hit is as a fraction between 0 and 1.
+pub fn segment_cast( e : &Entity, from : Pos2, to : Pos2 ) -> Option<f32> {
+  let d = to.sub_p( &from );
+  let len = d.length();
+
+  if len <= EPSILON {
+    return raycast( e, from, Vec2::new( 1.0, 0.0 ), 0.0 )
+  }
+
+  raycast( e, from, d.div_s( len ), len ).map( |t| t / len )
+}
+
+/// How far along the segment from `from` to `to`, as a fraction, it crosses
+/// the segment from `a` to `b`. Parallel segments never cross.
+pub fn segment_segment( from : Pos2, to : Pos2, a : Pos2, b : Pos2 )
+  -> Option<f32> {
+  let d = to.sub_p( &from );
+  let e = b.sub_p( &a );
+  let denom = d.x * e.y - d.y * e.x;
+  if denom == 0.0 {
+    return None
+  }
+
+  let w = a.sub_p( &from );
+  let t = ( w.x * e.y - w.y * e.x ) / denom;
+  let u = ( w.x * d.y - w.y * d.x ) / denom;
+
+  if t >= 0.0 && t <= 1.0 && u >= 0.0 && u <= 1.0 {
+    Some( t )
+  } else {
+    None
+  }
+}
+
+// The ray functions below take the ray's origin relative to the shape's
+// center.
+
+fn ray_circle( o : Vec2, d : Vec2, r : f32 ) -> Option<f32> {
+  let b = o.dot( &d );
+  let c = o.dot( &o ) - r * r;
+
+  if c <= 0.0 {
+    return Some( 0.0 )
+  }
+
+  let disc = b * b - c;
+  if b > 0.0 || disc < 0.0 {
+    return None
+  }
+
+  Some( -b - disc.sqrt() )
+}
+
+fn ray_aabb( o : Vec2, d : Vec2, h : Vec2 ) -> Option<f32> {
+  let mut near = 0.0f32;
+  let mut far = ::std::f32::INFINITY;
+
+  for &(o, d, h) in &[ (o.x, d.x, h.x), (o.y, d.y, h.y) ] {
+    if d.abs() <= EPSILON {
+      if o.abs() > h {
+        return None
+      }
+    } else {
+      let t1 = ( -h - o ) / d;
+      let t2 = ( h - o ) / d;
+      near = near.max( t1.min( t2 ) );
+      far = far.min( t1.max( t2 ) );
+
+      if near > far {
+        return None
+      }
+    }
+  }
+
+  Some( near )
+}
+
+/// A capsule is its two end circles plus the box between them, which is an
+/// axis-aligned box once the ray is turned into the segment's frame.
+fn ray_capsule( o : Vec2, d : Vec2, h : Vec2, r : f32 ) -> Option<f32> {
+  let ends = [ ray_circle( o.sub_v( &h ), d, r )
+             , ray_circle( o.add_v( &h ), d, r ) ];
+  let mut best = ends.iter().filter_map( |&t| t ).fold( None, min_hit );
+
+  let len = h.length();
+  if len > EPSILON {
+    let u = h.div_s( len );
+    let n = Vec2::new( -u.y, u.x );
+    let local_o = Vec2::new( o.dot( &u ), o.dot( &n ) );
+    let local_d = Vec2::new( d.dot( &u ), d.dot( &n ) );
+
+    if let Some( t ) = ray_aabb( local_o, local_d, Vec2::new( len, r ) ) {
+      best = min_hit( best, t );
+    }
+  }
+
+  best
+}
+
+/// The earlier of two hits, for folding over everything a cast hits.
+pub fn min_hit( best : Option<f32>, t : f32 ) -> Option<f32> {
+  match best {
+    Some( b ) if b <= t => Some( b ),
+    _ => Some( t )
+  }
+}
+
+pub const GRID_CELL_SIZE : f32 = 32.0;
+
+/// A uniform grid broadphase: every entity is put in the cells its bounds
+/// cover, and only entities sharing a cell are tested against each other.
+/// It's cheap to build, so it's just rebuilt every frame.
+pub struct Grid {
+  cell_size : f32,
+  cells     : HashMap<(i32, i32), Vec<EntityId>>
+}
+
+impl Grid {
+  pub fn new( cell_size : f32 ) -> Grid {
+    Grid { cell_size : cell_size, cells : HashMap::new() }
+  }
+
+  /// Builds a grid holding every actor in the world that has a hitbox.
+  pub fn build( world : &World ) -> Grid {
+    let mut grid = Grid::new( GRID_CELL_SIZE );
+
+    for (&id, actor) in world.iter() {
+      let e = actor.entity();
+      if let Hitbox::None = e.hitbox {
+        continue
+      }
+
+      grid.insert( id, e.pos, bounding_radius( &e.hitbox ) );
+    }
+
+    grid
+  }
+
+  fn cell( &self, x : f32, y : f32 ) -> (i32, i32) {
+    ( ( x / self.cell_size ).floor() as i32
+    , ( y / self.cell_size ).floor() as i32 )
+  }
+
+  fn covered( &self, pos : Pos2, radius : f32 ) -> Vec<(i32, i32)> {
+    let (x0, y0) = self.cell( pos.x - radius, pos.y - radius );
+    let (x1, y1) = self.cell( pos.x + radius, pos.y + radius );
+
+    let mut cells = Vec::new();
+    for x in x0..x1 + 1 {
+      for y in y0..y1 + 1 {
+        cells.push( (x, y) );
+      }
+    }
+
+    cells
+  }
+
+  pub fn insert( &mut self, id : EntityId, pos : Pos2, radius : f32 ) {
+    for c in self.covered( pos, radius ) {
+      self.cells.entry( c ).or_insert( Vec::new() ).push( id );
+    }
+  }
+
+  /// Everything that might be within `radius` of `pos`, sorted by ID.
+  pub fn query( &self, pos : Pos2, radius : f32 ) -> Vec<EntityId> {
+    let mut found = Vec::new();
+
+    for c in self.covered( pos, radius ) {
+      if let Some( ids ) = self.cells.get( &c ) {
+        found.extend( ids.iter().cloned() );
+      }
+    }
+
+    found.sort();
+    found.dedup();
+    found
+  }
+
+  /// Every pair of entities sharing at least one cell, each pair once with
+  /// the lower ID first, sorted so that every peer resolves them in the
+  /// same order.
+  pub fn candidate_pairs( &self ) -> Vec<(EntityId, EntityId)> {
+    let mut pairs = Vec::new();
+
+    for ids in self.cells.values() {
+      for (i, &a) in ids.iter().enumerate() {
+        for &b in &ids[i + 1..] {
+          pairs.push( if a < b { (a, b) } else { (b, a) } );
+        }
+      }
+    }
+
+    pairs.sort();
+    pairs.dedup();
+    pairs
+  }
+}
+
+/// Whether the actor takes up space that other units have to walk around.
+pub fn is_solid( actor : &Actor ) -> bool {
+  match *actor {
+    Actor::Projectile( .. ) => false,
+    _ => actor.is_targetable()
+  }
+}
+
+/// How easily the actor gets pushed around, structures don't budge at all.
+fn inverse_mass( actor : &Actor ) -> f32 {
+  match *actor {
+    Actor::Structure( .. ) => 0.0,
+    _ => 1.0
+  }
+}
+
+/// Pushes overlapping solid units apart so they don't end up standing in
+/// each other, splitting the push by how movable each one is.
+pub fn separate( world : &mut World, grid : &Grid ) {
+  for (a, b) in grid.candidate_pairs() {
+    let (c, wa, wb) = match (world.get( a ), world.get( b )) {
+      (Some( x ), Some( y )) if is_solid( x ) && is_solid( y ) =>
+        match contact( x.entity(), y.entity() ) {
+          Some( c ) => ( c, inverse_mass( x ), inverse_mass( y ) ),
+          None => continue
+        },
+      _ => continue
+    };
+
+    let total = wa + wb;
+    if total <= 0.0 {
+      continue
+    }
+
+    let push = c.normal.mul_s( c.depth / total );
+
+    if let Some( x ) = world.get_mut( a ) {
+      let e = x.entity_mut();
+      e.pos = e.pos.sub_v( &push.mul_s( wa ) );
+    }
+
+    if let Some( y ) = world.get_mut( b ) {
+      let e = y.entity_mut();
+      e.pos = e.pos.add_v( &push.mul_s( wb ) );
+    }
+  }
+}
+
+/// A projectile touching something it's allowed to hit.
+#[derive(Clone, Copy, Debug)]
+pub struct ProjectileHit {
+  pub projectile : EntityId,
+  pub target     : EntityId
+}
+
+/// Finds what every projectile hit this frame, at most one thing each, the
+/// one with the lowest ID if it touches several.
+pub fn projectile_hits( world : &World, grid : &Grid ) -> Vec<ProjectileHit> {
+  let mut hits = Vec::new();
+
+  for (&id, actor) in world.iter() {
+    let p = match *actor {
+      Actor::Projectile( ref p ) => p,
+      _ => continue
+    };
+
+    let radius = bounding_radius( &p.entity.hitbox );
+    let target = grid.query( p.entity.pos, radius )
+                     .into_iter()
+                     .find( |&other| match world.get( other ) {
+                       Some( a ) =>
+                         a.is_targetable() && p.can_hit( other, a.team() )
+                         && contact( &p.entity, a.entity() ).is_some(),
+                       None => false
+                     } );
+
+    if let Some( t ) = target {
+      hits.push( ProjectileHit { projectile : id, target : t } );
+    }
+  }
+
+  hits
+}
+
+#[cfg(test)]
+mod tests {
+  use cgmath::{Point, Vector};
+  use entity::{Entity, Hitbox, Pos2, Vec2};
+  use super::{contact, segment_cast, segment_segment};
+
+  fn entity( x : f32, y : f32, hitbox : Hitbox ) -> Entity {
+    Entity { pos    : Pos2::new( x, y )
+           , vel    : Vec2::new( 0.0, 0.0 )
+           , hitbox : hitbox }
+  }
+
+  fn close( a : f32, b : f32 ) -> bool {
+    ( a - b ).abs() < 1e-3
+  }
+
+  /// Checks the contact between `a` and `b`, and that moving `b` out along
+  /// it gets them apart.
+  fn check( a : &Entity, b : &Entity, normal : (f32, f32), depth : f32 ) {
+    let c = contact( a, b ).expect( "the entities should overlap" );
+    assert!( close( c.normal.x, normal.0 ) && close( c.normal.y, normal.1 )
+           , "normal {:?}", c.normal );
+    assert!( close( c.depth, depth ), "depth {}", c.depth );
+
+    let mut moved = b.clone();
+    moved.pos = moved.pos.add_v( &c.normal.mul_s( c.depth + 0.01 ) );
+    assert!( contact( a, &moved ).is_none() );
+  }
+
+  #[test]
+  fn circles() {
+    let a = entity( 0.0, 0.0, Hitbox::Circle( 5.0 ) );
+    check( &a, &entity( 8.0, 0.0, Hitbox::Circle( 5.0 ) ), (1.0, 0.0), 2.0 );
+    assert!( contact( &a, &entity( 10.0, 0.0, Hitbox::Circle( 5.0 ) ) )
+               .is_none() );
+  }
+
+  #[test]
+  fn circle_and_box() {
+    let a = entity( 0.0, 0.0, Hitbox::Circle( 5.0 ) );
+    let half = Vec2::new( 3.0, 3.0 );
+    check( &a, &entity( 7.0, 0.0, Hitbox::Aabb( half ) ), (1.0, 0.0), 1.0 );
+    // The center inside the box
+    check( &a, &entity( 0.5, 0.0, Hitbox::Aabb( half ) ), (1.0, 0.0), 7.5 );
+    assert!( contact( &a, &entity( 9.0, 0.0, Hitbox::Aabb( half ) ) )
+               .is_none() );
+
+    // The other way around the normal flips
+    let b = entity( 7.0, 0.0, Hitbox::Aabb( half ) );
+    check( &b, &a, (-1.0, 0.0), 1.0 );
+  }
+
+  #[test]
+  fn boxes() {
+    let half = Vec2::new( 2.0, 2.0 );
+    let a = entity( 0.0, 0.0, Hitbox::Aabb( half ) );
+    check( &a, &entity( 0.0, 3.0, Hitbox::Aabb( half ) ), (0.0, 1.0), 1.0 );
+    assert!( contact( &a, &entity( 4.0, 0.0, Hitbox::Aabb( half ) ) )
+               .is_none() );
+  }
+
+  #[test]
+  fn capsules() {
+    let capsule = Hitbox::Capsule( Vec2::new( 5.0, 0.0 ), 1.0 );
+    let a = entity( 0.0, 0.0, capsule.clone() );
+
+    check( &a, &entity( 4.0, 1.5, Hitbox::Circle( 1.0 ) ), (0.0, 1.0), 0.5 );
+    check( &a, &entity( 2.0, 1.5, capsule.clone() ), (0.0, 1.0), 0.5 );
+    check( &a, &entity( 0.0, 1.5, Hitbox::Aabb( Vec2::new( 10.0, 1.0 ) ) )
+         , (0.0, 1.0), 0.5 );
+    assert!( contact( &a, &entity( 0.0, 2.5
+                                 , Hitbox::Aabb( Vec2::new( 10.0, 1.0 ) ) ) )
+               .is_none() );
+  }
+
+  #[test]
+  fn no_hitbox_touches_nothing() {
+    let a = entity( 0.0, 0.0, Hitbox::None );
+    assert!( contact( &a, &entity( 0.0, 0.0, Hitbox::Circle( 5.0 ) ) )
+               .is_none() );
+  }
+
+  #[test]
+  fn casts() {
+    let e = entity( 10.0, 0.0, Hitbox::Circle( 2.0 ) );
+    let t = segment_cast( &e, Pos2::new( 0.0, 0.0 ), Pos2::new( 20.0, 0.0 ) );
+    assert!( t.map_or( false, |t| close( t, 0.4 ) ) );
+    assert!( segment_cast( &e, Pos2::new( 0.0, 5.0 ), Pos2::new( 20.0, 5.0 ) )
+               .is_none() );
+
+    let t = segment_segment( Pos2::new( 0.0, 0.0 ), Pos2::new( 10.0, 0.0 )
+                           , Pos2::new( 5.0, -1.0 ), Pos2::new( 5.0, 1.0 ) );
+    assert!( t.map_or( false, |t| close( t, 0.5 ) ) );
+  }
+}
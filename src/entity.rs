@@ -1,18 +1,31 @@
+use std::sync::Arc;
 use cgmath;
 use cgmath::{Vector2, Point2, Point, Vector, EuclideanVector, FixedArray};
-use packet::InstructionPacket;
-use rand::{thread_rng, Rng};
+use packet::{InstructionPacket, Order};
+use ability::{AbilityDef, Casting, CastTarget, ABILITY_SLOTS};
+use archetype::HeroDef;
+use status::Statuses;
+use item::{Inventory, ItemDef, Bonus, SELL_FRACTION};
+use progress::{self, MAX_LEVEL};
+use world::EntityId;
+use team::Team;
 
 pub type Vec2 = cgmath::Vector2<f32>;
 pub type Pos2 = cgmath::Point2<f32>;
 
-#[derive(Clone)]
+/// The shape an entity takes up, centered on its position.
+#[derive(Clone, Debug)]
 pub enum Hitbox {
   None,
-  Circle( f32 )
+  Circle( f32 ),
+  /// An axis-aligned box, given by its half extents
+  Aabb( Vec2 ),
+  /// A line segment with a radius around it, given by the offset from the
+  /// center to one end of the segment
+  Capsule( Vec2, f32 )
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Entity {
   pub pos    : Pos2,
   pub vel    : Vec2,
@@ -27,41 +40,719 @@ impl Entity {
   }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
+pub struct Health {
+  pub hp     : f32,
+  pub max_hp : f32,
+  pub armor  : f32
+}
+
+impl Health {
+  pub fn new( max_hp : f32, armor : f32 ) -> Health {
+    Health { hp : max_hp, max_hp : max_hp, armor : armor }
+  }
+
+  /// How much of `amount` gets through the armor, each point of armor is
+  /// worth an extra 1% of effective health.
+  pub fn mitigate( &self, amount : f32 ) -> f32 {
+    amount * 100.0 / ( 100.0 + self.armor.max( 0.0 ) )
+  }
+
+  /// Applies `amount` of damage before armor and returns how much was
+  /// actually taken.
+  pub fn take_damage( &mut self, amount : f32 ) -> f32 {
+    let dealt = self.mitigate( amount ).min( self.hp );
+    self.hp -= dealt;
+    dealt
+  }
+
+  pub fn is_depleted( &self ) -> bool {
+    self.hp <= 0.0
+  }
+
+  pub fn restore( &mut self ) {
+    self.hp = self.max_hp;
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LifeState {
+  Alive,
+  Dead { respawn_in : f32 }
+}
+
+/// Auto-attack stats, and who's being attacked.
+#[derive(Clone, Debug)]
+pub struct Attack {
+  pub range    : f32,
+  pub cooldown : f32,
+  pub damage   : f32,
+  pub timer    : f32,
+  pub target   : Option<EntityId>,
+  // Zero for melee attacks that hit right away
+  pub projectile_speed : f32,
+  // How much longer the last attack is drawn for, purely visual
+  pub flash    : f32
+}
+
+pub const ATTACK_FLASH_TIME : f32 = 0.1;
+
+impl Attack {
+  pub fn new( range : f32, cooldown : f32, damage : f32
+            , projectile_speed : f32 ) -> Attack {
+    Attack { range            : range
+           , cooldown         : cooldown
+           , damage           : damage
+           , timer            : 0.0
+           , target           : None
+           , projectile_speed : projectile_speed
+           , flash            : 0.0 }
+  }
+
+  pub fn is_ranged( &self ) -> bool {
+    self.projectile_speed > 0.0
+  }
+
+  pub fn update( &mut self, delta_time : f64 ) {
+    self.timer = ( self.timer - delta_time as f32 ).max( 0.0 );
+    self.flash = ( self.flash - delta_time as f32 ).max( 0.0 );
+  }
+
+  /// Attacks if the cooldown is up, returning whether it did.
+  pub fn try_fire( &mut self ) -> bool {
+    if self.timer > 0.0 {
+      return false
+    }
+
+    self.timer = self.cooldown;
+    self.flash = ATTACK_FLASH_TIME;
+    true
+  }
+
+  /// Chases the target at `target_pos` from `pos` by setting `move_to`
+  /// until it's in range, then attacks it whenever the cooldown allows.
+  /// Returns whether it attacked.
+  pub fn pursue( &mut self, pos : Pos2, target_pos : Pos2
+               , move_to : &mut Option<Pos2> ) -> bool {
+    if pos.sub_p( &target_pos ).length() > self.range {
+      *move_to = Some( target_pos );
+      false
+    } else {
+      *move_to = None;
+      self.try_fire()
+    }
+  }
+}
+
+pub const MINION_MAX_HP : f32 = 200.0;
+pub const MINION_ARMOR : f32 = 0.0;
+pub const MINION_RADIUS : f32 = 4.0;
+pub const MINION_SPEED : f32 = 60.0;
+// How close enemies have to come before a minion goes after them
+pub const MINION_AGGRO_RANGE : f32 = 100.0;
+// How far a minion chases something before giving up on it
+pub const MINION_LEASH_RANGE : f32 = 160.0;
+pub const TOWER_MAX_HP : f32 = 2000.0;
+pub const TOWER_ARMOR : f32 = 50.0;
+pub const TOWER_HALF_SIZE : f32 = 12.0;
+pub const BARRACKS_MAX_HP : f32 = 1500.0;
+pub const BARRACKS_ARMOR : f32 = 30.0;
+pub const BARRACKS_HALF_SIZE : f32 = 14.0;
+pub const CORE_MAX_HP : f32 = 3000.0;
+pub const CORE_ARMOR : f32 = 40.0;
+pub const CORE_HALF_SIZE : f32 = 18.0;
+
+/// A lane creep.
+#[derive(Clone, Debug)]
+pub struct Minion {
+  pub entity     : Entity,
+  pub team       : Team,
+  pub health     : Health,
+  pub attack     : Attack,
+  pub statuses   : Statuses,
+  pub target_pos : Option<Pos2>,
+  // What's left of the lane to walk, only known to the host
+  pub waypoints  : Vec<Pos2>
+}
+
+impl Minion {
+  pub fn new( sp : Pos2, team : Team ) -> Minion {
+    Minion { entity     : Entity { pos    : sp
+                                 , vel    : Vec2::new( 0.0, 0.0 )
+                                 , hitbox : Hitbox::Circle( MINION_RADIUS ) }
+           , team       : team
+           , health     : Health::new( MINION_MAX_HP, MINION_ARMOR )
+           , attack     : Attack::new( 40.0, 1.0, 15.0, 0.0 )
+           , statuses   : Statuses::new()
+           , target_pos : None
+           , waypoints  : Vec::new() }
+  }
+
+  /// Heads for the next lane waypoint, moving on to the one after once
+  /// it's close enough.
+  pub fn follow_lane( &mut self ) {
+    while let Some( &next ) = self.waypoints.first() {
+      if self.entity.pos.sub_p( &next ).length() < MINION_RADIUS {
+        self.waypoints.remove( 0 );
+      } else {
+        break
+      }
+    }
+
+    self.target_pos = self.waypoints.first().cloned();
+  }
+
+  pub fn pursue( &mut self, target_pos : Pos2 ) -> bool {
+    self.attack.pursue( self.entity.pos, target_pos, &mut self.target_pos )
+  }
+
+  pub fn update( &mut self, delta_time : f64 ) {
+    self.attack.update( delta_time );
+
+    if !self.statuses.can_move() {
+      self.entity.vel = Vec2::new( 0.0, 0.0 );
+    } else if let Some( dest ) = self.target_pos {
+      if self.entity.pos.sub_p( &dest ).length() > 1.0 {
+        self.entity.vel = dest.sub_p( &self.entity.pos )
+                              .normalize_to( MINION_SPEED
+                                             * self.statuses.speed_factor() );
+      }
+    }
+
+    self.entity.update( delta_time );
+  }
+}
+
+/// The kinds of buildings a team has. Towers shoot at enemies, barracks
+/// keep the core safe while they stand, and losing the core loses the match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StructureKind {
+  Tower,
+  Barracks,
+  Core
+}
+
+pub const STRUCTURE_KINDS : [StructureKind; 3]
+  = [ StructureKind::Tower, StructureKind::Barracks, StructureKind::Core ];
+
+impl StructureKind {
+  pub fn index( self ) -> u8 {
+    match self {
+      StructureKind::Tower    => 0,
+      StructureKind::Barracks => 1,
+      StructureKind::Core     => 2
+    }
+  }
+
+  pub fn from_index( i : u8 ) -> Option<StructureKind> {
+    STRUCTURE_KINDS.get( i as usize ).cloned()
+  }
+}
+
+/// A static building belonging to one of the teams.
+#[derive(Clone, Debug)]
+pub struct Structure {
+  pub entity : Entity,
+  pub team   : Team,
+  pub kind   : StructureKind,
+  pub health : Health,
+  // Only towers fight back
+  pub attack : Option<Attack>
+}
+
+impl Structure {
+  pub fn new( kind : StructureKind, pos : Pos2, team : Team ) -> Structure {
+    let (half_size, max_hp, armor, attack) = match kind {
+      StructureKind::Tower =>
+        ( TOWER_HALF_SIZE, TOWER_MAX_HP, TOWER_ARMOR
+        , Some( Attack::new( 180.0, 1.5, 90.0, 350.0 ) ) ),
+      StructureKind::Barracks =>
+        ( BARRACKS_HALF_SIZE, BARRACKS_MAX_HP, BARRACKS_ARMOR, None ),
+      StructureKind::Core =>
+        ( CORE_HALF_SIZE, CORE_MAX_HP, CORE_ARMOR, None )
+    };
+
+    Structure { entity : Entity { pos    : pos
+                                , vel    : Vec2::new( 0.0, 0.0 )
+                                , hitbox : Hitbox::Aabb(
+                                            Vec2::new( half_size, half_size ) ) }
+              , team   : team
+              , kind   : kind
+              , health : Health::new( max_hp, armor )
+              , attack : attack }
+  }
+
+  /// Shoots at the target at `target_pos` if it's in range, structures
+  /// can't go after anything. Returns whether it attacked.
+  pub fn pursue( &mut self, target_pos : Pos2 ) -> bool {
+    let pos = self.entity.pos;
+
+    match self.attack {
+      Some( ref mut a ) if pos.sub_p( &target_pos ).length() <= a.range =>
+        a.try_fire(),
+      _ => false
+    }
+  }
+
+  pub fn update( &mut self, delta_time : f64 ) {
+    if let Some( ref mut a ) = self.attack {
+      a.update( delta_time );
+    }
+  }
+}
+
+pub const RESPAWN_TIME : f32 = 5.0;
+// Higher level heroes stay dead longer
+pub const RESPAWN_TIME_PER_LEVEL : f32 = 2.0;
+
+/// How long a hero of the given level stays dead.
+pub fn respawn_time( level : u32 ) -> f32 {
+  RESPAWN_TIME + RESPAWN_TIME_PER_LEVEL * ( level.max( 1 ) - 1 ) as f32
+}
+
+#[derive(Clone, Debug)]
 pub struct Hero {
+  // What kind of hero this is, only its ID gets synced
+  pub def        : Arc<HeroDef>,
   pub entity     : Entity,
-  pub color      : [f32; 4],
-  pub target_pos : Option<Pos2>
+  pub team       : Team,
+  pub target_pos : Option<Pos2>,
+  pub health     : Health,
+  pub life       : LifeState,
+  pub attack     : Attack,
+  pub mana       : f32,
+  pub cooldowns  : [f32; ABILITY_SLOTS],
+  pub statuses   : Statuses,
+  pub level      : u32,
+  // Towards the next level
+  pub xp         : u32,
+  pub gold       : u32,
+  pub inventory  : Inventory,
+  // What the items add up to, which everyone gets to know even when the
+  // items themselves are only sent to the hero's own player
+  pub bonus      : Bonus,
+  // Left to spend on ranking up abilities
+  pub ability_points : u32,
+  // Unranked abilities can't be cast yet
+  pub ranks      : [u32; ABILITY_SLOTS],
+  pub casting    : Option<Casting>,
+  // Waypoints leading to `target_pos`, worked out locally and never synced
+  pub path       : Vec<Pos2>,
+  // The `target_pos` the path was found for
+  pub path_goal  : Option<Pos2>,
+  // How long the hero's been trying to move without getting anywhere
+  pub stuck_for  : f32,
+  // How long since the last sync, limits how far the host lets a client
+  // move its hero in one
+  pub since_sync : f32
 }
 
 impl Hero {
-  pub fn new( sp : Pos2 ) -> Hero {
-    let mut r = thread_rng();
-    // Generate the color
-    let c = [ r.gen_range( 0.0, 1.0 ), r.gen_range( 0.0, 1.0 )
-            , r.gen_range( 0.0, 1.0 ), 1.0 ];
-
+  pub fn new( def : Arc<HeroDef>, sp : Pos2, team : Team ) -> Hero {
     Hero { entity     : Entity { pos   : sp
                                , vel   : Vec2::new( 0.0, 0.0 )
-                               , hitbox: Hitbox::None }
-         , color      : c
-         , target_pos : None }
+                               , hitbox: Hitbox::Circle( def.radius ) }
+         , team       : team
+         , target_pos : None
+         , health     : Health::new( def.max_hp, def.armor )
+         , life       : LifeState::Alive
+         , attack     : def.attack.clone()
+         , mana       : def.max_mana
+         , def        : def
+         , cooldowns  : [0.0; ABILITY_SLOTS]
+         , statuses   : Statuses::new()
+         , level      : 1
+         , xp         : 0
+         , gold       : 0
+         , inventory  : Inventory::new()
+         , bonus      : Bonus::none()
+         , ability_points : 1
+         , ranks      : [0; ABILITY_SLOTS]
+         , casting    : None
+         , path       : Vec::new()
+         , path_goal  : None
+         , stuck_for  : 0.0
+         , since_sync : 0.0 }
+  }
+
+  /// Takes the state the host is in charge of from `host`, so that a
+  /// client's sync of its own hero can't overrule it. The client does get
+  /// to move it, but no further than its speed allows since the last sync
+  /// and not at all while it's dead, stunned or rooted.
+  pub fn take_host_state( &mut self, host : &Hero ) {
+    let reach = host.speed() * host.since_sync;
+    let offset = self.entity.pos.sub_p( &host.entity.pos );
+    let (pos, vel) = ( self.entity.pos, self.entity.vel );
+    let finite = pos.x.is_finite() && pos.y.is_finite()
+                 && vel.x.is_finite() && vel.y.is_finite();
+
+    if !finite || !host.is_alive() || !host.statuses.can_move() {
+      self.entity.pos = host.entity.pos;
+      self.entity.vel = host.entity.vel;
+    } else {
+      if offset.length() > reach {
+        self.entity.pos = host.entity.pos
+                              .add_v( &offset.normalize_to( reach ) );
+      }
+      if self.entity.vel.length() > host.speed() {
+        self.entity.vel = self.entity.vel.normalize_to( host.speed() );
+      }
+    }
+
+    self.since_sync = 0.0;
+
+    self.def = host.def.clone();
+    self.entity.hitbox = host.entity.hitbox.clone();
+    self.team = host.team;
+    self.health = host.health.clone();
+    self.life = host.life.clone();
+    self.mana = host.mana;
+    self.cooldowns = host.cooldowns;
+    self.statuses = host.statuses.clone();
+    self.inventory = host.inventory.clone();
+    self.bonus = host.bonus;
+    self.set_level( host.level );
+    self.xp = host.xp;
+    self.gold = host.gold;
+    self.ability_points = host.ability_points;
+    self.ranks = host.ranks;
+    self.casting = host.casting.clone();
+  }
+
+  /// Forgets what only the hero's own player gets to know: its gold,
+  /// experience and items. What the items add up to stays.
+  pub fn forget_private( &mut self ) {
+    self.xp = 0;
+    self.gold = 0;
+    self.inventory = Inventory::new();
+  }
+
+  /// The ability in `slot` at the rank the hero has it, `None` if it
+  /// hasn't learned it.
+  pub fn ability( &self, slot : usize ) -> Option<AbilityDef> {
+    match self.def.ability( slot ) {
+      Some( a ) if self.ranks[slot] > 0 =>
+        Some( a.at_rank( self.ranks[slot] ) ),
+      _ => None
+    }
+  }
+
+  pub fn max_mana( &self ) -> f32 {
+    self.def.max_mana + self.def.growth.mana * ( self.level - 1 ) as f32
+      + self.bonus.mana
+  }
+
+  /// Sets the level and the stats that follow from it and the item bonus,
+  /// leaving health and mana as they are.
+  pub fn set_level( &mut self, level : u32 ) {
+    let grown = ( level.max( 1 ) - 1 ) as f32;
+    let growth = self.def.growth;
+    let bonus = self.bonus;
+
+    self.level = level.max( 1 );
+    self.health.max_hp = self.def.max_hp + growth.hp * grown + bonus.hp;
+    self.health.armor = self.def.armor + growth.armor * grown + bonus.armor;
+    self.attack.damage = self.def.attack.damage + growth.damage * grown
+                       + bonus.damage;
+  }
+
+  /// Works the stats out again after the items changed. Health and mana
+  /// go up or down by as much as their maximum did, but never kill.
+  fn restat( &mut self ) {
+    let (max_hp, max_mana) = ( self.health.max_hp, self.max_mana() );
+    let level = self.level;
+    self.bonus = self.inventory.bonus();
+    self.set_level( level );
+
+    self.health.hp = ( self.health.hp + self.health.max_hp - max_hp )
+                       .max( 1.0 ).min( self.health.max_hp );
+    self.mana = ( self.mana + self.max_mana() - max_mana )
+                  .max( 0.0 ).min( self.max_mana() );
+  }
+
+  /// Pays for the item and puts it in the first free inventory slot. Being
+  /// close enough to a shop is up to the caller.
+  pub fn buy( &mut self, def : Arc<ItemDef> ) -> Result<(), &'static str> {
+    if !self.is_alive() {
+      return Err( "hero is dead" )
+    } else if self.gold < def.cost {
+      return Err( "not enough gold" )
+    }
+
+    let cost = def.cost;
+    try!( self.inventory.add( def ) );
+    self.gold -= cost;
+    self.restat();
+    Ok( () )
+  }
+
+  /// Sells the item in `slot` for part of what it cost, which is returned.
+  pub fn sell( &mut self, slot : usize ) -> Result<u32, &'static str> {
+    if !self.is_alive() {
+      return Err( "hero is dead" )
+    }
+
+    let item = try!( self.inventory.remove( slot ).ok_or( "no such item" ) );
+    let refund = ( item.def.cost as f32 * SELL_FRACTION ) as u32;
+    self.gold += refund;
+    self.restat();
+    Ok( refund )
+  }
+
+  /// Checks everything about using the active of the item in `slot` that
+  /// doesn't depend on the rest of the world.
+  pub fn can_use_item( &self, slot : usize )
+    -> Result<AbilityDef, &'static str> {
+    let item = try!( self.inventory.get( slot ).ok_or( "no such item" ) );
+    let active = try!( item.def.active.clone().ok_or( "nothing to use" ) );
+
+    if !self.is_alive() {
+      Err( "hero is dead" )
+    } else if self.statuses.is_stunned() {
+      Err( "stunned" )
+    } else if active.effect.moves_caster() && !self.statuses.can_move() {
+      Err( "rooted" )
+    } else if self.casting.is_some() {
+      Err( "casting" )
+    } else if item.cooldown > 0.0 {
+      Err( "on cooldown" )
+    } else {
+      Ok( active )
+    }
+  }
+
+  /// Puts the item in `slot` on cooldown, its active takes effect right
+  /// away.
+  pub fn use_item( &mut self, slot : usize ) {
+    if let Some( item ) = self.inventory.get_mut( slot ) {
+      item.cooldown = item.def.active.as_ref().map_or( 0.0, |a| a.cooldown );
+    }
+  }
+
+  /// Adds experience, leveling up as often as it's enough for. Each level
+  /// brings an ability point and tops health and mana up by what they
+  /// grew. Returns whether the hero leveled up.
+  pub fn gain_xp( &mut self, amount : u32 ) -> bool {
+    if self.level >= MAX_LEVEL {
+      return false
+    }
+
+    let before = self.level;
+    self.xp += amount;
+
+    while self.level < MAX_LEVEL
+       && self.xp >= progress::xp_to_next( self.level ) {
+      self.xp -= progress::xp_to_next( self.level );
+
+      let (max_hp, max_mana) = ( self.health.max_hp, self.max_mana() );
+      let next = self.level + 1;
+      self.set_level( next );
+      self.health.hp += self.health.max_hp - max_hp;
+      self.mana += self.max_mana() - max_mana;
+      self.ability_points += 1;
+    }
+
+    if self.level >= MAX_LEVEL {
+      self.xp = 0;
+    }
+
+    self.level > before
+  }
+
+  /// Spends an ability point on the ability in `slot`.
+  pub fn learn( &mut self, slot : usize ) -> Result<(), &'static str> {
+    if self.def.ability( slot ).is_none() {
+      Err( "no such ability" )
+    } else if self.ability_points == 0 {
+      Err( "no ability points" )
+    } else if self.ranks[slot] >= progress::max_rank( self.level ) {
+      Err( "can't rank it up any further yet" )
+    } else {
+      self.ability_points -= 1;
+      self.ranks[slot] += 1;
+      Ok( () )
+    }
+  }
+
+  /// Checks everything about casting the ability in `slot` that doesn't
+  /// depend on the rest of the world.
+  pub fn can_cast( &self, slot : usize ) -> Result<AbilityDef, &'static str> {
+    if self.def.ability( slot ).is_none() {
+      return Err( "no such ability" )
+    }
+
+    let def = match self.ability( slot ) {
+      Some( d ) => d,
+      None => return Err( "not learned yet" )
+    };
+
+    if !self.is_alive() {
+      Err( "hero is dead" )
+    } else if self.statuses.is_stunned() {
+      Err( "stunned" )
+    } else if def.effect.moves_caster() && !self.statuses.can_move() {
+      Err( "rooted" )
+    } else if self.casting.is_some() {
+      Err( "already casting" )
+    } else if self.cooldowns[slot] > 0.0 {
+      Err( "on cooldown" )
+    } else if self.mana < def.cost {
+      Err( "not enough mana" )
+    } else {
+      Ok( def )
+    }
+  }
+
+  pub fn begin_cast( &mut self, slot : usize, target : CastTarget ) {
+    let (cost, cooldown, cast_time) = match self.ability( slot ) {
+      Some( a ) => ( a.cost, a.cooldown, a.cast_time ),
+      None => return
+    };
+
+    self.mana -= cost;
+    self.cooldowns[slot] = cooldown;
+    self.target_pos = None;
+    self.attack.target = None;
+    self.casting = Some( Casting { slot      : slot
+                                 , target    : target
+                                 , remaining : cast_time } );
+  }
+
+  /// Takes the current cast if its cast time is over.
+  pub fn finished_cast( &mut self ) -> Option<Casting> {
+    match self.casting {
+      Some( ref c ) if c.remaining <= 0.0 => {},
+      _ => return None
+    }
+
+    self.casting.take()
+  }
+
+  pub fn is_alive( &self ) -> bool {
+    self.life == LifeState::Alive
+  }
+
+  pub fn die( &mut self ) {
+    self.health.hp = 0.0;
+    self.target_pos = None;
+    self.path.clear();
+    self.path_goal = None;
+    self.attack.target = None;
+    self.casting = None;
+    self.statuses.clear();
+    self.entity.vel = Vec2::new( 0.0, 0.0 );
+    self.life = LifeState::Dead { respawn_in : respawn_time( self.level ) };
+  }
+
+  /// How fast the hero moves right now.
+  pub fn speed( &self ) -> f32 {
+    if self.statuses.can_move() {
+      ( self.def.speed + self.bonus.speed )
+        * self.statuses.speed_factor()
+    } else {
+      0.0
+    }
+  }
+
+  /// How long until the hero respawns, if it's dead.
+  pub fn respawn_in( &self ) -> Option<f32> {
+    match self.life {
+      LifeState::Dead { respawn_in } => Some( respawn_in.max( 0.0 ) ),
+      LifeState::Alive => None
+    }
+  }
+
+  /// Whether the hero is dead and done waiting to respawn.
+  pub fn ready_to_respawn( &self ) -> bool {
+    match self.life {
+      LifeState::Dead { respawn_in } => respawn_in <= 0.0,
+      LifeState::Alive => false
+    }
+  }
+
+  /// Brings the hero back at `sp` with full health and mana.
+  pub fn respawn( &mut self, sp : Pos2 ) {
+    self.entity.pos = sp;
+    self.entity.vel = Vec2::new( 0.0, 0.0 );
+    self.health.restore();
+    self.mana = self.max_mana();
+    self.stuck_for = 0.0;
+    self.life = LifeState::Alive;
   }
 
   pub fn instruct( &mut self, instr : InstructionPacket ) {
-    if let Some( p ) = instr.move_to {
-      self.target_pos = Some( p );
+    // Casting can't be interrupted by new orders, stuns ignore them
+    if !self.is_alive() || self.casting.is_some()
+    || self.statuses.is_stunned() {
+      return
+    }
+
+    match instr.order {
+      Some( Order::Move( p ) ) => {
+        self.target_pos = Some( p );
+        self.attack.target = None;
+      },
+      Some( Order::Attack( id ) ) => {
+        self.target_pos = None;
+        self.attack.target = Some( id );
+      },
+      // Casts need the rest of the world to be validated, and only the host
+      // hands out ranks and runs the shops, see `Game`
+      Some( Order::Cast( .. ) ) | Some( Order::Learn( .. ) )
+      | Some( Order::BuyItem( .. ) ) | Some( Order::SellItem( .. ) )
+      | Some( Order::UseItem( .. ) ) | None => {}
     }
   }
 
+  /// Chases the attack target at `target_pos` until it's in range, then
+  /// attacks it whenever the cooldown allows. Returns whether it attacked.
+  pub fn pursue( &mut self, target_pos : Pos2 ) -> bool {
+    self.attack.pursue( self.entity.pos, target_pos, &mut self.target_pos )
+  }
+
   pub fn update( &mut self, delta_time : f64 ) {
+    self.since_sync += delta_time as f32;
+
+    if let LifeState::Dead { ref mut respawn_in } = self.life {
+      *respawn_in -= delta_time as f32;
+      return
+    }
+
+    self.attack.update( delta_time );
+
+    let dt = delta_time as f32;
+    for cd in self.cooldowns.iter_mut() {
+      *cd = ( *cd - dt ).max( 0.0 );
+    }
+    self.mana = ( self.mana + self.def.mana_regen * dt )
+                  .min( self.max_mana() );
+    self.inventory.update( delta_time );
+
+    if let Some( ref mut c ) = self.casting {
+      c.remaining -= dt;
+    }
 
-    if let Some( dest ) = self.target_pos {
+    // Stuck in place, but still set on going wherever it was going
+    if !self.statuses.can_move() {
+      self.entity.vel = Vec2::new( 0.0, 0.0 );
+      return
+    }
+
+    // Follow the path if there is one, otherwise head straight there
+    if let Some( dest ) = self.path.first().cloned().or( self.target_pos ) {
       if self.entity.pos.sub_p( &dest ).length() < 1.0 {
         self.entity.pos = dest;
-        self.target_pos = None;
+
+        if !self.path.is_empty() {
+          self.path.remove( 0 );
+        }
+
+        if self.path.is_empty() {
+          self.target_pos = None;
+        }
       } else {
-        self.entity.vel = dest.sub_p( &self.entity.pos ).normalize_to( 100.0 );
+        self.entity.vel = dest.sub_p( &self.entity.pos )
+                              .normalize_to( self.speed() );
       }
     }
 
@@ -0,0 +1,1870 @@
+use std::collections::HashSet;
+use piston_window::*;
+use cgmath::{Point, Vector, EuclideanVector};
+use super::NetController;
+use packet::{Packet, InstructionPacket, SyncPacket, SpawnPacket, DeathPacket
+            , MatchEndedPacket, Order};
+use entity::{Hero, Structure, StructureKind, Attack, Hitbox, Pos2, Vec2
+            , MINION_AGGRO_RANGE, MINION_LEASH_RANGE};
+use ability::{AbilityDef, CastTarget, Casting, Effect, Targeting
+             , ABILITY_SLOTS};
+use projectile::{Projectile, ProjectileDef, Motion, OnHit};
+use world::{World, Actor, EntityId};
+use collision::{self, Grid};
+use map::Map;
+use team::{self, Team, TEAMS};
+use nav::NavGrid;
+use wave::{self, WaveTimer, WAVE_SIZE};
+use stats::MatchStats;
+use matchstate::{MatchState, COUNTDOWN_TIME};
+use status::{Status, StatusDef};
+use progress::{self, XP_RANGE};
+use item::SHOP_RANGE;
+use vision::{self, Vision, Fog};
+
+#[derive(Clone)]
+pub struct Controller {
+  hero_id            : EntityId,
+  dirty              : bool,
+  instruction_packet : InstructionPacket
+}
+
+impl Controller {
+  fn new( id : EntityId ) -> Controller {
+    Controller { hero_id            : id
+               , dirty              : false
+               , instruction_packet : InstructionPacket::new( id ) }
+  }
+
+  fn refresh( &mut self ) {
+    if !self.dirty {
+      return
+    }
+
+    self.instruction_packet = InstructionPacket::new( self.hero_id );
+    self.dirty = false;
+  }
+}
+
+const PICK_RADIUS : f32 = 10.0;
+const ATTACK_PROJECTILE_RADIUS : f32 = 2.0;
+const ATTACK_PROJECTILE_LIFETIME : f32 = 2.0;
+// How far a destination can move before the path to it is found again
+const REPATH_DISTANCE : f32 = 16.0;
+// How long a hero can be held up before it looks for a way around
+const STUCK_TIME : f32 = 0.5;
+// Moving slower than this fraction of full speed counts as being held up
+const STUCK_SPEED_FRACTION : f32 = 0.25;
+// Seconds between working out what the player's team can see
+const FOG_INTERVAL : f64 = 0.1;
+
+pub struct Game {
+  net_controller    : NetController,
+  controller        : Controller,
+  world             : World,
+  map               : Map,
+  nav               : NavGrid,
+  waves             : WaveTimer,
+  stats             : MatchStats,
+  state             : MatchState,
+  // Heroes whose players are ready to start, only kept by the host
+  ready             : HashSet<EntityId>,
+  cursor            : Pos2,
+  debug             : bool,
+  // Number keys buy and item keys sell while it's open
+  shop_open         : bool,
+  // What the player's team can't see, and since when it hasn't been redone
+  fog               : Fog,
+  since_fog         : f64,
+  glyphs            : Option<Glyphs>
+}
+
+fn status_color( status : &Status ) -> [f32; 4] {
+  match *status {
+    Status::Stun        => [1.0, 0.8, 0.0, 1.0],
+    Status::Root        => [0.5, 0.3, 0.1, 1.0],
+    Status::Slow( _ )   => [0.4, 0.8, 1.0, 1.0],
+    Status::Burn( _ )   => [1.0, 0.4, 0.0, 1.0],
+    Status::Shield( _ ) => [0.7, 0.7, 0.7, 1.0]
+  }
+}
+
+/// Which of the keys 1 to 9 it is, counting from 0.
+fn number_key( key : Key ) -> Option<usize> {
+  let keys = [ Key::D1, Key::D2, Key::D3, Key::D4, Key::D5, Key::D6, Key::D7
+             , Key::D8, Key::D9 ];
+  keys.iter().position( |&k| k == key )
+}
+
+/// The inventory slot the key is bound to.
+fn item_key( key : Key ) -> Option<usize> {
+  let keys = [ Key::Z, Key::X, Key::C, Key::V, Key::B, Key::N ];
+  keys.iter().position( |&k| k == key )
+}
+
+impl Game {
+  pub fn new( mut nc : NetController, map : Map, glyphs : Option<Glyphs> )
+    -> Game {
+    let id = nc.assigned_hero_id;
+
+    // Everybody spawns the roster's heroes and the map's structures in the
+    // same order, so they get the same IDs everywhere without the host having
+    // to send them
+    let mut world = World::new();
+    for p in &nc.roster.players {
+      let sp = map.spawn_point( p.team ).unwrap();
+      let def = nc.heroes.find( &p.hero )
+                         .unwrap_or( nc.heroes.default_hero() )
+                         .clone();
+      world.insert( p.hero_id, Actor::Hero( Hero::new( def, sp, p.team ) ) );
+    }
+
+    for &(kind, s) in &map.structures {
+      world.spawn( Actor::Structure( Structure::new( kind, s.pos, s.team ) ) );
+    }
+
+    let stats = MatchStats::new( &nc.roster );
+    // Paths that fit the biggest hero fit all of them
+    let nav = NavGrid::build( &map, nc.heroes.max_radius() );
+
+    let mut ready = HashSet::new();
+    ready.insert( id );
+
+    // Everyone starts out knowing about everything, the host hides what
+    // each team can't see once the match is on
+    if nc.is_host {
+      nc.vision = Some( Vision::everything( &world ) );
+    }
+
+    let team = nc.roster.team_of( id ).unwrap_or( Team::Blue );
+    let mut fog = Fog::new( &map, team );
+    fog.update( &world, &map );
+
+    Game { net_controller : nc
+         , controller     : Controller::new( id )
+         , world          : world
+         , nav            : nav
+         , map            : map
+         , waves          : WaveTimer::new()
+         , stats          : stats
+         , state          : MatchState::Lobby
+         , ready          : ready
+         , cursor         : Pos2::new( 0.0, 0.0 )
+         , debug          : false
+         , shop_open      : false
+         , fog            : fog
+         , since_fog      : 0.0
+         , glyphs         : glyphs }
+  }
+
+  pub fn update_cursor( &mut self, x : f64, y : f64 ) {
+    self.cursor = Pos2::new( x as f32, y as f32 );
+  }
+
+  pub fn input_press( &mut self, button : Button ) {
+
+    if let Button::Keyboard( Key::D ) = button {
+      self.debug = !self.debug;
+    }
+
+    if let Button::Keyboard( Key::T ) = button {
+      self.net_controller.toggle_tracing();
+    }
+
+    if let Button::Keyboard( Key::P ) = button {
+      self.toggle_pause();
+    }
+
+    if let Button::Keyboard( Key::S ) = button {
+      self.shop_open = !self.shop_open;
+    }
+
+    let cursor = Pos2::new( self.cursor.x, self.cursor.y );
+
+    let order = match button {
+      Button::Mouse( MouseButton::Left ) => Some( Order::Move( cursor ) ),
+      // Right click attacks the enemy under the cursor, or moves otherwise
+      Button::Mouse( MouseButton::Right ) =>
+        match self.world.targetable_at( cursor, PICK_RADIUS ) {
+          Some( id ) if self.hostile( self.controller.hero_id, id )
+                     && self.in_view( id ) =>
+            Some( Order::Attack( id ) ),
+          _ => Some( Order::Move( cursor ) )
+        },
+      Button::Keyboard( Key::Q ) => self.cast_order( 0, cursor ),
+      Button::Keyboard( Key::W ) => self.cast_order( 1, cursor ),
+      Button::Keyboard( Key::E ) => self.cast_order( 2, cursor ),
+      Button::Keyboard( Key::R ) => self.cast_order( 3, cursor ),
+      Button::Keyboard( k ) => match ( number_key( k ), item_key( k ) ) {
+        (Some( n ), _) if self.shop_open => Some( Order::BuyItem( n as u8 ) ),
+        (Some( n ), _) if n < ABILITY_SLOTS => Some( Order::Learn( n ) ),
+        (_, Some( slot )) if self.shop_open => Some( Order::SellItem( slot ) ),
+        (_, Some( slot )) => self.use_item_order( slot, cursor ),
+        _ => None
+      },
+      _ => None
+    };
+
+    if let Some( o ) = order {
+      self.controller.instruction_packet.order = Some( o );
+      self.controller.dirty = true;
+    }
+  }
+
+  /// Aims the ability in `slot` at the cursor the way it wants to be aimed.
+  fn cast_order( &self, slot : usize, cursor : Pos2 ) -> Option<Order> {
+    self.world.hero( self.controller.hero_id )
+              .and_then( |h| h.ability( slot ) )
+              .and_then( |def| self.aim( &def, cursor ) )
+              .map( |target| Order::Cast( slot, target ) )
+  }
+
+  /// Aims the active of the item in `slot` like `cast_order` does.
+  fn use_item_order( &self, slot : usize, cursor : Pos2 ) -> Option<Order> {
+    self.world.hero( self.controller.hero_id )
+              .and_then( |h| h.inventory.get( slot ) )
+              .and_then( |i| i.def.active.as_ref() )
+              .and_then( |def| self.aim( def, cursor ) )
+              .map( |target| Order::UseItem( slot, target ) )
+  }
+
+  fn aim( &self, def : &AbilityDef, cursor : Pos2 ) -> Option<CastTarget> {
+    let target = match def.targeting {
+      Targeting::NoTarget => CastTarget::NoTarget,
+      Targeting::Point => CastTarget::Point( cursor ),
+      Targeting::Unit => match self.world.targetable_at( cursor, PICK_RADIUS ) {
+        Some( id ) if self.in_view( id ) => CastTarget::Unit( id ),
+        _ => return None
+      },
+      Targeting::Direction => match self.world.pos( self.controller.hero_id ) {
+        Some( own ) => CastTarget::Direction( cursor.sub_p( &own ) ),
+        None => return None
+      }
+    };
+
+    Some( target )
+  }
+
+  /// Whether the player's team can see the entity.
+  fn in_view( &self, id : EntityId ) -> bool {
+    self.world.get( id ).map_or( false, |a| self.shows( a ) )
+  }
+
+  /// Whether the actor is out of the fog, or doesn't care about it like its
+  /// own side and structures.
+  fn shows( &self, actor : &Actor ) -> bool {
+    match *actor {
+      Actor::Structure( _ ) => true,
+      _ => actor.team() == Some( self.fog.team() )
+           || self.fog.is_visible( actor.entity().pos )
+    }
+  }
+
+  /// Whether `source` is allowed to hurt `target`, see `team::hostile`.
+  fn hostile( &self, source : EntityId, target : EntityId ) -> bool {
+    team::hostile( self.world.get( source ).and_then( |a| a.team() )
+                 , self.world.get( target ).and_then( |a| a.team() ) )
+  }
+
+  fn instruct_hero( &mut self, mut ip : InstructionPacket ) {
+    trace!( Sim, "Instructing hero {:?}: {:?}", ip.hero_id, ip.order );
+
+    if !self.state.is_running() {
+      debug!( Sim, "Ignoring instruction for {:?} while {:?}"
+            , ip.hero_id, self.state );
+      return
+    }
+
+    // Move targets come off the wire, keep them on the map before they get
+    // anywhere near pathfinding
+    if let Some( Order::Move( p ) ) = ip.order {
+      match self.map.clamp( p ) {
+        Some( p ) => ip.order = Some( Order::Move( p ) ),
+        None => {
+          debug!( Sim, "Hero {:?} can't move to {:?}", ip.hero_id, p );
+          return
+        }
+      }
+    }
+
+    if let Some( Order::Attack( t ) ) = ip.order {
+      if !self.hostile( ip.hero_id, t ) {
+        debug!( Sim, "Hero {:?} can't attack ally {:?}", ip.hero_id, t );
+        return
+      }
+    }
+
+    // Only the host decides whether a cast goes through
+    if let Some( Order::Cast( slot, target ) ) = ip.order {
+      if self.net_controller.is_host {
+        self.start_cast( ip.hero_id, slot, target );
+      }
+      return
+    }
+
+    // Same for spending ability points and anything to do with items
+    match ip.order {
+      Some( Order::Learn( slot ) ) => {
+        if self.net_controller.is_host {
+          self.learn( ip.hero_id, slot );
+        }
+        return
+      },
+      Some( Order::BuyItem( item ) ) => {
+        if self.net_controller.is_host {
+          self.buy_item( ip.hero_id, item );
+        }
+        return
+      },
+      Some( Order::SellItem( slot ) ) => {
+        if self.net_controller.is_host {
+          self.sell_item( ip.hero_id, slot );
+        }
+        return
+      },
+      Some( Order::UseItem( slot, target ) ) => {
+        if self.net_controller.is_host {
+          self.use_item( ip.hero_id, slot, target );
+        }
+        return
+      },
+      _ => ()
+    }
+
+    match self.world.hero_mut( ip.hero_id ) {
+      Some( hero ) => hero.instruct( ip ),
+      None => warn!( Sim, "Instruction for unknown hero {:?}", ip.hero_id )
+    }
+  }
+
+  fn validate_cast( &self, id : EntityId, slot : usize, target : CastTarget )
+    -> Result<(), &'static str> {
+    let hero = try!( self.world.hero( id ).ok_or( "no such hero" ) );
+    let def = try!( hero.can_cast( slot ) );
+    self.validate_target( hero, id, &def, target )
+  }
+
+  /// Whether `target` is something the hero can aim `def` at from where it
+  /// is, for both abilities and item actives.
+  fn validate_target( &self, hero : &Hero, id : EntityId, def : &AbilityDef
+                    , target : CastTarget ) -> Result<(), &'static str> {
+    if target.targeting() != def.targeting {
+      return Err( "wrong kind of target" )
+    }
+
+    match target {
+      CastTarget::NoTarget => Ok( () ),
+      // NaN is never out of range, so it has to be ruled out first
+      CastTarget::Point( p ) =>
+        if !p.x.is_finite() || !p.y.is_finite() {
+          Err( "invalid point" )
+        } else if hero.entity.pos.sub_p( &p ).length() > def.cast_range {
+          Err( "out of range" )
+        } else {
+          Ok( () )
+        },
+      CastTarget::Unit( t ) => match self.world.get( t ) {
+        Some( a ) if t != id && a.is_targetable() =>
+          if def.effect.is_harmful() != self.hostile( id, t ) {
+            Err( "wrong team" )
+          } else if hero.entity.pos.sub_p( &a.entity().pos ).length()
+                    > def.cast_range {
+            Err( "out of range" )
+          } else {
+            Ok( () )
+          },
+        _ => Err( "invalid target" )
+      },
+      CastTarget::Direction( d ) =>
+        if !d.x.is_finite() || !d.y.is_finite() {
+          Err( "invalid direction" )
+        } else if d.length() <= 0.0 {
+          Err( "no direction" )
+        } else {
+          Ok( () )
+        }
+    }
+  }
+
+  fn start_cast( &mut self, id : EntityId, slot : usize, target : CastTarget ) {
+    match self.validate_cast( id, slot, target ) {
+      Ok( () ) => {
+        if let Some( hero ) = self.world.hero_mut( id ) {
+          hero.begin_cast( slot, target );
+        }
+
+        self.send_sync( id );
+      },
+      Err( why ) =>
+        debug!( Sim, "Rejected cast of ability {} by hero {:?}: {}"
+              , slot, id, why )
+    }
+  }
+
+  fn learn( &mut self, id : EntityId, slot : usize ) {
+    let learned = match self.world.hero_mut( id ) {
+      Some( hero ) => hero.learn( slot ),
+      None => Err( "no such hero" )
+    };
+
+    match learned {
+      Ok( () ) => self.send_sync( id ),
+      Err( why ) =>
+        debug!( Sim, "Hero {:?} can't learn ability {}: {}", id, slot, why )
+    }
+  }
+
+  /// Whether the hero is close enough to one of its team's bases to shop.
+  fn in_shop_range( &self, hero : &Hero ) -> bool {
+    self.map.bases.iter()
+                  .filter( |b| b.team == hero.team )
+                  .any( |b| hero.entity.pos.sub_p( &b.pos ).length()
+                            <= SHOP_RANGE )
+  }
+
+  fn buy_item( &mut self, id : EntityId, item : u8 ) {
+    let def = match self.net_controller.items.get( item ) {
+      Some( d ) => d.clone(),
+      None => {
+        debug!( Sim, "Hero {:?} can't buy unknown item {}", id, item );
+        return
+      }
+    };
+
+    let at_shop = self.world.hero( id )
+                            .map_or( false, |h| self.in_shop_range( h ) );
+    let bought = match self.world.hero_mut( id ) {
+      Some( _ ) if !at_shop => Err( "not at a shop" ),
+      Some( hero ) => hero.buy( def.clone() ),
+      None => Err( "no such hero" )
+    };
+
+    match bought {
+      Ok( () ) => {
+        info!( Sim, "Hero {:?} bought {}", id, def.name );
+        self.send_sync( id );
+      },
+      Err( why ) =>
+        debug!( Sim, "Hero {:?} can't buy {}: {}", id, def.name, why )
+    }
+  }
+
+  fn sell_item( &mut self, id : EntityId, slot : usize ) {
+    let at_shop = self.world.hero( id )
+                            .map_or( false, |h| self.in_shop_range( h ) );
+    let sold = match self.world.hero_mut( id ) {
+      Some( _ ) if !at_shop => Err( "not at a shop" ),
+      Some( hero ) => hero.sell( slot ),
+      None => Err( "no such hero" )
+    };
+
+    match sold {
+      Ok( gold ) => {
+        info!( Sim, "Hero {:?} sold item {} for {}", id, slot, gold );
+        self.send_sync( id );
+      },
+      Err( why ) =>
+        debug!( Sim, "Hero {:?} can't sell item {}: {}", id, slot, why )
+    }
+  }
+
+  /// Item actives skip the cast time and take effect right away.
+  fn use_item( &mut self, id : EntityId, slot : usize, target : CastTarget ) {
+    let validated = match self.world.hero( id ) {
+      Some( hero ) => hero.can_use_item( slot ).and_then( |def| {
+        try!( self.validate_target( hero, id, &def, target ) );
+        Ok( def )
+      } ),
+      None => Err( "no such hero" )
+    };
+
+    match validated {
+      Ok( def ) => {
+        if let Some( hero ) = self.world.hero_mut( id ) {
+          hero.use_item( slot );
+        }
+
+        self.apply_effect( id, &def, target );
+      },
+      Err( why ) =>
+        debug!( Sim, "Rejected use of item {} by hero {:?}: {}"
+              , slot, id, why )
+    }
+  }
+
+  /// Applies the effects of every cast that's done casting, host only.
+  fn update_casts( &mut self ) {
+    for id in self.world.ids() {
+      let cast = match self.world.hero_mut( id ) {
+        Some( hero ) => hero.finished_cast(),
+        None => None
+      };
+
+      if let Some( c ) = cast {
+        self.apply_ability( id, c );
+      }
+    }
+  }
+
+  fn apply_ability( &mut self, id : EntityId, cast : Casting ) {
+    let def = match self.world.hero( id )
+                              .and_then( |h| h.ability( cast.slot ) ) {
+      Some( d ) => d,
+      None => return
+    };
+
+    self.apply_effect( id, &def, cast.target );
+  }
+
+  /// Does what an ability or item active does, host only.
+  fn apply_effect( &mut self, id : EntityId, def : &AbilityDef
+                 , target : CastTarget ) {
+    debug!( Sim, "Hero {:?} used {} at {:?}", id, def.name, target );
+
+    let pos = match self.world.pos( id ) {
+      Some( p ) => p,
+      None => return
+    };
+
+    match (def.effect, target) {
+      (Effect::Damage( amount ), CastTarget::Unit( t )) =>
+        self.damage( t, amount, Some( id ) ),
+      (Effect::Heal( amount ), _) =>
+        self.heal( id, amount ),
+      (Effect::Blink, CastTarget::Point( p )) => {
+        let end = self.travel_end( id, pos, p );
+        self.set_pos( id, end )
+      },
+      (Effect::Dash( distance ), CastTarget::Direction( d )) => {
+        let to = pos.add_v( &d.normalize_to( distance ) );
+        let end = self.travel_end( id, pos, to );
+        self.set_pos( id, end )
+      },
+      (Effect::Projectile( p ), CastTarget::Unit( t )) =>
+        if let Some( tp ) = self.world.pos( t ) {
+          self.spawn_projectile( &p, id, tp.sub_p( &pos ), Motion::Homing( t ) );
+        },
+      (Effect::Projectile( p ), CastTarget::Direction( d )) =>
+        self.spawn_projectile( &p, id, d, Motion::Linear ),
+      (Effect::Status( s ), CastTarget::Unit( t )) =>
+        self.apply_status( t, s, Some( id ) ),
+      (Effect::Status( s ), CastTarget::NoTarget) =>
+        self.apply_status( id, s, Some( id ) ),
+      (e, t) =>
+        warn!( Sim, "Ability effect {:?} can't be applied to {:?}", e, t )
+    }
+
+    self.send_sync( id );
+  }
+
+  /// Where a blink or dash from `from` towards `to` ends: on the map and
+  /// short of the first wall or structure in the way.
+  fn travel_end( &self, id : EntityId, from : Pos2, to : Pos2 ) -> Pos2 {
+    let to = self.map.clamp( to ).unwrap_or( from );
+    let radius = self.world.get( id )
+                           .map_or( 0.0, |a| collision::bounding_radius(
+                                               &a.entity().hitbox ) );
+
+    let mut hit = self.map.segment_cast( from, to );
+    for (_, a) in self.world.iter() {
+      if let Actor::Structure( ref s ) = *a {
+        if let Some( t ) = collision::segment_cast( &s.entity, from, to ) {
+          hit = collision::min_hit( hit, t );
+        }
+      }
+    }
+
+    let d = to.sub_p( &from );
+    let length = d.length();
+    match hit {
+      // Stop a radius short so it doesn't end up inside
+      Some( t ) if length > 0.0 => {
+        let reach = ( t * length - radius ).max( 0.0 );
+        from.add_v( &d.mul_s( reach / length ) )
+      },
+      _ => to
+    }
+  }
+
+  fn set_pos( &mut self, id : EntityId, pos : Pos2 ) {
+    if let Some( a ) = self.world.get_mut( id ) {
+      a.entity_mut().pos = pos;
+    }
+
+    // The old path was from somewhere else
+    if let Some( hero ) = self.world.hero_mut( id ) {
+      hero.path_goal = None;
+    }
+  }
+
+  /// Sends the current state of an entity right away, outside of the
+  /// regular sync schedule.
+  fn send_sync( &mut self, id : EntityId ) {
+    let frame = match self.world.get( id ) {
+      Some( a ) => a.clone(),
+      None => return
+    };
+
+    self.net_controller.send_entity_sync( SyncPacket::new( id, frame ) );
+  }
+
+  fn sync_entity( &mut self, sp : SyncPacket ) {
+    trace!( Sim, "Syncing entity {:?}", sp.id );
+    let is_host = self.net_controller.is_host;
+    let map = &self.map;
+    let mut frame = sp.sync_frame;
+
+    match self.world.get_mut( sp.id ) {
+      Some( current ) => {
+        // The host owns health, death and casting, clients only get to sync
+        // the movement of their heroes. Anything else they send is ignored
+        if is_host {
+          match (&mut frame, &*current) {
+            (&mut Actor::Hero( ref mut f ), &Actor::Hero( ref c )) => {
+              f.take_host_state( c );
+              // Where it's headed gets pathed to, same as move orders
+              f.target_pos = f.target_pos.and_then( |p| map.clamp( p ) );
+            },
+            _ => {
+              warn!( Sim, "Ignoring client sync of non-hero {:?}", sp.id );
+              return
+            }
+          }
+
+          let checked = SyncPacket::new( sp.id, frame.clone() );
+          self.net_controller.relay_sync( checked );
+        }
+
+        *current = frame;
+      },
+      // The host only syncs what we can see, so it came into sight and the
+      // spawn got lost or we forgot about it too early
+      None if !is_host => self.world.insert( sp.id, frame ),
+      None => debug!( Sim, "Sync for unknown entity {:?}", sp.id )
+    }
+  }
+
+  /// Where a hero respawns, at its team's fountain.
+  fn respawn_point( &self, id : EntityId ) -> Option<Pos2> {
+    self.world.hero( id ).and_then( |h| self.map.fountain( h.team ) )
+  }
+
+  fn heal( &mut self, id : EntityId, amount : f32 ) {
+    if let Some( health ) = self.world.get_mut( id )
+                                      .and_then( |a| a.health_mut() ) {
+      health.hp = ( health.hp + amount ).min( health.max_hp );
+    }
+  }
+
+  /// Puts a status on a unit, host only. Stuns interrupt casts.
+  fn apply_status( &mut self, id : EntityId, def : StatusDef
+                 , source : Option<EntityId> ) {
+    if !self.net_controller.is_host {
+      return
+    }
+
+    match self.world.get_mut( id ) {
+      Some( actor ) if actor.is_targetable() => {
+        match actor.statuses_mut() {
+          Some( s ) => s.apply( def, source ),
+          None => return
+        }
+
+        if let ( Status::Stun, &mut Actor::Hero( ref mut hero ) )
+             = ( def.status, actor ) {
+          hero.casting = None;
+        }
+      },
+      _ => return
+    }
+
+    debug!( Sim, "{:?} got {:?} from {:?}", id, def, source );
+    self.send_sync( id );
+  }
+
+  /// Counts every status down, the host deals the burn damage that ticked.
+  fn update_statuses( &mut self, delta_time : f64 ) {
+    for id in self.world.ids() {
+      let burns = match self.world.get_mut( id )
+                                  .and_then( |a| a.statuses_mut() ) {
+        Some( s ) => s.update( delta_time ),
+        None => continue
+      };
+
+      for (amount, source) in burns {
+        self.damage( id, amount, source );
+      }
+    }
+  }
+
+  /// Applies damage to anything with health, only the host does this,
+  /// everybody else hears about the result through syncs and death packets.
+  fn damage( &mut self, id : EntityId, amount : f32, source : Option<EntityId> ) {
+    if !self.net_controller.is_host {
+      return
+    }
+
+    // No friendly fire
+    if let Some( s ) = source {
+      if !self.hostile( s, id ) {
+        return
+      }
+    }
+
+    if self.is_protected( id ) {
+      return
+    }
+
+    let (dealt, depleted) = {
+      let actor = match self.world.get_mut( id ) {
+        Some( a ) => a,
+        None => return
+      };
+
+      if !actor.is_targetable() {
+        return
+      }
+
+      // Shields soak it up first
+      let amount = match actor.statuses_mut() {
+        Some( s ) => s.absorb( amount ),
+        None => amount
+      };
+
+      let health = match actor.health_mut() {
+        Some( h ) => h,
+        None => return
+      };
+
+      let dealt = health.take_damage( amount );
+      debug!( Sim, "{:?} took {} damage from {:?}", id, dealt, source );
+
+      ( dealt, health.is_depleted() )
+    };
+
+    if let Some( p ) = source.and_then( |s| self.stats.player_mut( s ) ) {
+      p.damage_dealt += dealt;
+    }
+
+    if depleted {
+      let dp = DeathPacket { id : id, killer : source };
+      self.kill( dp.clone() );
+      self.net_controller.send_death( dp );
+    }
+
+    self.send_sync( id );
+  }
+
+  /// Heroes stay around to respawn, anything else is gone for good.
+  fn kill( &mut self, dp : DeathPacket ) {
+    info!( Sim, "{:?} was killed by {:?}", dp.id, dp.killer );
+
+    let lost_core = match self.world.get( dp.id ) {
+      Some( &Actor::Structure( ref s ) ) if s.kind == StructureKind::Core =>
+        Some( s.team ),
+      _ => None
+    };
+
+    if self.net_controller.is_host {
+      self.record_kill( &dp );
+      self.reward_kill( &dp );
+    }
+
+    let was_hero = match self.world.hero_mut( dp.id ) {
+      Some( hero ) => { hero.die(); true },
+      None => false
+    };
+
+    if !was_hero {
+      self.world.despawn( dp.id );
+    }
+
+    if let Some( team ) = lost_core {
+      if self.net_controller.is_host {
+        self.end_match( team.opponent() );
+      }
+    }
+  }
+
+  /// Counts a death towards the match stats, host only.
+  fn record_kill( &mut self, dp : &DeathPacket ) {
+    let victim = match self.world.get( dp.id ) {
+      Some( a ) => a,
+      None => return
+    };
+
+    if let Actor::Hero( .. ) = *victim {
+      if let Some( p ) = self.stats.player_mut( dp.id ) {
+        p.deaths += 1;
+      }
+    }
+
+    let killer = match dp.killer {
+      Some( k ) => self.stats.player_mut( k ),
+      None => None
+    };
+
+    if let Some( p ) = killer {
+      match *victim {
+        Actor::Hero( .. ) => p.kills += 1,
+        Actor::Minion( .. ) => p.minion_kills += 1,
+        Actor::Structure( .. ) => p.structure_kills += 1,
+        Actor::Projectile( .. ) => ()
+      }
+    }
+  }
+
+  /// Hands out the bounty on whatever died, host only. The gold goes to the
+  /// hero that got the kill, the experience is shared by its team's heroes
+  /// close enough to the death.
+  fn reward_kill( &mut self, dp : &DeathPacket ) {
+    let (bounty, pos) = match self.world.get( dp.id ) {
+      Some( a ) => ( progress::bounty( a ), a.entity().pos ),
+      None => return
+    };
+
+    // Only heroes get paid, minions and towers don't care
+    let killer = match dp.killer {
+      Some( k ) => k,
+      None => return
+    };
+    let team = match self.world.hero( killer ) {
+      Some( hero ) => hero.team,
+      None => return
+    };
+
+    let sharing = self.world.iter()
+                            .filter_map( |(&id, a)| match *a {
+                              Actor::Hero( ref h ) if h.team == team
+                                && h.is_alive()
+                                && ( id == killer
+                                  || h.entity.pos.sub_p( &pos ).length()
+                                     <= XP_RANGE ) => Some( id ),
+                              _ => None
+                            } )
+                            .collect::<Vec<_>>();
+
+    if let Some( hero ) = self.world.hero_mut( killer ) {
+      hero.gold += bounty.gold;
+    }
+
+    if !sharing.is_empty() {
+      let share = bounty.xp / sharing.len() as u32;
+
+      for &id in &sharing {
+        if let Some( hero ) = self.world.hero_mut( id ) {
+          if hero.gain_xp( share ) {
+            info!( Sim, "Hero {:?} reached level {}", id, hero.level );
+          }
+        }
+      }
+    }
+
+    self.send_sync( killer );
+    for id in sharing.into_iter().filter( |&id| id != killer ) {
+      self.send_sync( id );
+    }
+  }
+
+  /// How many of a team's structures of the given kind are still standing.
+  fn standing( &self, team : Team, kind : StructureKind ) -> usize {
+    self.world.iter()
+              .filter( |&(_, a)| match *a {
+                Actor::Structure( ref s ) => s.team == team && s.kind == kind,
+                _ => false
+              } )
+              .count()
+  }
+
+  /// Whether the entity can't be hurt right now: cores are safe as long as
+  /// any of their team's barracks stand.
+  fn is_protected( &self, id : EntityId ) -> bool {
+    match self.world.get( id ) {
+      Some( &Actor::Structure( ref s ) ) if s.kind == StructureKind::Core =>
+        self.standing( s.team, StructureKind::Barracks ) > 0,
+      _ => false
+    }
+  }
+
+  /// Ends the match in the winner's favour and lets everyone know, host
+  /// only.
+  fn end_match( &mut self, winner : Team ) {
+    self.set_state( MatchState::Ended( winner ) );
+    self.send_match_ended();
+  }
+
+  fn send_match_ended( &mut self ) {
+    if let Some( w ) = self.state.winner() {
+      let me = MatchEndedPacket { winner : w, stats : self.stats.clone() };
+      self.net_controller.send_match_ended( me );
+    }
+  }
+
+  fn match_ended( &mut self, me : MatchEndedPacket ) {
+    self.follow_state( MatchState::Ended( me.winner ) );
+    self.stats = me.stats;
+  }
+
+  /// Moves the match on to `state` and tells the clients, host only.
+  fn set_state( &mut self, state : MatchState ) {
+    info!( Sim, "Match state: {:?}", state );
+    self.state = state;
+    self.net_controller.send_match_state( state );
+  }
+
+  /// Follows a state change from the host, unless it's an old one that
+  /// arrived late.
+  fn follow_state( &mut self, state : MatchState ) {
+    if !self.state.can_become( state ) {
+      debug!( Sim, "Ignoring stale match state {:?}", state );
+      return
+    }
+
+    if self.state != state {
+      info!( Sim, "Match state: {:?}", state );
+    }
+
+    self.state = state;
+  }
+
+  /// Marks the player that sent the packet we're handling as ready, host
+  /// only.
+  fn peer_ready( &mut self ) {
+    let hero = self.net_controller.last_sender()
+                   .and_then( |s| self.net_controller.peer_heroes.get( &s ) )
+                   .cloned();
+
+    match hero {
+      Some( id ) => self.player_ready( id ),
+      None => debug!( Sim, "Ready from someone who isn't playing" )
+    }
+  }
+
+  fn player_ready( &mut self, id : EntityId ) {
+    if self.ready.insert( id ) {
+      info!( Sim, "Player with hero {:?} is ready", id );
+    }
+  }
+
+  fn everyone_ready( &self ) -> bool {
+    self.net_controller.roster.players.iter()
+                                      .all( |p| self.ready.contains( &p.hero_id ) )
+  }
+
+  /// Moves the match along as everyone joins and gets ready, host only.
+  fn update_state( &mut self, delta_time : f64 ) {
+    self.state.tick( delta_time );
+
+    let next = match self.state {
+      // Everyone's already in by the time the lobby makes a game
+      MatchState::Lobby => Some( MatchState::Loading ),
+      MatchState::Loading if self.everyone_ready() =>
+        Some( MatchState::Countdown( COUNTDOWN_TIME ) ),
+      MatchState::Countdown( left ) if left <= 0.0 =>
+        Some( MatchState::InProgress ),
+      _ => None
+    };
+
+    if let Some( s ) = next {
+      self.set_state( s );
+    }
+  }
+
+  /// Pauses or unpauses the match, only the host gets to.
+  fn toggle_pause( &mut self ) {
+    if !self.net_controller.is_host {
+      info!( Sim, "Only the host can pause" );
+      return
+    }
+
+    match self.state {
+      MatchState::InProgress => self.set_state( MatchState::Paused ),
+      MatchState::Paused => self.set_state( MatchState::InProgress ),
+      _ => ()
+    }
+  }
+
+  /// Moves attackers into range of their targets and lets them attack.
+  /// Everyone runs this so attacks show up everywhere, but only the host's
+  /// `damage` and `spawn_projectile` actually do anything.
+  fn update_attacks( &mut self ) {
+    for id in self.world.ids() {
+      let target = match self.world.get( id ) {
+        Some( a ) if a.is_targetable() && !a.is_stunned() => match a.attack() {
+          Some( &Attack { target : Some( t ), .. } ) => t,
+          _ => continue
+        },
+        _ => continue
+      };
+
+      let target_pos = match self.world.get( target ) {
+        Some( a ) if a.is_targetable() && self.hostile( id, target ) =>
+          Some( a.entity().pos ),
+        _ => None
+      };
+
+      let (fired, attack, pos) = match self.world.get_mut( id ) {
+        Some( actor ) => match target_pos {
+          Some( tp ) => ( actor.pursue( tp ), actor.attack().unwrap().clone()
+                        , actor.entity().pos ),
+          None => {
+            actor.attack_mut().unwrap().target = None;
+            continue
+          }
+        },
+        None => continue
+      };
+
+      if !fired {
+        continue
+      }
+
+      if attack.is_ranged() {
+        let def = ProjectileDef { speed    : attack.projectile_speed
+                                , radius   : ATTACK_PROJECTILE_RADIUS
+                                , lifetime : ATTACK_PROJECTILE_LIFETIME
+                                , on_hit   : OnHit::Damage( attack.damage )
+                                , status   : None };
+        let heading = target_pos.unwrap().sub_p( &pos );
+        self.spawn_projectile( &def, id, heading, Motion::Homing( target ) );
+      } else {
+        self.damage( target, attack.damage, Some( id ) );
+      }
+    }
+  }
+
+  /// The closest enemy of `id` within `range` that can be attacked and
+  /// passes `filter`, for the units that pick their own targets.
+  fn closest_enemy<F>( &self, grid : &Grid, id : EntityId, range : f32
+                     , filter : F ) -> Option<EntityId>
+    where F : Fn( &Actor ) -> bool {
+    let pos = match self.world.pos( id ) {
+      Some( p ) => p,
+      None => return None
+    };
+
+    let mut best = None;
+    let mut best_dist = range;
+
+    for other in grid.query( pos, range ) {
+      let dist = match self.world.get( other ) {
+        Some( a ) if a.is_targetable() && self.hostile( id, other )
+                     && filter( a ) =>
+          a.entity().pos.sub_p( &pos ).length(),
+        _ => continue
+      };
+
+      if dist <= best_dist {
+        best = Some( other );
+        best_dist = dist;
+      }
+    }
+
+    best
+  }
+
+  /// Minion AI, host only: go after enemies that come close, give up on
+  /// them when they get too far away, and walk the lane otherwise.
+  fn update_minions( &mut self ) {
+    let grid = Grid::build( &self.world );
+
+    for id in self.world.ids() {
+      let (pos, target) = match self.world.get( id ) {
+        Some( &Actor::Minion( ref m ) ) => ( m.entity.pos, m.attack.target ),
+        _ => continue
+      };
+
+      let keep = target.and_then( |t| self.world.get( t ) )
+                       .map( |a| a.is_targetable()
+                              && a.entity().pos.sub_p( &pos ).length()
+                                 <= MINION_LEASH_RANGE )
+                       .unwrap_or( false );
+
+      let new_target = if keep {
+        target
+      } else {
+        self.closest_enemy( &grid, id, MINION_AGGRO_RANGE, |_| true )
+      };
+
+      if let Some( &mut Actor::Minion( ref mut m ) ) = self.world.get_mut( id ) {
+        m.attack.target = new_target;
+
+        if new_target.is_none() {
+          m.follow_lane();
+        }
+      }
+
+      if new_target != target {
+        self.send_sync( id );
+      }
+    }
+  }
+
+  /// Tower AI, host only: keep shooting the current target while it's in
+  /// range, otherwise go for the closest minion, and only for heroes when
+  /// there are no minions around.
+  fn update_towers( &mut self ) {
+    let grid = Grid::build( &self.world );
+
+    for id in self.world.ids() {
+      let (pos, range, target) = match self.world.get( id ) {
+        Some( &Actor::Structure( Structure { attack : Some( ref a )
+                                           , ref entity, .. } ) ) =>
+          ( entity.pos, a.range, a.target ),
+        _ => continue
+      };
+
+      let keep = target.and_then( |t| self.world.get( t ) )
+                       .map( |a| a.is_targetable()
+                              && a.entity().pos.sub_p( &pos ).length()
+                                 <= range )
+                       .unwrap_or( false );
+
+      let new_target = if keep {
+        target
+      } else {
+        let minion = |a : &Actor| match *a {
+          Actor::Minion( .. ) => true,
+          _ => false
+        };
+        let hero = |a : &Actor| match *a {
+          Actor::Hero( .. ) => true,
+          _ => false
+        };
+
+        self.closest_enemy( &grid, id, range, minion )
+            .or_else( || self.closest_enemy( &grid, id, range, hero ) )
+      };
+
+      if let Some( a ) = self.world.get_mut( id ).and_then( |a| a.attack_mut() ) {
+        a.target = new_target;
+      }
+
+      if new_target != target {
+        self.send_sync( id );
+      }
+    }
+  }
+
+  /// Sends out a wave for every team whose timer is up, host only. Each of
+  /// the enemy's barracks that's down adds a minion to the wave.
+  fn spawn_waves( &mut self, delta_time : f64 ) {
+    for team in self.waves.update( delta_time ) {
+      let enemy = team.opponent();
+      let built = self.map.structures.iter()
+                                     .filter( |&&(k, p)|
+                                       k == StructureKind::Barracks
+                                       && p.team == enemy )
+                                     .count();
+      let lost = built.saturating_sub(
+                   self.standing( enemy, StructureKind::Barracks ) );
+
+      for minion in wave::wave( &self.map, team, WAVE_SIZE + lost ) {
+        let m = Actor::Minion( minion );
+        let id = self.world.spawn( m.clone() );
+        self.net_controller.send_spawn( SpawnPacket { id : id, actor : m } );
+      }
+    }
+  }
+
+  /// Regular syncs of everything the host runs on its own.
+  fn send_host_syncs( &mut self ) {
+    for id in self.world.ids() {
+      if let Some( &Actor::Minion( .. ) ) = self.world.get( id ) {
+        self.send_sync( id );
+      }
+    }
+
+    // The match state and result go out like any other packet and could get
+    // lost, so keep repeating them, along with who's playing for anyone
+    // still in the lobby
+    let state = self.state;
+    self.net_controller.send_match_state( state );
+    self.send_match_ended();
+
+    if self.state.is_starting() {
+      let roster = self.net_controller.roster.clone();
+      self.net_controller.send_start( roster );
+    }
+  }
+
+  /// Shoots a projectile from an entity, host only, clients hear about it
+  /// through a spawn packet.
+  fn spawn_projectile( &mut self, def : &ProjectileDef, owner : EntityId
+                     , heading : Vec2, motion : Motion ) {
+    if !self.net_controller.is_host {
+      return
+    }
+
+    let (pos, team) = match self.world.get( owner ) {
+      Some( a ) => match a.team() {
+        Some( t ) => ( a.entity().pos, t ),
+        None => return
+      },
+      None => return
+    };
+
+    let p = Actor::Projectile( Projectile::new( def, owner, team, pos, heading
+                                              , motion ) );
+    let id = self.world.spawn( p.clone() );
+
+    self.net_controller.send_spawn( SpawnPacket { id : id, actor : p } );
+  }
+
+  fn apply_hit( &mut self, target : EntityId, p : &Projectile ) {
+    match p.on_hit {
+      OnHit::Damage( d ) => self.damage( target, d, Some( p.owner ) ),
+      OnHit::Heal( h ) => {
+        self.heal( target, h );
+        self.send_sync( target );
+      }
+    }
+
+    if let Some( s ) = p.status {
+      self.apply_status( target, s, Some( p.owner ) );
+    }
+  }
+
+  /// Moves the projectiles and finds what they hit. Everybody removes a
+  /// projectile when it hits, but only the host applies its effect.
+  fn update_projectiles( &mut self, delta_time : f64 ) {
+    for id in self.world.ids() {
+      let motion = match self.world.get( id ) {
+        Some( &Actor::Projectile( ref p ) ) => p.motion.clone(),
+        _ => continue
+      };
+
+      let target_pos = match motion {
+        Motion::Homing( t ) => match self.world.get( t ) {
+          Some( a ) if a.is_targetable() => Some( a.entity().pos ),
+          _ => None
+        },
+        Motion::Linear => None
+      };
+
+      if let Some( p ) = self.world.projectile_mut( id ) {
+        p.update( delta_time, target_pos );
+      }
+    }
+
+    let hits = {
+      let grid = Grid::build( &self.world );
+      collision::projectile_hits( &self.world, &grid )
+    };
+
+    for hit in hits {
+      let projectile = self.world.despawn( hit.projectile );
+
+      if self.net_controller.is_host {
+        if let Some( Actor::Projectile( p ) ) = projectile {
+          self.apply_hit( hit.target, &p );
+        }
+
+        self.net_controller.send_despawn( hit.projectile );
+      }
+    }
+
+    // Projectiles don't go through walls
+    let map = &self.map;
+    let expired = self.world.iter()
+                            .filter( |&(_, a)| match *a {
+                              Actor::Projectile( ref p ) =>
+                                p.expired() || map.blocks( p.entity.pos ),
+                              _ => false
+                            } )
+                            .map( |(id, _)| *id )
+                            .collect::<Vec<_>>();
+
+    for id in expired {
+      self.world.despawn( id );
+    }
+  }
+
+  /// Keeps the path of every moving hero leading to where it's going,
+  /// finding a new one when the destination moved or the hero got stuck on
+  /// other units, in which case it tries to go around them.
+  fn update_paths( &mut self ) {
+    for id in self.world.ids() {
+      let (pos, goal, stuck, radius) = match self.world.hero_mut( id ) {
+        Some( hero ) => match hero.target_pos {
+          Some( t ) => {
+            let moved = hero.path_goal.map( |g| g.sub_p( &t ).length()
+                                                > REPATH_DISTANCE )
+                                      .unwrap_or( true );
+            let stuck = hero.stuck_for >= STUCK_TIME;
+
+            if !moved && !stuck {
+              continue
+            }
+
+            ( hero.entity.pos, t, stuck, hero.def.radius )
+          },
+          None => {
+            hero.path.clear();
+            hero.path_goal = None;
+            hero.stuck_for = 0.0;
+            continue
+          }
+        },
+        None => continue
+      };
+
+      let avoid = if stuck {
+        self.world.iter()
+                  .filter( |&(&other, a)| other != id
+                                       && collision::is_solid( a ) )
+                  .map( |(_, a)| {
+                    let e = a.entity();
+                    ( e.pos, collision::bounding_radius( &e.hitbox ) + radius )
+                  } )
+                  .collect::<Vec<_>>()
+      } else {
+        Vec::new()
+      };
+
+      let path = self.nav.find_path( pos, goal, &avoid );
+      trace!( Sim, "Path for {:?} to {:?}: {:?}", id, goal, path );
+
+      if let Some( hero ) = self.world.hero_mut( id ) {
+        if path.is_empty() {
+          debug!( Sim, "No path for {:?} to {:?}", id, goal );
+          hero.target_pos = None;
+        }
+
+        hero.path = path;
+        hero.path_goal = Some( goal );
+        hero.stuck_for = 0.0;
+      }
+    }
+  }
+
+  /// Notes which heroes didn't get much closer to where they're going since
+  /// `before` was taken.
+  fn track_progress( &mut self, before : Vec<(EntityId, Pos2)>
+                   , delta_time : f64 ) {
+    let dt = delta_time as f32;
+
+    for (id, old) in before {
+      if let Some( hero ) = self.world.hero_mut( id ) {
+        let moved = hero.entity.pos.sub_p( &old ).length();
+
+        if hero.target_pos.is_some()
+        && moved < hero.speed() * dt * STUCK_SPEED_FRACTION {
+          hero.stuck_for += dt;
+        } else {
+          hero.stuck_for = 0.0;
+        }
+      }
+    }
+  }
+
+  fn respawn_heroes( &mut self ) {
+    for id in self.world.ids() {
+      let spawn = match self.respawn_point( id ) {
+        Some( sp ) => sp,
+        None => continue
+      };
+      let respawned = match self.world.hero_mut( id ) {
+        Some( hero ) if hero.ready_to_respawn() => {
+          hero.respawn( spawn );
+          true
+        },
+        _ => false
+      };
+
+      if respawned {
+        self.send_sync( id );
+      }
+    }
+  }
+
+  fn send_controlled_hero_sync( &mut self ) {
+    let id = self.controller.hero_id;
+
+    if let Some( controlled_hero ) = self.world.get( id ).cloned() {
+      let sync_packet = SyncPacket::new( id, controlled_hero );
+      self.net_controller.send_sync_packet( sync_packet );
+    }
+  }
+
+  pub fn update( &mut self, delta_time : f64 ) {
+    // Send the instructions to the player's hero
+    // TODO: fold together spammed instructions
+    if self.controller.dirty && self.state.is_running() {
+      let mut ip = self.controller.instruction_packet.clone();
+      self.instruct_hero( ip );
+      ip = self.controller.instruction_packet.clone();
+      self.net_controller.send_instruction( ip );
+    }
+
+    let is_host = self.net_controller.is_host;
+
+    if self.net_controller.poke_packets() {
+      loop {
+        if let Some( u ) = self.net_controller.next() {
+          match u {
+            Packet::InstructionPacket( ip ) =>
+              self.instruct_hero( ip ),
+            Packet::SyncPacket( sp ) =>
+              self.sync_entity( sp ),
+            Packet::Ping => self.net_controller.handle_ping(),
+            Packet::Pong => self.net_controller.handle_pong(),
+            Packet::YourPing( p ) => self.net_controller.update_ping( p ),
+            // The host decides who dies, clients only hear about it
+            Packet::Death( dp ) => if !is_host {
+              self.kill( dp )
+            },
+            // Same for what's in the world
+            Packet::Spawn( sp ) => if !is_host {
+              self.world.insert( sp.id, sp.actor )
+            },
+            Packet::Despawn( id ) | Packet::Hide( id ) => if !is_host {
+              self.world.despawn( id );
+            },
+            // Only the host's own core check ends the match
+            Packet::MatchEnded( me ) => if !is_host {
+              self.match_ended( me )
+            },
+            Packet::MatchState( ms ) => if !is_host {
+              self.follow_state( ms )
+            },
+            // Clients can only say they're ready themselves
+            Packet::Ready( _ ) => if is_host {
+              self.peer_ready()
+            },
+            // Repeats of what was said in the lobby
+            Packet::Join( .. ) | Packet::Welcome( .. ) | Packet::Lobby( .. )
+            | Packet::Choice( .. ) | Packet::Start( .. ) => ()
+          }
+        } else {
+          break
+        }
+      }
+    }
+
+    if self.net_controller.poke_sync() {
+      self.send_controlled_hero_sync();
+
+      if self.net_controller.is_host {
+        self.send_host_syncs();
+      } else if self.state.is_starting() {
+        // Until the host has heard it, in case it got lost
+        self.net_controller.send_ready();
+      }
+    }
+
+    if self.net_controller.is_host {
+      self.update_state( delta_time );
+    } else {
+      self.state.tick( delta_time );
+    }
+
+    self.since_fog += delta_time;
+    if self.since_fog >= FOG_INTERVAL {
+      self.since_fog = 0.0;
+      self.fog.update( &self.world, &self.map );
+
+      if !self.net_controller.is_host {
+        self.forget_unseen();
+      }
+    }
+
+    // Nothing moves until the match starts, while it's paused or once it's
+    // over
+    if !self.state.is_running() {
+      self.controller.refresh();
+      return
+    }
+
+    if self.net_controller.is_host {
+      self.spawn_waves( delta_time );
+      self.update_minions();
+      self.update_towers();
+    }
+
+    self.update_attacks();
+    self.update_projectiles( delta_time );
+    self.update_statuses( delta_time );
+
+    self.update_paths();
+
+    let before = self.world.iter()
+                           .filter_map( |(&id, a)| match *a {
+                             Actor::Hero( ref h ) if h.target_pos.is_some() =>
+                               Some( (id, h.entity.pos) ),
+                             _ => None
+                           } )
+                           .collect::<Vec<_>>();
+
+    // Update all the heroes, minions and structures
+    for (_, actor) in self.world.iter_mut() {
+      match *actor {
+        Actor::Hero( ref mut hero ) => hero.update( delta_time ),
+        Actor::Minion( ref mut minion ) => minion.update( delta_time ),
+        Actor::Structure( ref mut s ) => s.update( delta_time ),
+        Actor::Projectile( .. ) => ()
+      }
+    }
+
+    // Keep units from walking through each other
+    let grid = Grid::build( &self.world );
+    collision::separate( &mut self.world, &grid );
+
+    for (_, actor) in self.world.iter_mut() {
+      if let Actor::Projectile( .. ) = *actor {
+        continue
+      }
+
+      self.map.push_out( actor.entity_mut() );
+    }
+
+    self.track_progress( before, delta_time );
+
+    if self.net_controller.is_host {
+      self.update_casts();
+      self.respawn_heroes();
+      self.update_vision();
+    }
+
+    self.controller.refresh();
+  }
+
+  /// Works out what each team sees now and tells the clients what came
+  /// into their team's sight and what went out of it, host only.
+  fn update_vision( &mut self ) {
+    let vision = Vision::compute( &self.world, &self.map );
+    let before = match self.net_controller.vision.take() {
+      Some( v ) => v,
+      None => Vision::everything( &self.world )
+    };
+
+    for &team in TEAMS.iter() {
+      for id in vision.revealed( &before, team ) {
+        if let Some( actor ) = self.world.get( id ).cloned() {
+          let spawn = Packet::Spawn( SpawnPacket { id : id, actor : actor } );
+          self.net_controller.send_to_team( team, spawn );
+        }
+      }
+
+      // Whatever's gone altogether was despawned for everyone that saw it
+      for id in vision.concealed( &before, team ) {
+        if self.world.get( id ).is_some() {
+          self.net_controller.send_to_team( team, Packet::Hide( id ) );
+        }
+      }
+    }
+
+    self.net_controller.vision = Some( vision );
+  }
+
+  /// Forgets the enemies our team can't see anymore, in case the host's
+  /// word on it got lost. Clients only, the host keeps simulating them.
+  fn forget_unseen( &mut self ) {
+    let seen = vision::visible_to( &self.world, &self.map, self.fog.team() );
+    let unseen = self.world.ids()
+                           .into_iter()
+                           .filter( |id| !seen.contains( id ) )
+                           .collect::<Vec<_>>();
+
+    for id in unseen {
+      trace!( Sim, "Lost sight of {:?}", id );
+      self.world.despawn( id );
+    }
+  }
+
+  fn overlay_lines( &self ) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    let id = self.controller.hero_id;
+    lines.push( format!( "hero {} on team {:?}", id.0
+                       , self.net_controller.roster.team_of( id ) ) );
+
+    for (peer, s) in self.net_controller.all_peer_stats() {
+      lines.push( format!( "{}", peer ) );
+      lines.push( format!( "  rtt: {}  loss: {:.1}%"
+                         , s.rtt.map( |r| format!( "{}ms", r ) )
+                                .unwrap_or( "?".to_string() )
+                         , s.loss * 100.0 ) );
+      lines.push( format!( "  in: {} pkt/s {} B/s  out: {} pkt/s {} B/s"
+                         , s.rate.packets_in, s.rate.bytes_in
+                         , s.rate.packets_out, s.rate.bytes_out ) );
+      lines.push( format!( "  snapshot age: {}"
+                         , s.snapshot_age().map( |a| format!( "{}ms", a ) )
+                                           .unwrap_or( "-".to_string() ) ) );
+    }
+
+    lines.push( format!( "queued packets: {}"
+                       , self.net_controller.queued_packets() ) );
+
+    lines
+  }
+
+  /// What's going on with the match, shown over the game when it isn't
+  /// running. Once it's over that's the result and everyone's stats.
+  fn state_lines( &self ) -> Vec<String> {
+    let mut lines = match self.state.describe() {
+      Some( d ) => vec![ d ],
+      None => return Vec::new()
+    };
+
+    if self.state.winner().is_none() {
+      return lines
+    }
+
+    for p in &self.stats.players {
+      let player = match self.net_controller.roster.player( p.hero_id ) {
+        Some( player ) => player,
+        None => continue
+      };
+
+      lines.push( format!( "{} ({:?}): {} kills, {} deaths, {} minions, \
+                            {} structures, {:.0} damage"
+                         , player.name, player.team
+                         , p.kills, p.deaths, p.minion_kills
+                         , p.structure_kills, p.damage_dealt ) );
+    }
+
+    lines
+  }
+
+  /// How long until the own hero comes back, shown while it's dead.
+  fn respawn_lines( &self ) -> Vec<String> {
+    match self.world.hero( self.controller.hero_id )
+                    .and_then( |h| h.respawn_in() ) {
+      Some( t ) => vec![ format!( "Respawning in {:.0}", t.ceil() ) ],
+      None => Vec::new()
+    }
+  }
+
+  /// The own hero's level, experience, gold, abilities and items, always
+  /// shown at the bottom of the screen.
+  fn hud_lines( &self ) -> Vec<String> {
+    let hero = match self.world.hero( self.controller.hero_id ) {
+      Some( h ) => h,
+      None => return Vec::new()
+    };
+
+    let xp = if hero.level >= progress::MAX_LEVEL {
+      "max".to_string()
+    } else {
+      format!( "{}/{}", hero.xp, progress::xp_to_next( hero.level ) )
+    };
+
+    let mut lines = vec![ format!( "level {}  xp {}  gold {}"
+                                 , hero.level, xp, hero.gold ) ];
+
+    let abilities = hero.def.abilities
+                        .iter()
+                        .zip( hero.ranks.iter() )
+                        .map( |(a, r)| format!( "{} {}", a.name, r ) )
+                        .collect::<Vec<_>>();
+    lines.push( abilities.join( "  " ) );
+
+    let keys = [ "Z", "X", "C", "V", "B", "N" ];
+    let items = hero.inventory.slots
+                    .iter()
+                    .zip( keys.iter() )
+                    .map( |(s, k)| match *s {
+                      Some( ref i ) if i.cooldown > 0.0 =>
+                        format!( "{}: {} ({:.0})", k, i.def.name, i.cooldown ),
+                      Some( ref i ) => format!( "{}: {}", k, i.def.name ),
+                      None => format!( "{}: -", k )
+                    } )
+                    .collect::<Vec<_>>();
+    lines.push( items.join( "  " ) );
+
+    if hero.ability_points > 0 {
+      lines.push( format!( "{} ability points, 1-4: learn"
+                         , hero.ability_points ) );
+    }
+
+    lines
+  }
+
+  /// What the shop sells, shown while it's open.
+  fn shop_lines( &self ) -> Vec<String> {
+    let mut lines = vec![ "Shop: 1-9 buy, Z-N sell, S closes".to_string() ];
+
+    let at_shop = self.world.hero( self.controller.hero_id )
+                            .map_or( false, |h| self.in_shop_range( h ) );
+    if !at_shop {
+      lines.push( "Only open at your base".to_string() );
+    }
+
+    for (i, item) in self.net_controller.items.items.iter().take( 9 )
+                                                   .enumerate() {
+      lines.push( format!( "{}: {} ({} gold)", i + 1, item.name, item.cost ) );
+    }
+
+    lines
+  }
+
+  pub fn draw( &mut self, w : &PistonWindow ) {
+    let overlay = if self.debug && self.glyphs.is_some() {
+      self.overlay_lines()
+    } else {
+      Vec::new()
+    };
+
+    let mut banner = self.state_lines();
+    banner.extend( self.respawn_lines() );
+    let hud = if self.glyphs.is_some() {
+      self.hud_lines()
+    } else {
+      Vec::new()
+    };
+    let covered = self.fog.covered();
+    let shop = if self.shop_open && self.glyphs.is_some() {
+      self.shop_lines()
+    } else {
+      Vec::new()
+    };
+
+    w.draw_2d( |c, g| {
+      clear( [1.0; 4], g );
+
+      for w in &self.map.walls {
+        let points = w.points.iter()
+                             .map( |p| [ p.x as f64, p.y as f64 ] )
+                             .collect::<Vec<_>>();
+        polygon( [0.5, 0.5, 0.5, 1.0], &points, c.transform, g );
+      }
+
+      for b in &self.map.bases {
+        let (x, y) = ( b.pos.x as f64, b.pos.y as f64 );
+        let mut color = b.team.color();
+        color[3] = 0.3;
+        rectangle( color, [ x - 20.0, y - 20.0, 40.0, 40.0 ], c.transform, g );
+      }
+
+      for f in &self.map.fountains {
+        let (x, y) = ( f.pos.x as f64, f.pos.y as f64 );
+        let mut color = f.team.color();
+        color[3] = 0.2;
+        ellipse( color, [ x - 15.0, y - 15.0, 30.0, 30.0 ], c.transform, g );
+      }
+
+      for (_, actor) in self.world.iter() {
+        // The host knows where everyone is, but the player shouldn't
+        if !self.shows( actor ) {
+          continue
+        }
+
+        if !actor.is_targetable() {
+          if let Actor::Projectile( ref p ) = *actor {
+            let r = collision::bounding_radius( &p.entity.hitbox ) as f64;
+            let (x, y) = ( p.entity.pos.x as f64, p.entity.pos.y as f64 );
+
+            ellipse( [0.1, 0.1, 0.1, 1.0], [ x - r, y - r, r * 2.0, r * 2.0 ]
+                   , c.transform, g );
+          }
+
+          continue
+        }
+
+        let pos = actor.entity().pos;
+        let (x, y) = ( pos.x as f64, pos.y as f64 );
+
+        match *actor {
+          Actor::Hero( ref hero ) => {
+            // The hero's own color, ringed in its team's
+            let r = hero.def.radius as f64;
+            ellipse( hero.team.color()
+                   , [ x - r - 2.0, y - r - 2.0, r * 2.0 + 4.0, r * 2.0 + 4.0 ]
+                   , c.transform, g );
+            ellipse( hero.def.color, [ x - r, y - r, r * 2.0, r * 2.0 ]
+                   , c.transform, g );
+
+            // Mana bar
+            let fraction = ( hero.mana / hero.max_mana() ) as f64;
+            rectangle( [0.0, 0.3, 0.9, 1.0]
+                     , [ x - 10.0, y - 8.0, 20.0 * fraction, 2.0 ]
+                     , c.transform, g );
+
+            // Show melee attacks as a short flash of a line to the target,
+            // ranged ones show up as projectiles
+            if let Some( t ) = hero.attack.target {
+              if hero.attack.flash > 0.0 && !hero.attack.is_ranged() {
+                if let Some( tp ) = self.world.pos( t ) {
+                  line( [0.0, 0.0, 0.0, 1.0], 1.0
+                      , [ x, y, tp.x as f64, tp.y as f64 ]
+                      , c.transform, g );
+                }
+              }
+            }
+          },
+          Actor::Minion( ref m ) =>
+            rectangle( m.team.color(), [ x - 4.0, y - 4.0, 8.0, 8.0 ]
+                     , c.transform, g ),
+          Actor::Structure( ref s ) => {
+            let half = match s.entity.hitbox {
+              Hitbox::Aabb( h ) => h.x as f64,
+              _ => 12.0
+            };
+            rectangle( s.team.color()
+                     , [ x - half, y - half, half * 2.0, half * 2.0 ]
+                     , c.transform, g );
+
+            // Mark the core so it stands out from the other buildings
+            if s.kind == StructureKind::Core {
+              rectangle( [1.0; 4], [ x - 4.0, y - 4.0, 8.0, 8.0 ]
+                       , c.transform, g );
+            }
+          },
+          Actor::Projectile( _ ) => {}
+        }
+
+        // A pip for each status
+        if let Some( statuses ) = actor.statuses() {
+          for (i, e) in statuses.effects.iter().enumerate() {
+            rectangle( status_color( &e.status )
+                     , [ x - 10.0 + 4.0 * i as f64, y + 8.0, 3.0, 3.0 ]
+                     , c.transform, g );
+          }
+        }
+
+        // Health bar
+        if let Some( health ) = actor.health() {
+          let fraction = ( health.hp / health.max_hp ) as f64;
+          rectangle( [0.8, 0.0, 0.0, 1.0], [ x - 10.0, y - 11.0, 20.0, 3.0 ]
+                   , c.transform, g );
+          rectangle( [0.0, 0.8, 0.0, 1.0]
+                   , [ x - 10.0, y - 11.0, 20.0 * fraction, 3.0 ]
+                   , c.transform, g );
+        }
+      }
+
+      // Darker where the team hasn't been yet
+      for &(cell, explored) in &covered {
+        let alpha = if explored { 0.3 } else { 0.6 };
+        rectangle( [0.0, 0.0, 0.0, alpha], cell, c.transform, g );
+      }
+
+      if let Some( ref mut glyphs ) = self.glyphs {
+        let text = Text::new_color( [0.0, 0.0, 0.0, 1.0], 12 );
+
+        for (i, line) in overlay.iter().enumerate() {
+          text.draw( line, glyphs, &c.draw_state
+                   , c.transform.trans( 8.0, 16.0 + 14.0 * i as f64 ), g );
+        }
+
+        let big = Text::new_color( [0.0, 0.0, 0.0, 1.0], 16 );
+        for (i, line) in banner.iter().enumerate() {
+          big.draw( line, glyphs, &c.draw_state
+                  , c.transform.trans( 120.0, 200.0 + 20.0 * i as f64 ), g );
+        }
+
+        // Counted up from the bottom
+        for (i, line) in hud.iter().rev().enumerate() {
+          text.draw( line, glyphs, &c.draw_state
+                   , c.transform.trans( 8.0, 470.0 - 14.0 * i as f64 ), g );
+        }
+
+        for (i, line) in shop.iter().enumerate() {
+          text.draw( line, glyphs, &c.draw_state
+                   , c.transform.trans( 420.0, 16.0 + 14.0 * i as f64 ), g );
+        }
+      }
+    } );
+  }
+}
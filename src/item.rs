@@ -0,0 +1,231 @@
+use std::path::Path;
+use std::sync::Arc;
+use ability::AbilityDef;
+use archetype::{read_sources, parse_targeting, parse_effect};
+use map::{hash_bytes, parse_f32};
+
+// How many items a hero can carry, their actives are bound to Z, X, C, V, B
+// and N in that order
+pub const INVENTORY_SLOTS : usize = 6;
+// How close to its team's base a hero has to be to buy and sell
+pub const SHOP_RANGE : f32 = 60.0;
+// What selling an item gets back of what it cost
+pub const SELL_FRACTION : f32 = 0.5;
+
+/// What carrying an item adds to a hero's stats.
+#[derive(Clone, Copy, Debug)]
+pub struct Bonus {
+  pub hp     : f32,
+  pub armor  : f32,
+  pub mana   : f32,
+  pub damage : f32,
+  pub speed  : f32
+}
+
+impl Bonus {
+  pub fn none() -> Bonus {
+    Bonus { hp : 0.0, armor : 0.0, mana : 0.0, damage : 0.0, speed : 0.0 }
+  }
+
+  pub fn add( &self, other : &Bonus ) -> Bonus {
+    Bonus { hp     : self.hp + other.hp
+          , armor  : self.armor + other.armor
+          , mana   : self.mana + other.mana
+          , damage : self.damage + other.damage
+          , speed  : self.speed + other.speed }
+  }
+}
+
+/// Everything about a kind of item that doesn't change during a match.
+#[derive(Clone, Debug)]
+pub struct ItemDef {
+  // Index into the `ItemBook`, what goes over the wire
+  pub id     : u8,
+  pub name   : String,
+  pub cost   : u32,
+  pub bonus  : Bonus,
+  // Used like an ability, without mana or cast time
+  pub active : Option<AbilityDef>
+}
+
+/// All the items the shops sell. Everyone has to have the same ones, which
+/// the hash lets peers check.
+#[derive(Debug)]
+pub struct ItemBook {
+  pub items : Vec<Arc<ItemDef>>,
+  pub hash  : u64
+}
+
+fn parse_active( args : &[&str] ) -> Result<AbilityDef, String> {
+  if args.len() < 4 {
+    return Err( "expected a targeting, range, cooldown and effect"
+                  .to_string() )
+  }
+
+  Ok( AbilityDef { name       : String::new()
+                 , targeting  : try!( parse_targeting( args[0] ) )
+                 , cast_range : try!( parse_f32( args[1] ) )
+                 , cost       : 0.0
+                 , cooldown   : try!( parse_f32( args[2] ) )
+                 , cast_time  : 0.0
+                 , effect     : try!( parse_effect( &args[3..] ) ) } )
+}
+
+impl ItemDef {
+  /// Parses an item, one definition per line with `#` starting comments:
+  ///
+  /// ```text
+  /// name <name>
+  /// cost <gold>
+  /// bonus hp|armor|mana|damage|speed <amount>
+  /// active <targeting> <range> <cooldown> <effect>
+  /// ```
+  ///
+  /// There can be any number of bonuses and at most one active, which is
+  /// aimed and does the same things as an ability, see `HeroDef::parse`.
+  pub fn parse( id : u8, source : &str ) -> Result<ItemDef, String> {
+    let mut def = ItemDef { id     : id
+                          , name   : String::new()
+                          , cost   : 0
+                          , bonus  : Bonus::none()
+                          , active : None };
+
+    for (n, line) in source.lines().enumerate() {
+      let line = line.split( '#' ).next().unwrap().trim();
+      let words = line.split_whitespace().collect::<Vec<_>>();
+
+      if words.is_empty() {
+        continue
+      }
+
+      let (kind, args) = ( words[0], &words[1..] );
+      let err = |e : String| format!( "line {}: {}", n + 1, e );
+
+      match kind {
+        "name" if args.len() == 1 =>
+          def.name = args[0].to_string(),
+        "cost" if args.len() == 1 =>
+          def.cost = try!( args[0].parse().map_err( |_|
+                             err( format!( "Invalid cost: {}", args[0] ) ) ) ),
+        "bonus" if args.len() == 2 => {
+          let amount = try!( parse_f32( args[1] ).map_err( &err ) );
+          match args[0] {
+            "hp"     => def.bonus.hp += amount,
+            "armor"  => def.bonus.armor += amount,
+            "mana"   => def.bonus.mana += amount,
+            "damage" => def.bonus.damage += amount,
+            "speed"  => def.bonus.speed += amount,
+            _ => return Err( err( format!( "Invalid bonus: {}", args[0] ) ) )
+          }
+        },
+        "active" if def.active.is_none() =>
+          def.active = Some( try!( parse_active( args ).map_err( &err ) ) ),
+        _ =>
+          return Err( err( format!( "Invalid definition: {}", line ) ) )
+      }
+    }
+
+    if def.name.is_empty() {
+      return Err( "The item has no name".to_string() )
+    }
+
+    // Actives go by the item's name
+    if let Some( ref mut a ) = def.active {
+      a.name = def.name.clone();
+    }
+
+    Ok( def )
+  }
+}
+
+impl ItemBook {
+  /// Loads every `.item` file in `dir`, see `ItemDef::parse` for the
+  /// format. There don't have to be any.
+  pub fn load<P : AsRef<Path>>( dir : P ) -> Result<ItemBook, String> {
+    let sources = try!( read_sources( dir.as_ref(), "item" ) );
+
+    let mut items = Vec::new();
+    let mut all = String::new();
+
+    if sources.len() > 256 {
+      return Err( "There can't be more than 256 items".to_string() )
+    }
+
+    for (path, source) in sources {
+      let def = try!( ItemDef::parse( items.len() as u8, &source )
+                        .map_err( |e| format!( "{}: {}"
+                                             , path.display(), e ) ) );
+
+      if items.iter().any( |i : &Arc<ItemDef>| i.name == def.name ) {
+        return Err( format!( "{}: There's already an item called {}"
+                           , path.display(), def.name ) )
+      }
+
+      all.push_str( &source );
+      items.push( Arc::new( def ) );
+    }
+
+    Ok( ItemBook { items : items
+                 , hash  : hash_bytes( all.as_bytes() ) } )
+  }
+
+  pub fn get( &self, id : u8 ) -> Option<&Arc<ItemDef>> {
+    self.items.get( id as usize )
+  }
+}
+
+/// An item in a hero's inventory.
+#[derive(Clone, Debug)]
+pub struct Item {
+  pub def      : Arc<ItemDef>,
+  // Until the active can be used again
+  pub cooldown : f32
+}
+
+/// What a hero carries, a fixed number of slots that can be empty.
+#[derive(Clone, Debug)]
+pub struct Inventory {
+  pub slots : Vec<Option<Item>>
+}
+
+impl Inventory {
+  pub fn new() -> Inventory {
+    Inventory { slots : vec![ None; INVENTORY_SLOTS ] }
+  }
+
+  pub fn get( &self, slot : usize ) -> Option<&Item> {
+    self.slots.get( slot ).and_then( |s| s.as_ref() )
+  }
+
+  pub fn get_mut( &mut self, slot : usize ) -> Option<&mut Item> {
+    self.slots.get_mut( slot ).and_then( |s| s.as_mut() )
+  }
+
+  /// Puts the item in the first free slot, which is returned.
+  pub fn add( &mut self, def : Arc<ItemDef> ) -> Result<usize, &'static str> {
+    match self.slots.iter().position( |s| s.is_none() ) {
+      Some( i ) => {
+        self.slots[i] = Some( Item { def : def, cooldown : 0.0 } );
+        Ok( i )
+      },
+      None => Err( "inventory is full" )
+    }
+  }
+
+  pub fn remove( &mut self, slot : usize ) -> Option<Item> {
+    self.slots.get_mut( slot ).and_then( |s| s.take() )
+  }
+
+  /// What all the items add up to.
+  pub fn bonus( &self ) -> Bonus {
+    self.slots.iter()
+              .filter_map( |s| s.as_ref() )
+              .fold( Bonus::none(), |b, i| b.add( &i.def.bonus ) )
+  }
+
+  pub fn update( &mut self, delta_time : f64 ) {
+    for item in self.slots.iter_mut().filter_map( |s| s.as_mut() ) {
+      item.cooldown = ( item.cooldown - delta_time as f32 ).max( 0.0 );
+    }
+  }
+}
@@ -0,0 +1,363 @@
+use std::net::SocketAddr;
+use piston_window::*;
+use super::NetController;
+use packet::{Packet, Welcome};
+use map::Map;
+use team::{Team, TEAMS, Player, Roster};
+use world::EntityId;
+use game::Game;
+
+// Seconds between repeats of what we last said, in case it got lost
+const RESEND_INTERVAL : f64 = 0.5;
+
+#[derive(Clone, Debug)]
+pub struct LobbyPlayer {
+  pub player : Player,
+  pub ready  : bool
+}
+
+/// What a player picked for themselves in the lobby.
+#[derive(Clone, Debug)]
+pub struct LobbyChoice {
+  pub team  : Team,
+  pub hero  : String,
+  pub ready : bool
+}
+
+/// Where players gather before a match. Clients keep asking to join until
+/// the host welcomes them, then send their picks, and the host keeps
+/// everyone up to date on who's there. The host starts the match once
+/// everyone's ready.
+pub struct Lobby {
+  net_controller : NetController,
+  map            : Map,
+  name           : String,
+  choice         : LobbyChoice,
+  // Everyone in the lobby, the host's list is the one that counts
+  players        : Vec<LobbyPlayer>,
+  // Which client plays which hero, host only
+  members        : Vec<(SocketAddr, EntityId)>,
+  // Whether the host has let us in, always true for the host
+  joined         : bool,
+  started        : Option<Roster>,
+  error          : Option<String>,
+  since_resend   : f64,
+  glyphs         : Option<Glyphs>
+}
+
+impl Lobby {
+  pub fn new( mut nc : NetController, map : Map, name : String, team : Team
+            , glyphs : Option<Glyphs> ) -> Lobby {
+    let choice = LobbyChoice { team  : team
+                             , hero  : nc.heroes.default_hero().name.clone()
+                             , ready : false };
+
+    let mut players = Vec::new();
+    let is_host = nc.is_host;
+
+    // The host is always in its own lobby, and always plays hero ID 0
+    if is_host {
+      nc.assigned_hero_id = EntityId( 0 );
+      players.push( LobbyPlayer { player : Player { hero_id : EntityId( 0 )
+                                                  , team    : team
+                                                  , name    : name.clone()
+                                                  , hero    : choice.hero.clone() }
+                                , ready  : false } );
+    }
+
+    Lobby { net_controller : nc
+          , map            : map
+          , name           : name
+          , choice         : choice
+          , players        : players
+          , members        : Vec::new()
+          , joined         : is_host
+          , started        : None
+          , error          : None
+          , since_resend   : RESEND_INTERVAL
+          , glyphs         : glyphs }
+  }
+
+  pub fn is_started( &self ) -> bool {
+    self.started.is_some()
+  }
+
+  /// Why we can't play, e.g. because the host is on a different map.
+  pub fn error( &self ) -> Option<&String> {
+    self.error.as_ref()
+  }
+
+  /// Hands everything over to the match the lobby started.
+  pub fn into_game( self ) -> Game {
+    let mut nc = self.net_controller;
+    nc.roster = self.started.expect( "the match hasn't started" );
+    Game::new( nc, self.map, self.glyphs )
+  }
+
+  pub fn input_press( &mut self, button : Button ) {
+    let mut choice = self.choice.clone();
+
+    match button {
+      // Picks are locked in while ready
+      Button::Keyboard( Key::Tab ) if !choice.ready =>
+        choice.team = choice.team.opponent(),
+      Button::Keyboard( Key::H ) if !choice.ready => {
+        let heroes = &self.net_controller.heroes.heroes;
+        let i = heroes.iter()
+                      .position( |h| h.name == choice.hero )
+                      .unwrap_or( 0 );
+        choice.hero = heroes[( i + 1 ) % heroes.len()].name.clone();
+      },
+      Button::Keyboard( Key::Space ) =>
+        choice.ready = !choice.ready,
+      Button::Keyboard( Key::Return ) if self.net_controller.is_host =>
+        self.start(),
+      _ => ()
+    }
+
+    self.choice = choice;
+
+    if self.net_controller.is_host {
+      let id = self.net_controller.assigned_hero_id;
+      let choice = self.choice.clone();
+      self.apply_choice( id, choice );
+    } else if self.joined {
+      let choice = self.choice.clone();
+      self.net_controller.send_choice( choice );
+    }
+  }
+
+  pub fn update( &mut self, delta_time : f64 ) {
+    self.net_controller.poke_sync();
+
+    while self.net_controller.poke_packets() {
+      let p = match self.net_controller.next() {
+        Some( p ) => p,
+        None => break
+      };
+
+      let sender = self.net_controller.last_sender();
+
+      match p {
+        Packet::Ping => self.net_controller.handle_ping(),
+        Packet::Pong => self.net_controller.handle_pong(),
+        Packet::YourPing( p ) => self.net_controller.update_ping( p ),
+        Packet::Join( name ) => if let Some( s ) = sender {
+          self.join( s, name )
+        },
+        Packet::Welcome( w ) => self.welcomed( w ),
+        Packet::Choice( c ) => {
+          let member = self.members.iter()
+                                   .find( |&&(a, _)| Some( a ) == sender )
+                                   .map( |&(_, id)| id );
+          if let Some( id ) = member {
+            self.apply_choice( id, c );
+          }
+        },
+        Packet::Lobby( players ) => if !self.net_controller.is_host {
+          self.players = players
+        },
+        Packet::Start( roster ) => if !self.net_controller.is_host {
+          info!( Net, "The host started the match" );
+          self.started = Some( roster );
+        },
+        p => trace!( Net, "Ignoring {:?} in the lobby", p )
+      }
+    }
+
+    self.since_resend += delta_time;
+    if self.since_resend >= RESEND_INTERVAL {
+      self.since_resend = 0.0;
+      self.resend();
+    }
+  }
+
+  /// Repeats whatever the others need to hear from us.
+  fn resend( &mut self ) {
+    if self.net_controller.is_host {
+      let players = self.players.clone();
+      self.net_controller.send_lobby( players );
+    } else if self.joined {
+      let choice = self.choice.clone();
+      self.net_controller.send_choice( choice );
+    } else {
+      let name = self.name.clone();
+      self.net_controller.send_join( name );
+    }
+  }
+
+  /// Lets a client in, or welcomes it again if it didn't hear us the first
+  /// time. Host only.
+  fn join( &mut self, peer : SocketAddr, name : String ) {
+    if !self.net_controller.is_host {
+      return
+    }
+
+    let id = match self.members.iter().find( |&&(a, _)| a == peer ) {
+      Some( &(_, id) ) => id,
+      None => {
+        let id = EntityId( self.players.len() as u32 );
+        info!( Net, "{} joined the lobby from {}", name, peer );
+
+        // Fill up the smaller team
+        let team = *TEAMS.iter()
+                         .min_by_key( |&&t| self.players.iter()
+                                                 .filter( |p| p.player.team == t )
+                                                 .count() )
+                         .unwrap();
+        let hero = self.net_controller.heroes.default_hero().name.clone();
+
+        self.players.push(
+          LobbyPlayer { player : Player { hero_id : id
+                                        , team    : team
+                                        , name    : name
+                                        , hero    : hero }
+                      , ready  : false } );
+        self.members.push( (peer, id) );
+        self.net_controller.peer_heroes.insert( peer, id );
+        self.net_controller.add_peer( peer );
+        id
+      }
+    };
+
+    let welcome = Welcome { your_id     : id
+                          , map_name    : self.map.name.clone()
+                          , map_hash    : self.map.hash
+                          , heroes_hash : self.net_controller.heroes.hash
+                          , items_hash  : self.net_controller.items.hash };
+    self.net_controller.send_welcome( peer, welcome );
+
+    let players = self.players.clone();
+    self.net_controller.send_lobby( players );
+  }
+
+  /// The host let us in, client only.
+  fn welcomed( &mut self, w : Welcome ) {
+    if self.joined {
+      return
+    }
+
+    if w.map_name != self.map.name || w.map_hash != self.map.hash {
+      self.error = Some( format!( "The host is playing on map {} ({:x}), but \
+                                   we have {} ({:x})"
+                                , w.map_name, w.map_hash
+                                , self.map.name, self.map.hash ) );
+      return
+    }
+
+    if w.heroes_hash != self.net_controller.heroes.hash {
+      self.error = Some( format!( "The host has different heroes ({:x}) than \
+                                   we do ({:x})"
+                                , w.heroes_hash
+                                , self.net_controller.heroes.hash ) );
+      return
+    }
+
+    if w.items_hash != self.net_controller.items.hash {
+      self.error = Some( format!( "The host has different items ({:x}) than \
+                                   we do ({:x})"
+                                , w.items_hash
+                                , self.net_controller.items.hash ) );
+      return
+    }
+
+    info!( Net, "Joined the lobby as hero {:?}", w.your_id );
+    self.net_controller.assigned_hero_id = w.your_id;
+    self.joined = true;
+
+    let choice = self.choice.clone();
+    self.net_controller.send_choice( choice );
+  }
+
+  /// Takes a player's picks, host only.
+  fn apply_choice( &mut self, id : EntityId, choice : LobbyChoice ) {
+    if self.net_controller.heroes.find( &choice.hero ).is_none() {
+      warn!( Net, "Hero {:?} picked a hero we don't have: {}", id, choice.hero );
+      return
+    }
+
+    let changed = match self.players.iter_mut()
+                                    .find( |p| p.player.hero_id == id ) {
+      Some( p ) => {
+        let before = ( p.player.team, p.player.hero.clone(), p.ready );
+
+        // Picks can't change while ready, a late packet could still try
+        if !p.ready || !choice.ready {
+          p.player.team = choice.team;
+          p.player.hero = choice.hero;
+        }
+        p.ready = choice.ready;
+
+        before != ( p.player.team, p.player.hero.clone(), p.ready )
+      },
+      None => false
+    };
+
+    if changed {
+      let players = self.players.clone();
+      self.net_controller.send_lobby( players );
+    }
+  }
+
+  /// Starts the match if everyone's ready, host only.
+  fn start( &mut self ) {
+    if !self.players.iter().all( |p| p.ready ) {
+      info!( Net, "Not everyone is ready yet" );
+      return
+    }
+
+    let roster = Roster { players : self.players.iter()
+                                                .map( |p| p.player.clone() )
+                                                .collect() };
+    info!( Net, "Starting the match with {} players", roster.players.len() );
+
+    self.net_controller.send_start( roster.clone() );
+    self.started = Some( roster );
+  }
+
+  fn lines( &self ) -> Vec<(String, [f32; 4])> {
+    let black = [0.0, 0.0, 0.0, 1.0];
+    let mut lines = Vec::new();
+
+    if !self.joined {
+      lines.push( ( "Joining...".to_string(), black ) );
+      return lines
+    }
+
+    lines.push( ( format!( "Lobby on {}", self.map.name ), black ) );
+
+    for p in &self.players {
+      let you = p.player.hero_id == self.net_controller.assigned_hero_id;
+      lines.push( ( format!( "{}{}  {:?}  {}  {}"
+                           , p.player.name, if you { " (you)" } else { "" }
+                           , p.player.team, p.player.hero
+                           , if p.ready { "ready" } else { "not ready" } )
+                  , p.player.team.color() ) );
+    }
+
+    lines.push( ( "Tab: switch team  H: pick hero  Space: ready".to_string()
+                , black ) );
+
+    if self.net_controller.is_host {
+      lines.push( ( "Enter: start once everyone's ready".to_string(), black ) );
+    }
+
+    lines
+  }
+
+  pub fn draw( &mut self, w : &PistonWindow ) {
+    let lines = self.lines();
+
+    w.draw_2d( |c, g| {
+      clear( [1.0; 4], g );
+
+      if let Some( ref mut glyphs ) = self.glyphs {
+        for (i, &(ref line, color)) in lines.iter().enumerate() {
+          Text::new_color( color, 14 ).draw(
+            line, glyphs, &c.draw_state
+          , c.transform.trans( 40.0, 60.0 + 20.0 * i as f64 ), g );
+        }
+      }
+    } );
+  }
+}
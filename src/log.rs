@@ -0,0 +1,256 @@
+use std::io;
+use std::io::Write;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::sync::{Mutex, Once, ONCE_INIT};
+use time;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+  Error,
+  Warn,
+  Info,
+  Debug,
+  Trace
+}
+
+impl Level {
+  pub fn from_str( s : &str ) -> Option<Level> {
+    match s {
+      "error" => Some( Level::Error ),
+      "warn"  => Some( Level::Warn ),
+      "info"  => Some( Level::Info ),
+      "debug" => Some( Level::Debug ),
+      "trace" => Some( Level::Trace ),
+      _ => None
+    }
+  }
+
+  fn name( self ) -> &'static str {
+    match self {
+      Level::Error => "ERROR",
+      Level::Warn  => "WARN",
+      Level::Info  => "INFO",
+      Level::Debug => "DEBUG",
+      Level::Trace => "TRACE"
+    }
+  }
+}
+
+/// The parts of the game that log, each one can be given its own level.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Target {
+  Net,
+  Packet,
+  Sim,
+  Render
+}
+
+const TARGET_COUNT : usize = 4;
+
+impl Target {
+  pub fn from_str( s : &str ) -> Option<Target> {
+    match s {
+      "net"    => Some( Target::Net ),
+      "packet" => Some( Target::Packet ),
+      "sim"    => Some( Target::Sim ),
+      "render" => Some( Target::Render ),
+      _ => None
+    }
+  }
+
+  fn name( self ) -> &'static str {
+    match self {
+      Target::Net    => "net",
+      Target::Packet => "packet",
+      Target::Sim    => "sim",
+      Target::Render => "render"
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+  pub levels        : [Level; TARGET_COUNT],
+  pub file          : Option<String>,
+  pub max_file_size : u64,
+  pub kept_files    : usize
+}
+
+impl LogConfig {
+  /// Applies a `--log` spec to the config. The spec is a comma separated
+  /// list of either a level for every target, or `target=level` pairs,
+  /// e.g. `warn,net=debug,packet=trace`.
+  pub fn apply_spec( &mut self, spec : &str ) -> Result<(), String> {
+    for part in spec.split( ',' ).filter( |p| !p.is_empty() ) {
+      let mut kv = part.splitn( 2, '=' );
+      let first = kv.next().unwrap();
+
+      match kv.next() {
+        None => {
+          let level = try!( Level::from_str( first ).ok_or(
+                              format!( "Unknown log level: {}", first ) ) );
+          self.levels = [level; TARGET_COUNT];
+        },
+        Some( l ) => {
+          let target = try!( Target::from_str( first ).ok_or(
+                               format!( "Unknown log target: {}", first ) ) );
+          let level = try!( Level::from_str( l ).ok_or(
+                              format!( "Unknown log level: {}", l ) ) );
+          self.levels[target as usize] = level;
+        }
+      }
+    }
+
+    Ok( () )
+  }
+}
+
+impl Default for LogConfig {
+  fn default() -> LogConfig {
+    LogConfig { levels        : [Level::Info; TARGET_COUNT]
+              , file          : None
+              , max_file_size : 8 * 1024 * 1024
+              , kept_files    : 4 }
+  }
+}
+
+// A log file that gets moved to `<path>.1` (and so on) once it gets too big
+struct RotatingFile {
+  path     : String,
+  file     : File,
+  size     : u64,
+  max_size : u64,
+  kept     : usize
+}
+
+impl RotatingFile {
+  fn open( path : &str, max_size : u64, kept : usize )
+    -> io::Result<RotatingFile> {
+    let file = try!( OpenOptions::new().write( true ).append( true )
+                                       .create( true ).open( path ) );
+    let size = try!( file.metadata() ).len();
+
+    Ok( RotatingFile { path     : path.to_string()
+                     , file     : file
+                     , size     : size
+                     , max_size : max_size
+                     , kept     : kept } )
+  }
+
+  fn rotate( &mut self ) -> io::Result<()> {
+    for i in ( 1..self.kept ).rev() {
+      let from = format!( "{}.{}", self.path, i );
+      // Missing older files are fine, we just haven't rotated that far yet
+      let _ = fs::rename( &from, format!( "{}.{}", self.path, i + 1 ) );
+    }
+
+    try!( fs::rename( &self.path, format!( "{}.1", self.path ) ) );
+
+    self.file = try!( File::create( &self.path ) );
+    self.size = 0;
+
+    Ok( () )
+  }
+
+  fn write_line( &mut self, line : &str ) -> io::Result<()> {
+    if self.size + line.len() as u64 > self.max_size && self.size > 0 {
+      try!( self.rotate() );
+    }
+
+    try!( self.file.write_all( line.as_bytes() ) );
+    self.size += line.len() as u64;
+
+    Ok( () )
+  }
+}
+
+struct Logger {
+  levels : [Level; TARGET_COUNT],
+  file   : Option<Mutex<RotatingFile>>
+}
+
+static INIT : Once = ONCE_INIT;
+static mut LOGGER : *const Logger = 0 as *const Logger;
+
+/// Sets up logging, only the first call has any effect. Until it's called
+/// everything at `Info` and above goes to stderr.
+pub fn init( config : LogConfig ) -> io::Result<()> {
+  let file = match config.file {
+    Some( ref p ) =>
+      Some( Mutex::new(
+        try!( RotatingFile::open( p, config.max_file_size
+                                , config.kept_files ) ) ) ),
+    None => None
+  };
+
+  let logger = Box::new( Logger { levels : config.levels, file : file } );
+
+  INIT.call_once( || unsafe {
+    LOGGER = Box::into_raw( logger );
+  } );
+
+  Ok( () )
+}
+
+fn logger() -> Option<&'static Logger> {
+  unsafe {
+    if LOGGER.is_null() {
+      None
+    } else {
+      Some( &*LOGGER )
+    }
+  }
+}
+
+pub fn enabled( target : Target, level : Level ) -> bool {
+  match logger() {
+    Some( l ) => level <= l.levels[target as usize],
+    None => level <= Level::Info
+  }
+}
+
+pub fn log( target : Target, level : Level, args : fmt::Arguments ) {
+  if !enabled( target, level ) {
+    return
+  }
+
+  let now = time::now();
+  let line = format!( "[{:02}:{:02}:{:02}.{:03} {:<5} {}] {}\n"
+                    , now.tm_hour, now.tm_min, now.tm_sec
+                    , now.tm_nsec / 1000000
+                    , level.name(), target.name(), args );
+
+  let _ = io::stderr().write_all( line.as_bytes() );
+
+  if let Some( &Logger { file : Some( ref f ), .. } ) = logger() {
+    let _ = f.lock().unwrap().write_line( &line );
+  }
+}
+
+macro_rules! log {
+  ( $target:ident, $level:ident, $( $arg:tt )+ ) => (
+    ::log::log( ::log::Target::$target, ::log::Level::$level
+              , format_args!( $( $arg )+ ) )
+  )
+}
+
+macro_rules! error {
+  ( $target:ident, $( $arg:tt )+ ) => ( log!( $target, Error, $( $arg )+ ) )
+}
+
+macro_rules! warn {
+  ( $target:ident, $( $arg:tt )+ ) => ( log!( $target, Warn, $( $arg )+ ) )
+}
+
+macro_rules! info {
+  ( $target:ident, $( $arg:tt )+ ) => ( log!( $target, Info, $( $arg )+ ) )
+}
+
+macro_rules! debug {
+  ( $target:ident, $( $arg:tt )+ ) => ( log!( $target, Debug, $( $arg )+ ) )
+}
+
+macro_rules! trace {
+  ( $target:ident, $( $arg:tt )+ ) => ( log!( $target, Trace, $( $arg )+ ) )
+}
@@ -5,9 +5,32 @@ extern crate rand;
 extern crate capnp;
 extern crate time;
 
+#[macro_use]
+mod log;
 mod packet;
 mod entity;
 mod udpstream;
+mod transport;
+mod netsim;
+mod verbosestream;
+mod netstats;
+mod ability;
+mod archetype;
+mod item;
+mod projectile;
+mod status;
+mod vision;
+mod progress;
+mod world;
+mod collision;
+mod map;
+mod nav;
+mod team;
+mod wave;
+mod stats;
+mod matchstate;
+mod lobby;
+mod game;
 
 use piston_window::*;
 use std::default::Default;
@@ -15,11 +38,29 @@ use std::net::{TcpListener, TcpStream, UdpSocket, SocketAddr, ToSocketAddrs};
 use std::thread;
 use std::io::{BufReader, BufWriter, Read, Write};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use time::{Timespec, get_time};
 
-use packet::{Packet, InstructionPacket, SyncPacket, net_thread};
-use entity::{Hero, Pos2, Vec2};
+use packet::{Packet, Incoming, InstructionPacket, Order, SyncPacket
+            , SpawnPacket, DeathPacket, MatchEndedPacket, Welcome, net_thread};
 use udpstream::{BufUdpStream, UdpStream};
+use transport::{Transport, UdpTransport};
+use netsim::{NetConditions, SimulatedTransport};
+use verbosestream::VerboseTransport;
+use netstats::{MeteredTransport, TrafficCounters, PeerStats, now_ms};
+use log::LogConfig;
+use world::{EntityId, Actor};
+use game::Game;
+use map::Map;
+use archetype::HeroBook;
+use item::ItemBook;
+use team::{Team, Roster};
+use matchstate::MatchState;
+use vision::Vision;
+use lobby::{Lobby, LobbyPlayer, LobbyChoice};
 
 mod packets_capnp {
   include!( concat!( env!("OUT_DIR"), "/packets_capnp.rs" ) );
@@ -27,15 +68,115 @@ mod packets_capnp {
 
 const GAME_TITLE : &'static str = "Mubah - v0.1.0";
 
+pub const HOST_PORT : u16 = 4114;
+pub const CLIENT_PORT : u16 = 4004;
+
 #[derive(Clone)]
 struct GameSettings {
   pub resolution : [u32; 2],
   pub fullscreen : bool,
   pub vsync      : bool,
-  pub host       : Option<String>
+  pub host       : Option<String>,
+  pub net        : NetConditions,
+  pub trace      : bool,
+  pub font       : String,
+  pub log        : LogConfig,
+  pub map        : String,
+  pub heroes     : String,
+  pub items      : String,
+  pub team       : Team,
+  pub name       : String,
+  pub port       : Option<u16>
+}
+
+const USAGE : &'static str = "\
+Usage: mubah [options] [host]
+
+Hosts a game when no host is given, otherwise joins the one at `host`.
+
+Options:
+  --trace         Log every datagram sent and received (toggle in game with T)
+  --font PATH     Font used by the debug overlay (toggle in game with D)
+  --log SPEC      Log levels, either one for everything or per target, e.g.
+                  `warn,net=debug,packet=trace`. Targets are net, packet,
+                  sim and render, levels are error, warn, info, debug, trace
+  --log-file PATH Also write the log to PATH, rotated once it gets too big
+  --map PATH      Map to play on, has to be the same one the host uses
+  --heroes DIR    Directory with the hero definitions, has to have the same
+                  ones the host has
+  --items DIR     Directory with the item definitions, same as for heroes
+  --team TEAM     Team to start out on in the lobby, blue or red
+  --name NAME     Name to show the other players in the lobby
+  --port N        Local port to use, needed to run more than one client on
+                  the same machine
+
+Network simulation options:
+  --latency MS    Delay every outgoing datagram by MS milliseconds
+  --jitter MS     Add up to MS milliseconds of random extra delay
+  --loss P        Drop outgoing datagrams with probability P
+  --duplicate P   Send outgoing datagrams twice with probability P
+  --reorder P     Hold back outgoing datagrams with probability P
+  --seed N        Seed for the simulator, to reproduce a previous run";
+
+fn parse_arg<T : std::str::FromStr>( flag : &str, value : Option<String> )
+  -> Result<T, String> {
+  match value {
+    Some( v ) =>
+      v.parse().map_err( |_| format!( "Invalid value for {}: {}", flag, v ) ),
+    None => Err( format!( "Missing value for {}", flag ) )
+  }
 }
 
 impl GameSettings {
+  pub fn from_args<I : Iterator<Item=String>>( mut args : I )
+    -> Result<GameSettings, String> {
+    let mut settings : GameSettings = Default::default();
+
+    while let Some( arg ) = args.next() {
+      match &arg[..] {
+        "--latency" =>
+          settings.net.latency = try!( parse_arg( &arg, args.next() ) ),
+        "--jitter" =>
+          settings.net.jitter = try!( parse_arg( &arg, args.next() ) ),
+        "--loss" =>
+          settings.net.loss = try!( parse_arg( &arg, args.next() ) ),
+        "--duplicate" =>
+          settings.net.duplicate = try!( parse_arg( &arg, args.next() ) ),
+        "--reorder" =>
+          settings.net.reorder = try!( parse_arg( &arg, args.next() ) ),
+        "--seed" =>
+          settings.net.seed = Some( try!( parse_arg( &arg, args.next() ) ) ),
+        "--trace" =>
+          settings.trace = true,
+        "--font" =>
+          settings.font = try!( parse_arg( &arg, args.next() ) ),
+        "--log" => {
+          let spec : String = try!( parse_arg( &arg, args.next() ) );
+          try!( settings.log.apply_spec( &spec ) );
+        },
+        "--log-file" =>
+          settings.log.file = Some( try!( parse_arg( &arg, args.next() ) ) ),
+        "--map" =>
+          settings.map = try!( parse_arg( &arg, args.next() ) ),
+        "--heroes" =>
+          settings.heroes = try!( parse_arg( &arg, args.next() ) ),
+        "--items" =>
+          settings.items = try!( parse_arg( &arg, args.next() ) ),
+        "--team" =>
+          settings.team = try!( parse_arg( &arg, args.next() ) ),
+        "--name" =>
+          settings.name = try!( parse_arg( &arg, args.next() ) ),
+        "--port" =>
+          settings.port = Some( try!( parse_arg( &arg, args.next() ) ) ),
+        _ if arg.starts_with( "--" ) =>
+          return Err( format!( "Unknown option: {}", arg ) ),
+        _ => settings.host = Some( arg )
+      }
+    }
+
+    Ok( settings )
+  }
+
   pub fn make_window( &self ) -> PistonWindow {
     WindowSettings::new( GAME_TITLE, self.resolution )
       .exit_on_esc( true )
@@ -44,8 +185,48 @@ impl GameSettings {
       .into()
   }
 
-  pub fn make_net_controller( &self ) -> NetController {
-    NetController::new( self.host.clone() )
+  pub fn load_glyphs( &self, w : &PistonWindow ) -> Option<Glyphs> {
+    match Glyphs::new( Path::new( &self.font ), w.factory.borrow().clone() ) {
+      Ok( g ) => Some( g ),
+      Err( e ) => {
+        warn!( Render, "Couldn't load font {}, debug overlay disabled: {:?}"
+             , self.font, e );
+        None
+      }
+    }
+  }
+
+  pub fn make_net_controller( &self, heroes : HeroBook, items : ItemBook )
+    -> Result<NetController, String> {
+    let port = match (self.host.is_some(), self.port) {
+      (_, Some( p )) => p,
+      (true, None) => CLIENT_PORT,
+      (false, None) => HOST_PORT
+    };
+
+    let mut transport : Box<Transport>
+      = Box::new( try!( UdpTransport::bind( ("0.0.0.0", port) ).map_err( |e|
+          format!( "Couldn't bind to port {}: {}", port, e ) ) ) );
+
+    if !self.net.is_perfect() {
+      transport = Box::new( try!(
+        SimulatedTransport::new( transport, self.net.clone() ).map_err( |e|
+          format!( "Couldn't set up the network simulation: {}", e ) ) ) );
+    }
+
+    let host_addr = match self.host {
+      Some( ref h ) => {
+        let mut addrs = try!( (&h[..], HOST_PORT).to_socket_addrs().map_err(
+                          |e| format!( "Couldn't resolve {}: {}", h, e ) ) );
+        Some( try!( addrs.next().ok_or(
+                      format!( "{} doesn't resolve to any address", h ) ) ) )
+      },
+      None => None
+    };
+
+    let nc = NetController::new( transport, host_addr, heroes, items );
+    nc.set_tracing( self.trace );
+    Ok( nc )
   }
 }
 
@@ -55,63 +236,90 @@ impl Default for GameSettings {
       resolution : [ 640, 480 ],
       fullscreen : false,
       vsync      : false,
-      host       : None
+      host       : None,
+      net        : Default::default(),
+      trace      : false,
+      font       : "assets/font.ttf".to_string(),
+      log        : Default::default(),
+      map        : "assets/maps/default.map".to_string(),
+      heroes     : "assets/heroes".to_string(),
+      items      : "assets/items".to_string(),
+      team       : Team::Blue,
+      name       : "player".to_string(),
+      port       : None
     }
   }
 }
 
-#[derive(Clone, PartialEq, Eq)]
-enum PingStatus {
-  Ready,
-  Awaiting( Timespec )
+fn is_move( ip : &InstructionPacket ) -> bool {
+  match ip.order {
+    Some( Order::Move( .. ) ) => true,
+    _ => false
+  }
 }
 
-struct NetController {
+// How long to wait for a pong before giving up on a ping
+const PING_TIMEOUT_MS : i64 = 2000;
+
+pub struct NetController {
   net_thread_killer      : Sender<()>,
-  net_thread_outbox      : Receiver<Packet>,
+  net_thread_outbox      : Receiver<Incoming>,
   output_stream          : BufUdpStream,
-  packets                : Vec<Packet>,
+  packets                : Vec<Incoming>,
   frames_since_last_sync : usize,
-  assigned_hero_id       : usize,
+  pub assigned_hero_id   : EntityId,
+  pub is_host            : bool,
+  pub roster             : Roster,
+  // Which client plays which hero, host only
+  pub peer_heroes        : HashMap<SocketAddr, EntityId>,
+  // What each team can see, the host only tells clients about that. Clients
+  // and the lobby don't filter anything
+  pub vision             : Option<Vision>,
+  // The heroes everyone picks from, needed to decode them too
+  pub heroes             : Arc<HeroBook>,
+  // What the shops sell, needed to decode inventories
+  pub items              : Arc<ItemBook>,
+  // Everyone we send to: just the host for clients, every client for the
+  // host
+  peers                  : Vec<SocketAddr>,
+  // Who sent the packet we're handling
+  last_sender            : Option<SocketAddr>,
   ping                   : u32,
-  ping_status            : PingStatus
+  // When we pinged the peers we're still waiting on a pong from
+  pings                  : HashMap<SocketAddr, Timespec>,
+  tracing                : Arc<AtomicBool>,
+  next_sequence          : HashMap<SocketAddr, u32>,
+  traffic                : TrafficCounters,
+  stats                  : HashMap<SocketAddr, PeerStats>,
+  last_stats_sample      : u64
 }
 
 impl NetController {
-  pub fn new( mut host : Option<String> ) -> NetController {
+  /// Sets up networking over `transport`, talking to `host` if given or
+  /// waiting for clients to join us otherwise. Who's playing is worked out
+  /// in the lobby.
+  pub fn new( transport : Box<Transport>, host : Option<SocketAddr>
+            , heroes : HeroBook, items : ItemBook ) -> NetController {
+    let heroes = Arc::new( heroes );
+    let items = Arc::new( items );
     let (inb, outb) = channel();
     let (killer, killed) = channel();
 
-    // Try to connect
-    let port = if host.is_some() {
-      4004
-    } else {
-      4114
-    };
-
-    let socket = UdpSocket::bind( ("0.0.0.0", port) ).unwrap();
-    let mut stream = BufWriter::new( UdpStream::new( socket ) );
+    let traffic = Arc::new( Mutex::new( HashMap::new() ) );
+    let transport = MeteredTransport::new( transport, traffic.clone() );
 
-    let mut id = 0;
-
-    // Do client handshake procedure
-    if let Some( h ) = host {
-      let addr = (&h[..], 4114).to_socket_addrs().unwrap().next().unwrap();
-
-      stream.get_mut().set_target( addr );
-      Packet::write_connect( &mut stream );
-      id = Packet::read_intial_sync( stream.get_mut() ).unwrap();
-    } else {
-      Packet::read_connect( stream.get_mut() ).unwrap();
-      // TODO: Figure what what the client ID actually should be
-      stream.get_mut().target = stream.get_ref().sender;
-      Packet::write_initial_sync( 1, &mut stream );
-    }
+    let tracing = Arc::new( AtomicBool::new( false ) );
+    let transport = VerboseTransport::new( Box::new( transport )
+                                         , tracing.clone(), heroes.clone()
+                                         , items.clone() );
+    let stream = BufWriter::new( UdpStream::new( Box::new( transport ) ) );
 
     let usstream = stream.get_ref().try_clone().unwrap();
+    let thread_heroes = heroes.clone();
+    let thread_items = items.clone();
 
     thread::spawn( move || {
-      net_thread( usstream, inb, killed );
+      net_thread( usstream, inb, killed, thread_heroes, thread_items );
     } );
 
     NetController { net_thread_killer     : killer
@@ -119,15 +327,216 @@ impl NetController {
                   , output_stream         : stream
                   , packets               : Vec::new()
                   , frames_since_last_sync: 420
-                  , assigned_hero_id      : id
+                  , assigned_hero_id      : EntityId( 0 )
+                  , is_host               : host.is_none()
+                  , roster                : Roster { players : Vec::new() }
+                  , peer_heroes           : HashMap::new()
+                  , vision                : None
+                  , heroes                : heroes
+                  , items                 : items
+                  , peers                 : host.into_iter().collect()
+                  , last_sender           : None
                   , ping                  : 0
-                  , ping_status           : PingStatus::Ready }
+                  , pings                 : HashMap::new()
+                  , tracing               : tracing
+                  , next_sequence         : HashMap::new()
+                  , traffic               : traffic
+                  , stats                 : HashMap::new()
+                  , last_stats_sample     : now_ms() }
+  }
+
+  /// Starts sending to a client that joined, host only.
+  pub fn add_peer( &mut self, peer : SocketAddr ) {
+    if !self.peers.contains( &peer ) {
+      info!( Net, "{} joined", peer );
+      self.peers.push( peer );
+    }
+  }
+
+  /// Where the packet we're handling came from.
+  pub fn last_sender( &self ) -> Option<SocketAddr> {
+    self.last_sender
+  }
+
+  /// Sends a packet to every peer.
+  fn send( &mut self, p : Packet ) {
+    for peer in self.peers.clone() {
+      self.send_to( peer, p.clone() );
+    }
+  }
+
+  /// The team of the hero a client plays.
+  fn peer_team( &self, peer : SocketAddr ) -> Option<Team> {
+    self.peer_heroes.get( &peer ).and_then( |&id| self.roster.team_of( id ) )
+  }
+
+  /// Whether the peer's team can see the entity, anything goes without
+  /// vision. Peers without a team see nothing.
+  fn peer_sees( &self, peer : SocketAddr, id : EntityId ) -> bool {
+    match self.vision {
+      Some( ref v ) =>
+        self.peer_team( peer ).map_or( false, |t| v.sees( t, id ) ),
+      None => true
+    }
+  }
+
+  /// Sends a packet about an entity to every peer that can see it.
+  fn send_seen( &mut self, id : EntityId, p : Packet ) {
+    for peer in self.peers.clone() {
+      if self.peer_sees( peer, id ) {
+        self.send_to( peer, p.clone() );
+      }
+    }
+  }
+
+  /// Sends a packet to every peer on the team.
+  pub fn send_to_team( &mut self, team : Team, p : Packet ) {
+    for peer in self.peers.clone() {
+      if self.peer_team( peer ) == Some( team ) {
+        self.send_to( peer, p.clone() );
+      }
+    }
+  }
+
+  /// Leaves out what the peer isn't supposed to know about an actor, host
+  /// only. A hero's gold, experience and items are for its own player, and
+  /// where it's headed for its own team.
+  fn tailor_actor( &self, peer : SocketAddr, id : EntityId
+                 , actor : &mut Actor ) {
+    if let Actor::Hero( ref mut hero ) = *actor {
+      if self.peer_heroes.get( &peer ) != Some( &id ) {
+        hero.forget_private();
+      }
+
+      if self.peer_team( peer ) != Some( hero.team ) {
+        hero.target_pos = None;
+        hero.path.clear();
+      }
+    }
+  }
+
+  /// The packet as the peer gets to see it, see `tailor_actor`. Enemies
+  /// don't hear about move orders at all, they follow the hero's syncs.
+  fn tailor( &self, peer : SocketAddr, p : Packet ) -> Option<Packet> {
+    if !self.is_host {
+      return Some( p )
+    }
+
+    match p {
+      Packet::SyncPacket( mut sp ) => {
+        self.tailor_actor( peer, sp.id, &mut sp.sync_frame );
+        Some( Packet::SyncPacket( sp ) )
+      },
+      Packet::Spawn( mut sp ) => {
+        self.tailor_actor( peer, sp.id, &mut sp.actor );
+        Some( Packet::Spawn( sp ) )
+      },
+      Packet::InstructionPacket( ref ip )
+        if is_move( ip )
+           && self.peer_team( peer ) != self.roster.team_of( ip.hero_id ) =>
+        None,
+      p => Some( p )
+    }
+  }
+
+  /// Sends a packet to just one peer, each peer gets its own sequence
+  /// numbers so it can tell what it missed.
+  pub fn send_to( &mut self, peer : SocketAddr, p : Packet ) {
+    let p = match self.tailor( peer, p ) {
+      Some( p ) => p,
+      None => return
+    };
+    let sequence = {
+      let next = self.next_sequence.entry( peer ).or_insert( 0 );
+      let s = *next;
+      *next = next.wrapping_add( 1 );
+      s
+    };
+
+    self.output_stream.get_mut().set_target( peer );
+    p.write_packet( sequence, &mut self.output_stream );
+  }
+
+  fn peer_stats( &mut self, peer : SocketAddr ) -> &mut PeerStats {
+    self.stats.entry( peer ).or_insert_with( PeerStats::new )
+  }
+
+  /// Stats for every peer we've heard from, for the debug overlay.
+  pub fn all_peer_stats( &self ) -> Vec<(SocketAddr, PeerStats)> {
+    self.stats.iter().map( |(a, s)| (*a, s.clone()) ).collect()
+  }
+
+  /// How many received packets are still waiting to be handled.
+  pub fn queued_packets( &self ) -> usize {
+    self.packets.len()
+  }
+
+  fn update_stats( &mut self ) {
+    let now = now_ms();
+    if now - self.last_stats_sample < 1000 {
+      return
+    }
+    self.last_stats_sample = now;
+
+    let totals = self.traffic.lock().unwrap().clone();
+    for (peer, t) in totals {
+      self.peer_stats( peer ).roll_window( t );
+    }
+  }
+
+  pub fn set_tracing( &self, on : bool ) {
+    self.tracing.store( on, Ordering::Relaxed );
+  }
+
+  pub fn toggle_tracing( &self ) {
+    let on = !self.tracing.load( Ordering::Relaxed );
+    self.set_tracing( on );
+    info!( Net, "Packet tracing {}", if on { "enabled" } else { "disabled" } );
   }
 
   pub fn poke_packets( &mut self ) -> bool {
     
     match self.net_thread_outbox.try_recv() {
-      Ok( o ) => self.packets.push( o ),
+      Ok( o ) => {
+        {
+          let stats = self.peer_stats( o.sender );
+          stats.record_sequence( o.sequence );
+
+          if let Packet::SyncPacket( .. ) = o.packet {
+            stats.last_snapshot = Some( now_ms() );
+          }
+        }
+
+        // Clients only talk to the host, so it passes on the orders they
+        // give their heroes to everyone else that can see them. Syncs are
+        // passed on once the host has checked them, see `relay_sync`. What
+        // they say about anyone else's heroes is dropped
+        if self.is_host {
+          let about = match o.packet {
+            Packet::InstructionPacket( ref ip ) => Some( ip.hero_id ),
+            Packet::SyncPacket( ref sp ) => Some( sp.id ),
+            _ => None
+          };
+
+          if let Some( id ) = about {
+            if self.peer_heroes.get( &o.sender ) != Some( &id ) {
+              warn!( Net, "{} sent a packet about {:?}, which isn't its hero"
+                   , o.sender, id );
+              return !self.packets.is_empty()
+            }
+
+            if let Packet::InstructionPacket( .. ) = o.packet {
+              for peer in self.peers.clone() {
+                if peer != o.sender && self.peer_sees( peer, id ) {
+                  self.send_to( peer, o.packet.clone() );
+                }
+              }
+            }
+          }
+        }
+
+        self.packets.push( o )
+      },
       Err( TryRecvError::Disconnected ) =>
         panic!( "Disconnected from net thread." ),
       _ => {}
@@ -139,48 +548,119 @@ impl NetController {
   pub fn poke_sync( &mut self ) -> bool {
     self.frames_since_last_sync += 1;
 
-    // Ping every 60 frames
-    /* DISABLED PINING
+    self.update_stats();
 
-    if self.frames_since_last_sync % 60 == 0
-    && self.ping_status == PingStatus::Ready {
-      // Ping your host
-      Packet::Ping.write_packet( &mut self.output_stream );
-      self.ping_status = PingStatus::Awaiting( get_time() );
-    }
+    // A lost ping or pong would leave us waiting forever
+    let now = get_time();
+    self.pings.retain( |_, ts| ( now - *ts ).num_milliseconds()
+                               <= PING_TIMEOUT_MS );
 
-    */
+    // Ping every 60 frames
+    if self.frames_since_last_sync % 60 == 0 {
+      for peer in self.peers.clone() {
+        if !self.pings.contains_key( &peer ) {
+          self.send_to( peer, Packet::Ping );
+          self.pings.insert( peer, now );
+        }
+      }
+    }
 
     self.frames_since_last_sync >= 120
   }
 
   pub fn send_sync_packet( &mut self, sp : SyncPacket ) {
     self.frames_since_last_sync = 0;
-    Packet::SyncPacket( sp ).write_packet( &mut self.output_stream );
+    self.send_seen( sp.id, Packet::SyncPacket( sp ) );
+  }
+
+  /// Sends a sync right away, outside of the regular sync schedule.
+  pub fn send_entity_sync( &mut self, sp : SyncPacket ) {
+    self.send_seen( sp.id, Packet::SyncPacket( sp ) );
+  }
+
+  /// Passes a client's sync of its hero on to everyone else that can see
+  /// it, after the host took what it's in charge of.
+  pub fn relay_sync( &mut self, sp : SyncPacket ) {
+    for peer in self.peers.clone() {
+      if Some( peer ) != self.last_sender && self.peer_sees( peer, sp.id ) {
+        self.send_to( peer, Packet::SyncPacket( sp.clone() ) );
+      }
+    }
+  }
+
+  pub fn send_death( &mut self, dp : DeathPacket ) {
+    self.send_seen( dp.id, Packet::Death( dp ) );
+  }
+
+  pub fn send_match_ended( &mut self, me : MatchEndedPacket ) {
+    self.send( Packet::MatchEnded( me ) );
+  }
+
+  pub fn send_match_state( &mut self, state : MatchState ) {
+    self.send( Packet::MatchState( state ) );
+  }
+
+  pub fn send_join( &mut self, name : String ) {
+    self.send( Packet::Join( name ) );
+  }
+
+  pub fn send_welcome( &mut self, peer : SocketAddr, welcome : Welcome ) {
+    self.send_to( peer, Packet::Welcome( welcome ) );
+  }
+
+  pub fn send_lobby( &mut self, players : Vec<LobbyPlayer> ) {
+    self.send( Packet::Lobby( players ) );
+  }
+
+  pub fn send_choice( &mut self, choice : LobbyChoice ) {
+    self.send( Packet::Choice( choice ) );
+  }
+
+  pub fn send_start( &mut self, roster : Roster ) {
+    self.send( Packet::Start( roster ) );
+  }
+
+  pub fn send_ready( &mut self ) {
+    let id = self.assigned_hero_id;
+    self.send( Packet::Ready( id ) );
+  }
+
+  pub fn send_spawn( &mut self, sp : SpawnPacket ) {
+    self.send_seen( sp.id, Packet::Spawn( sp ) );
+  }
+
+  pub fn send_despawn( &mut self, id : EntityId ) {
+    self.send_seen( id, Packet::Despawn( id ) );
   }
 
   pub fn send_instruction( &mut self, ip : InstructionPacket ) {
-    Packet::InstructionPacket( ip ).write_packet( &mut self.output_stream );
+    self.send_seen( ip.hero_id, Packet::InstructionPacket( ip ) );
   }
 
   pub fn handle_ping( &mut self ) {
-    match self.ping_status {
-      PingStatus::Ready => Packet::Ping.write_packet( &mut self.output_stream ),
-      PingStatus::Awaiting( ts ) => {
-        let now = get_time();
-        // Calcualte the difference in time, and convert it to milliseconds
-        let ms = ( now.sec - ts.sec ) as u32 * 1000
-               + ( now.nsec - ts.nsec ) as u32 / 1000000;
-        // Send the ping
-        Packet::YourPing( ms ).write_packet( &mut self.output_stream );
-        self.ping_status = PingStatus::Ready;
-      }
+    if let Some( peer ) = self.last_sender {
+      self.send_to( peer, Packet::Pong );
+    }
+  }
+
+  pub fn handle_pong( &mut self ) {
+    let peer = match self.last_sender {
+      Some( p ) => p,
+      None => return
+    };
+
+    if let Some( ts ) = self.pings.remove( &peer ) {
+      let ms = ( get_time() - ts ).num_milliseconds() as u32;
+      self.peer_stats( peer ).rtt = Some( ms );
+
+      // Let the other side know too
+      self.send_to( peer, Packet::YourPing( ms ) );
     }
   }
 
   pub fn update_ping( &mut self, p : u32 ) {
     self.ping = p;
-    println!( "Ping: {}ms", p );
+    debug!( Net, "Ping: {}ms", p );
   }
 
 }
@@ -189,11 +669,12 @@ impl Iterator for NetController {
   type Item = Packet;
 
   fn next( &mut self ) -> Option<Packet> {
-    if !self.packets.is_empty() {
-      self.packets
-          .pop()
-    } else {
-      None
+    match self.packets.pop() {
+      Some( i ) => {
+        self.last_sender = Some( i.sender );
+        Some( i.packet )
+      },
+      None => None
     }
   }
 }
@@ -206,164 +687,224 @@ impl Drop for NetController {
 }
 
 
-#[derive(Clone)]
-struct Controller {
-  hero_id            : usize,
-  dirty              : bool,
-  instruction_packet : InstructionPacket
-}
-
-impl Controller {
-  fn new( id : usize ) -> Controller {
-    Controller { hero_id            : id
-               , dirty              : false
-               , instruction_packet : InstructionPacket::new( id ) }
-  }
-
-  fn refresh( &mut self ) {
-    if !self.dirty {
+fn main() {
+  let settings = match GameSettings::from_args( std::env::args().skip(1) ) {
+    Ok( s ) => s,
+    Err( e ) => {
+      println!( "{}\n\n{}", e, USAGE );
       return
-    } 
+    }
+  };
 
-    self.instruction_packet = InstructionPacket::new( self.hero_id );
-    self.dirty = false;
+  if let Err( e ) = log::init( settings.log.clone() ) {
+    println!( "Couldn't open log file: {}", e );
+    return
   }
-}
-
-const SPAWN_POINT : Pos2 = Pos2 { x : 100.0, y : 100.0 };
-const OTHER_SPAWN_POINT : Pos2 = Pos2 { x : 200.0, y : 300.0 };
 
-struct Game {
-  net_controller    : NetController,
-  controller        : Controller,
-  heroes            : Vec<Hero>,
-  cursor            : Pos2,
-  debug             : bool
-}
+  info!( Net, "Host: {:?}", settings.host );
 
-impl Game {
-  fn new( nc : NetController ) -> Game {
-    let id = nc.assigned_hero_id;
+  let map = match Map::load( &settings.map ) {
+    Ok( m ) => m,
+    Err( e ) => {
+      error!( Sim, "{}", e );
+      return
+    }
+  };
 
-    Game { net_controller : nc
-         , controller     : Controller::new( id )
-         , heroes         : vec![ Hero::new( SPAWN_POINT )
-                                , Hero::new( OTHER_SPAWN_POINT ) ]
-         , cursor         : Pos2::new( 0.0, 0.0 )
-         , debug          : false }
-  }
+  info!( Sim, "Playing on {} ({:x})", map.name, map.hash );
 
-  fn update_cursor( &mut self, x : f64, y : f64 ) {
-    self.cursor = Pos2::new( x as f32, y as f32 );
-  }
+  let heroes = match HeroBook::load( &settings.heroes ) {
+    Ok( h ) => h,
+    Err( e ) => {
+      error!( Sim, "{}", e );
+      return
+    }
+  };
 
-  fn input_press( &mut self, button : Button ) {
+  info!( Sim, "Loaded {} heroes ({:x})", heroes.heroes.len(), heroes.hash );
 
-    if let Button::Keyboard( Key::D ) = button {
-      self.debug = true;
+  let items = match ItemBook::load( &settings.items ) {
+    Ok( i ) => i,
+    Err( e ) => {
+      error!( Sim, "{}", e );
+      return
     }
+  };
 
-    self.controller.instruction_packet.move_to =
-      Some( Pos2::new( self.cursor.x, self.cursor.y ) );
+  info!( Sim, "Loaded {} items ({:x})", items.items.len(), items.hash );
 
-    self.controller.dirty = true;
-  }
+  let nc = match settings.make_net_controller( heroes, items ) {
+    Ok( nc ) => nc,
+    Err( e ) => {
+      error!( Net, "{}", e );
+      return
+    }
+  };
 
-  fn instruct_hero( &mut self, ip : InstructionPacket ) {
-    self.heroes[ip.hero_id].instruct( ip );
-  }
+  let mut window = settings.make_window();
 
-  fn sync_hero( &mut self, sp : SyncPacket ) {
-    self.heroes[sp.hero_id] = sp.sync_frame;
-  }
+  window.set_max_fps( 60 );
+  window.set_ups( 120 );
 
-  fn send_controlled_hero_sync( &mut self ) {
-    let controlled_hero = self.heroes[self.controller.hero_id].clone();
-    let sync_packet
-      = SyncPacket::new( self.controller.hero_id, controlled_hero );
-    self.net_controller.send_sync_packet( sync_packet );
-  }
+  let glyphs = settings.load_glyphs( &window );
+  let mut lobby = Some( Lobby::new( nc, map, settings.name.clone()
+                                  , settings.team, glyphs ) );
+  let mut game : Option<Game> = None;
 
-  fn update( &mut self, delta_time : f64 ) {
-    // Send the instructions to the player's hero
-    // TODO: fold together spammed instructions
-    if self.controller.dirty {
-      let mut ip = self.controller.instruction_packet.clone();
-      self.instruct_hero( ip );
-      ip = self.controller.instruction_packet.clone();
-      self.net_controller.send_instruction( ip );
-    }
+  for e in window {
+    if let Some( ref mut game ) = game {
+      if let Some( xy ) = e.mouse_cursor_args() {
+        game.update_cursor( xy[0], xy[1] );
+      }
 
-    if self.net_controller.poke_packets() {
-      loop {
-        if let Some( u ) = self.net_controller.next() {
-          match u {
-            Packet::InstructionPacket( ip ) =>
-              self.instruct_hero( ip ),
-            Packet::SyncPacket( sp ) =>
-              self.sync_hero( sp ),
-            Packet::Ping => self.net_controller.handle_ping(),
-            Packet::YourPing( p ) => self.net_controller.update_ping( p )
-          }
-        } else {
-          break
-        }
+      if let Some( b ) = e.press_args() {
+        game.input_press( b );
       }
-    }
 
-    if self.net_controller.poke_sync() {
-      self.send_controlled_hero_sync();
-    }
+      if let Some( ua ) = e.update_args() {
+        game.update( ua.dt );
+      }
 
-    // Update all the heroes
-    for hero in self.heroes.iter_mut() {
-      hero.update( delta_time );
+      game.draw( &e );
+      continue
     }
 
-    self.controller.refresh();
-  }
+    let started = match lobby {
+      Some( ref mut lobby ) => {
+        if let Some( b ) = e.press_args() {
+          lobby.input_press( b );
+        }
 
-  fn draw( &self, w : &PistonWindow ) {
-    w.draw_2d( |c, g| {
-      clear( [1.0; 4], g );
+        if let Some( ua ) = e.update_args() {
+          lobby.update( ua.dt );
+        }
 
-      for hero in &self.heroes {
-        ellipse( hero.color
-               , [ hero.entity.pos.x as f64, hero.entity.pos.y as f64, 10.0, 10.0 ]
-               , c.transform, g );
-      }
-    } );
+        if let Some( err ) = lobby.error() {
+          error!( Net, "{}", err );
+          return
+        }
+
+        lobby.draw( &e );
+        lobby.is_started()
+      },
+      None => false
+    };
+
+    if started {
+      game = lobby.take().map( |l| l.into_game() );
+    }
   }
 }
 
-fn main() {
-  let mut settings : GameSettings = Default::default();
-
-  settings.host = std::env::args().skip(1).next();
-  println!("Host: {:?}",settings.host );
+#[cfg(test)]
+mod tests {
+  use std::thread;
+  use std::time::Duration;
+  use super::{NetController, HOST_PORT};
+  use packet::{Packet, InstructionPacket, Order, Welcome};
+  use transport::{channel_pair, channel_hub, loopback_addr};
+  use archetype::HeroBook;
+  use item::ItemBook;
+  use world::EntityId;
+  use entity::Pos2;
+
+  fn controller( transport : ::transport::ChannelTransport
+               , host : Option<::std::net::SocketAddr> ) -> NetController {
+    NetController::new( Box::new( transport ), host
+                      , HeroBook::load( "assets/heroes" ).unwrap()
+                      , ItemBook::load( "assets/items" ).unwrap() )
+  }
 
-  let nc = settings.make_net_controller();
-  let mut window = settings.make_window();
+  /// Waits up to a second for the next packet.
+  fn receive( nc : &mut NetController ) -> Option<Packet> {
+    for _ in 0..100 {
+      nc.poke_packets();
+      if let Some( p ) = nc.next() {
+        return Some( p )
+      }
+      thread::sleep( Duration::from_millis( 10 ) );
+    }
 
-  window.set_max_fps( 60 );
-  window.set_ups( 120 );
+    None
+  }
 
-  let mut game = Game::new( nc );
+  fn move_order( id : EntityId ) -> Packet {
+    let order = Order::Move( Pos2::new( 1.0, 2.0 ) );
+    Packet::InstructionPacket( InstructionPacket { hero_id : id
+                                                 , order   : Some( order ) } )
+  }
 
-  for e in window {
+  #[test]
+  fn host_and_client_talk_over_channels() {
+    let (host_transport, client_transport) = channel_pair();
+    let client_addr = client_transport.local_addr();
+    let mut host = controller( host_transport, None );
+    let mut client = controller( client_transport
+                               , Some( loopback_addr( HOST_PORT ) ) );
+
+    client.send_join( "client".to_string() );
+    match receive( &mut host ) {
+      Some( Packet::Join( ref name ) ) => assert_eq!( name, "client" ),
+      other => panic!( "Expected a join, got {:?}", other )
+    }
+    assert_eq!( host.last_sender(), Some( client_addr ) );
+
+    let hero = EntityId( 1 );
+    host.peer_heroes.insert( client_addr, hero );
+    host.add_peer( client_addr );
+    let welcome = Welcome { your_id     : hero
+                          , map_name    : "test".to_string()
+                          , map_hash    : 1
+                          , heroes_hash : host.heroes.hash
+                          , items_hash  : host.items.hash };
+    host.send_welcome( client_addr, welcome );
+
+    match receive( &mut client ) {
+      Some( Packet::Welcome( w ) ) => assert_eq!( w.your_id, hero ),
+      other => panic!( "Expected a welcome, got {:?}", other )
+    }
 
-    if let Some( xy ) = e.mouse_cursor_args() {
-      game.update_cursor( xy[0], xy[1] );
+    // Orders for someone else's hero never make it past the host
+    client.send_to( loopback_addr( HOST_PORT ), move_order( EntityId( 0 ) ) );
+    client.send_to( loopback_addr( HOST_PORT ), move_order( hero ) );
+    match receive( &mut host ) {
+      Some( Packet::InstructionPacket( ip ) ) =>
+        assert_eq!( ip.hero_id, hero ),
+      other => panic!( "Expected an instruction, got {:?}", other )
     }
 
-    if let Some( b ) = e.press_args() {
-      game.input_press( b );
+    host.send_to( client_addr, Packet::Ping );
+    match receive( &mut client ) {
+      Some( Packet::Ping ) => client.handle_ping(),
+      other => panic!( "Expected a ping, got {:?}", other )
+    }
+    match receive( &mut host ) {
+      Some( Packet::Pong ) => (),
+      other => panic!( "Expected a pong, got {:?}", other )
     }
+  }
+
+  #[test]
+  fn several_clients_join_one_host() {
+    let (host_transport, client_transports) = channel_hub( 3 );
+    let mut host = controller( host_transport, None );
+    let mut clients = Vec::new();
+    let mut addrs = Vec::new();
 
-    if let Some( ua ) = e.update_args() {
-      game.update( ua.dt );
+    for t in client_transports {
+      addrs.push( t.local_addr() );
+      clients.push( controller( t, Some( loopback_addr( HOST_PORT ) ) ) );
     }
 
-    game.draw( &e )
+    for (i, client) in clients.iter_mut().enumerate() {
+      client.send_join( format!( "client {}", i ) );
+
+      match receive( &mut host ) {
+        Some( Packet::Join( ref name ) ) =>
+          assert_eq!( *name, format!( "client {}", i ) ),
+        other => panic!( "Expected a join, got {:?}", other )
+      }
+      assert_eq!( host.last_sender(), Some( addrs[i] ) );
+    }
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,339 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use cgmath::{Point, Vector, EuclideanVector};
+use entity::{Entity, StructureKind, Pos2, Vec2};
+use collision;
+use team::Team;
+
+/// An impassable piece of terrain, the points go around its outline.
+#[derive(Clone, Debug)]
+pub struct Wall {
+  pub points : Vec<Pos2>
+}
+
+impl Wall {
+  fn edges<'a>( &'a self ) -> Box<Iterator<Item=(Pos2, Pos2)> + 'a> {
+    let n = self.points.len();
+    Box::new( ( 0..n ).map( move |i| ( self.points[i]
+                                      , self.points[( i + 1 ) % n] ) ) )
+  }
+
+  pub fn contains( &self, p : Pos2 ) -> bool {
+    // Count how many edges a ray going right from `p` crosses
+    let mut inside = false;
+
+    for (a, b) in self.edges() {
+      if ( a.y > p.y ) != ( b.y > p.y ) {
+        let x = a.x + ( p.y - a.y ) / ( b.y - a.y ) * ( b.x - a.x );
+        if p.x < x {
+          inside = !inside;
+        }
+      }
+    }
+
+    inside
+  }
+
+  /// The point on the wall's outline closest to `p`.
+  pub fn closest_point( &self, p : Pos2 ) -> Pos2 {
+    let mut best = self.points[0];
+    let mut best_dist = ::std::f32::INFINITY;
+
+    for (a, b) in self.edges() {
+      let ab = b.sub_p( &a );
+      let len2 = ab.dot( &ab );
+      let t = if len2 > 0.0 {
+        ( p.sub_p( &a ).dot( &ab ) / len2 ).max( 0.0 ).min( 1.0 )
+      } else {
+        0.0
+      };
+
+      let q = a.add_v( &ab.mul_s( t ) );
+      let dist = q.sub_p( &p ).length();
+      if dist < best_dist {
+        best = q;
+        best_dist = dist;
+      }
+    }
+
+    best
+  }
+
+  /// How far along the segment from `from` to `to`, as a fraction, it first
+  /// crosses the wall's outline.
+  pub fn segment_cast( &self, from : Pos2, to : Pos2 ) -> Option<f32> {
+    self.edges()
+        .filter_map( |(a, b)| collision::segment_segment( from, to, a, b ) )
+        .fold( None, collision::min_hit )
+  }
+}
+
+/// A path minions walk along, going from the blue end of the map to the
+/// red one, red walks it backwards.
+#[derive(Clone, Debug)]
+pub struct Lane {
+  pub name      : String,
+  pub waypoints : Vec<Pos2>
+}
+
+/// Something that belongs to one of the teams and sits at a fixed place.
+#[derive(Clone, Copy, Debug)]
+pub struct Placement {
+  pub team : Team,
+  pub pos  : Pos2
+}
+
+/// Everything about the battlefield that doesn't change during a match.
+#[derive(Clone, Debug)]
+pub struct Map {
+  pub name       : String,
+  // Hash of the map file, so peers can check they're playing on the same
+  pub hash       : u64,
+  pub size       : Vec2,
+  pub walls      : Vec<Wall>,
+  pub spawns     : Vec<Placement>,
+  pub lanes      : Vec<Lane>,
+  pub structures : Vec<(StructureKind, Placement)>,
+  pub bases      : Vec<Placement>,
+  // Where dead heroes come back
+  pub fountains  : Vec<Placement>
+}
+
+/// FNV-1a, simple and stable across platforms and builds.
+pub fn hash_bytes( bytes : &[u8] ) -> u64 {
+  let mut h = 0xcbf29ce484222325u64;
+
+  for &b in bytes {
+    h ^= b as u64;
+    h = h.wrapping_mul( 0x100000001b3 );
+  }
+
+  h
+}
+
+pub fn parse_f32( s : &str ) -> Result<f32, String> {
+  s.parse().map_err( |_| format!( "Invalid number: {}", s ) )
+}
+
+fn parse_point( s : &str ) -> Result<Pos2, String> {
+  let mut parts = s.split( ',' );
+
+  match (parts.next(), parts.next(), parts.next()) {
+    (Some( x ), Some( y ), None) =>
+      Ok( Pos2::new( try!( parse_f32( x ) ), try!( parse_f32( y ) ) ) ),
+    _ => Err( format!( "Invalid point, expected x,y: {}", s ) )
+  }
+}
+
+fn parse_placement( args : &[&str] ) -> Result<Placement, String> {
+  if args.len() != 2 {
+    return Err( "expected a team and a point".to_string() )
+  }
+
+  Ok( Placement { team : try!( args[0].parse() )
+                , pos  : try!( parse_point( args[1] ) ) } )
+}
+
+impl Map {
+  /// Loads a map file, see `Map::parse` for the format.
+  pub fn load<P : AsRef<Path>>( path : P ) -> Result<Map, String> {
+    let path = path.as_ref();
+    let mut source = String::new();
+
+    try!( File::open( path )
+            .and_then( |mut f| f.read_to_string( &mut source ) )
+            .map_err( |e| format!( "Couldn't read map {}: {}"
+                                 , path.display(), e ) ) );
+
+    Map::parse( &source ).map_err( |e| format!( "{}: {}", path.display(), e ) )
+  }
+
+  /// Parses a map, one definition per line with `#` starting comments:
+  ///
+  /// ```text
+  /// name <name>
+  /// size <width> <height>
+  /// wall <x,y> <x,y> <x,y> ...
+  /// spawn <team> <x,y>
+  /// lane <name> <x,y> <x,y> ...
+  /// tower <team> <x,y>
+  /// barracks <team> <x,y>
+  /// core <team> <x,y>
+  /// base <team> <x,y>
+  /// fountain <team> <x,y>
+  /// ```
+  ///
+  /// Teams are `blue` or `red`, and each needs a spawn and a core. Heroes
+  /// respawn at their team's fountain, or at its spawn if it has none. Walls
+  /// have to be convex, bigger shapes can be made out of several.
+  pub fn parse( source : &str ) -> Result<Map, String> {
+    let mut map = Map { name       : String::new()
+                      , hash       : hash_bytes( source.as_bytes() )
+                      , size       : Vec2::new( 0.0, 0.0 )
+                      , walls      : Vec::new()
+                      , spawns     : Vec::new()
+                      , lanes      : Vec::new()
+                      , structures : Vec::new()
+                      , bases      : Vec::new()
+                      , fountains  : Vec::new() };
+
+    for (n, line) in source.lines().enumerate() {
+      let line = line.split( '#' ).next().unwrap().trim();
+      let words = line.split_whitespace().collect::<Vec<_>>();
+
+      if words.is_empty() {
+        continue
+      }
+
+      let (kind, args) = ( words[0], &words[1..] );
+      let err = |e : String| format!( "line {}: {}", n + 1, e );
+
+      match kind {
+        "name" if args.len() == 1 =>
+          map.name = args[0].to_string(),
+        "size" if args.len() == 2 =>
+          map.size = Vec2::new( try!( parse_f32( args[0] ).map_err( &err ) )
+                              , try!( parse_f32( args[1] ).map_err( &err ) ) ),
+        "wall" if args.len() >= 3 => {
+          let mut points = Vec::new();
+          for a in args {
+            points.push( try!( parse_point( a ).map_err( &err ) ) );
+          }
+          map.walls.push( Wall { points : points } );
+        },
+        "lane" if args.len() >= 3 => {
+          let mut waypoints = Vec::new();
+          for a in &args[1..] {
+            waypoints.push( try!( parse_point( a ).map_err( &err ) ) );
+          }
+          map.lanes.push( Lane { name      : args[0].to_string()
+                               , waypoints : waypoints } );
+        },
+        "spawn" =>
+          map.spawns.push( try!( parse_placement( args ).map_err( &err ) ) ),
+        "tower" | "barracks" | "core" => {
+          let structure = match kind {
+            "tower"    => StructureKind::Tower,
+            "barracks" => StructureKind::Barracks,
+            _          => StructureKind::Core
+          };
+          let placement = try!( parse_placement( args ).map_err( &err ) );
+          map.structures.push( (structure, placement) );
+        },
+        "base" =>
+          map.bases.push( try!( parse_placement( args ).map_err( &err ) ) ),
+        "fountain" =>
+          map.fountains.push( try!( parse_placement( args ).map_err( &err ) ) ),
+        _ =>
+          return Err( err( format!( "Invalid definition: {}", line ) ) )
+      }
+    }
+
+    if map.name.is_empty() {
+      return Err( "The map has no name".to_string() )
+    }
+
+    if map.size.x <= 0.0 || map.size.y <= 0.0 {
+      return Err( "The map has no size".to_string() )
+    }
+
+    if map.spawn_point( Team::Blue ).is_none()
+    || map.spawn_point( Team::Red ).is_none() {
+      return Err( "The map needs a spawn point for both teams".to_string() )
+    }
+
+    if map.core( Team::Blue ).is_none() || map.core( Team::Red ).is_none() {
+      return Err( "The map needs a core for both teams".to_string() )
+    }
+
+    Ok( map )
+  }
+
+  /// Where heroes of the given team spawn, the first spawn point listed for
+  /// it.
+  pub fn spawn_point( &self, team : Team ) -> Option<Pos2> {
+    self.spawns.iter().find( |s| s.team == team ).map( |s| s.pos )
+  }
+
+  /// Where dead heroes of the given team come back, the first fountain
+  /// listed for it or its spawn point.
+  pub fn fountain( &self, team : Team ) -> Option<Pos2> {
+    self.fountains.iter()
+                  .find( |f| f.team == team )
+                  .map( |f| f.pos )
+                  .or( self.spawn_point( team ) )
+  }
+
+  /// The closest point to `p` on the map, `None` if `p` isn't a point at
+  /// all because it's NaN or infinite.
+  pub fn clamp( &self, p : Pos2 ) -> Option<Pos2> {
+    if !p.x.is_finite() || !p.y.is_finite() {
+      return None
+    }
+
+    Some( Pos2::new( p.x.max( 0.0 ).min( self.size.x )
+                   , p.y.max( 0.0 ).min( self.size.y ) ) )
+  }
+
+  /// Where the given team's core stands.
+  pub fn core( &self, team : Team ) -> Option<Pos2> {
+    self.structures.iter()
+                   .find( |&&(k, p)| k == StructureKind::Core && p.team == team )
+                   .map( |&(_, p)| p.pos )
+  }
+
+  /// Whether `p` is inside a wall or outside the map.
+  pub fn blocks( &self, p : Pos2 ) -> bool {
+    p.x < 0.0 || p.y < 0.0 || p.x > self.size.x || p.y > self.size.y
+    || self.walls.iter().any( |w| w.contains( p ) )
+  }
+
+  /// Whether a circle at `p` would touch a wall or stick out of the map.
+  pub fn obstructs( &self, p : Pos2, radius : f32 ) -> bool {
+    p.x < radius || p.y < radius
+    || p.x > self.size.x - radius || p.y > self.size.y - radius
+    || self.walls.iter().any( |w| w.contains( p )
+                               || w.closest_point( p ).sub_p( &p ).length()
+                                  < radius )
+  }
+
+  /// How far along the segment from `from` to `to`, as a fraction, it
+  /// first runs into a wall.
+  pub fn segment_cast( &self, from : Pos2, to : Pos2 ) -> Option<f32> {
+    self.walls.iter()
+              .filter_map( |w| w.segment_cast( from, to ) )
+              .fold( None, collision::min_hit )
+  }
+
+  /// Whether nothing on the map is in the way between the two points.
+  pub fn line_of_sight( &self, from : Pos2, to : Pos2 ) -> bool {
+    self.segment_cast( from, to ).is_none()
+  }
+
+  /// Pushes an entity out of any walls it's in, and back inside the map.
+  pub fn push_out( &self, e : &mut Entity ) {
+    let r = collision::bounding_radius( &e.hitbox );
+
+    for w in &self.walls {
+      let closest = w.closest_point( e.pos );
+      let to_center = e.pos.sub_p( &closest );
+      let dist = to_center.length();
+
+      if w.contains( e.pos ) {
+        // Out through the nearest edge, which is the other way from the
+        // center when it's inside
+        if dist > 0.0 {
+          e.pos = closest.add_v( &to_center.mul_s( -r / dist ) );
+        } else {
+          e.pos = closest;
+        }
+      } else if dist < r && dist > 0.0 {
+        e.pos = closest.add_v( &to_center.mul_s( r / dist ) );
+      }
+    }
+
+    e.pos.x = e.pos.x.max( r ).min( self.size.x - r );
+    e.pos.y = e.pos.y.max( r ).min( self.size.y - r );
+  }
+}
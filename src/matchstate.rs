@@ -0,0 +1,77 @@
+use team::Team;
+
+// Seconds between everyone being ready and the match starting
+pub const COUNTDOWN_TIME : f32 = 3.0;
+
+/// The phases a match goes through. The host decides when to move on and
+/// tells the clients, who just follow along.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchState {
+  /// Waiting for everyone to join
+  Lobby,
+  /// Everyone's joined, waiting for them to be ready to play
+  Loading,
+  /// About to start, with the seconds left
+  Countdown( f32 ),
+  InProgress,
+  Paused,
+  /// Over, with the winning team
+  Ended( Team )
+}
+
+impl MatchState {
+  /// How far along the match is, so a state that arrives late can't take
+  /// the match back to an earlier phase.
+  fn stage( &self ) -> u8 {
+    match *self {
+      MatchState::Lobby => 0,
+      MatchState::Loading => 1,
+      MatchState::Countdown( _ ) => 2,
+      MatchState::InProgress | MatchState::Paused => 3,
+      MatchState::Ended( _ ) => 4
+    }
+  }
+
+  /// Whether a client following along should switch to `next`.
+  pub fn can_become( &self, next : MatchState ) -> bool {
+    next.stage() >= self.stage()
+  }
+
+  /// Whether the world moves and heroes take orders.
+  pub fn is_running( &self ) -> bool {
+    *self == MatchState::InProgress
+  }
+
+  /// Whether players are still getting ready, before the countdown.
+  pub fn is_starting( &self ) -> bool {
+    self.stage() < 2
+  }
+
+  pub fn winner( &self ) -> Option<Team> {
+    match *self {
+      MatchState::Ended( t ) => Some( t ),
+      _ => None
+    }
+  }
+
+  /// Counts the countdown down, everyone does this so it shows smoothly
+  /// between updates from the host.
+  pub fn tick( &mut self, delta_time : f64 ) {
+    if let MatchState::Countdown( ref mut left ) = *self {
+      *left = ( *left - delta_time as f32 ).max( 0.0 );
+    }
+  }
+
+  /// What to tell the players about the state, if anything.
+  pub fn describe( &self ) -> Option<String> {
+    match *self {
+      MatchState::Lobby => Some( "Waiting for players".to_string() ),
+      MatchState::Loading => Some( "Getting ready".to_string() ),
+      MatchState::Countdown( left ) =>
+        Some( format!( "Starting in {}", left.ceil() as u32 ) ),
+      MatchState::InProgress => None,
+      MatchState::Paused => Some( "Paused".to_string() ),
+      MatchState::Ended( t ) => Some( format!( "{:?} team wins!", t ) )
+    }
+  }
+}
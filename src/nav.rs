@@ -0,0 +1,352 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use cgmath::{Point, Vector, EuclideanVector};
+use entity::Pos2;
+use map::Map;
+
+pub const NAV_CELL_SIZE : f32 = 8.0;
+
+// Step costs, scaled up so they can stay integers
+const STRAIGHT_COST : u32 = 10;
+const DIAGONAL_COST : u32 = 14;
+
+type Cell = (i32, i32);
+
+/// Which parts of the map a unit of a given size can stand on, split up
+/// into square cells for pathfinding.
+pub struct NavGrid {
+  width   : i32,
+  height  : i32,
+  blocked : Vec<bool>
+}
+
+#[derive(PartialEq, Eq)]
+struct Open {
+  estimate : u32,
+  cost     : u32,
+  cell     : Cell
+}
+
+// The heap is a max-heap, so the order is flipped to get the cheapest first.
+// Ties go by cell so the search comes out the same on every peer.
+impl Ord for Open {
+  fn cmp( &self, other : &Open ) -> Ordering {
+    match other.estimate.cmp( &self.estimate ) {
+      Ordering::Equal => self.cell.cmp( &other.cell ),
+      o => o
+    }
+  }
+}
+
+impl PartialOrd for Open {
+  fn partial_cmp( &self, other : &Open ) -> Option<Ordering> {
+    Some( self.cmp( other ) )
+  }
+}
+
+fn octile( a : Cell, b : Cell ) -> u32 {
+  let dx = ( a.0 - b.0 ).abs() as u32;
+  let dy = ( a.1 - b.1 ).abs() as u32;
+
+  STRAIGHT_COST * ( dx + dy )
+  - ( 2 * STRAIGHT_COST - DIAGONAL_COST ) * dx.min( dy )
+}
+
+impl NavGrid {
+  /// Builds the grid for units of the given radius, a cell is blocked if a
+  /// unit standing in its middle would touch a wall or the edge of the map.
+  pub fn build( map : &Map, radius : f32 ) -> NavGrid {
+    let width = ( map.size.x / NAV_CELL_SIZE ).ceil() as i32;
+    let height = ( map.size.y / NAV_CELL_SIZE ).ceil() as i32;
+
+    let mut blocked = Vec::with_capacity( ( width * height ) as usize );
+    for y in 0..height {
+      for x in 0..width {
+        let center = Pos2::new( ( x as f32 + 0.5 ) * NAV_CELL_SIZE
+                              , ( y as f32 + 0.5 ) * NAV_CELL_SIZE );
+        blocked.push( map.obstructs( center, radius ) );
+      }
+    }
+
+    NavGrid { width : width, height : height, blocked : blocked }
+  }
+
+  fn cell( &self, p : Pos2 ) -> Cell {
+    ( ( p.x / NAV_CELL_SIZE ).floor() as i32
+    , ( p.y / NAV_CELL_SIZE ).floor() as i32 )
+  }
+
+  fn center( &self, c : Cell ) -> Pos2 {
+    Pos2::new( ( c.0 as f32 + 0.5 ) * NAV_CELL_SIZE
+             , ( c.1 as f32 + 0.5 ) * NAV_CELL_SIZE )
+  }
+
+  fn index( &self, c : Cell ) -> usize {
+    ( c.1 * self.width + c.0 ) as usize
+  }
+
+  fn in_bounds( &self, c : Cell ) -> bool {
+    c.0 >= 0 && c.1 >= 0 && c.0 < self.width && c.1 < self.height
+  }
+
+  fn walkable( &self, c : Cell, avoid : &HashSet<Cell> ) -> bool {
+    self.in_bounds( c ) && !self.blocked[self.index( c )]
+    && !avoid.contains( &c )
+  }
+
+  /// The cells covered by circles the path should go around, such as other
+  /// units in the way.
+  fn avoided_cells( &self, avoid : &[(Pos2, f32)] ) -> HashSet<Cell> {
+    let mut cells = HashSet::new();
+
+    for &(pos, r) in avoid {
+      let (x0, y0) = self.cell( Pos2::new( pos.x - r, pos.y - r ) );
+      let (x1, y1) = self.cell( Pos2::new( pos.x + r, pos.y + r ) );
+
+      for x in x0..x1 + 1 {
+        for y in y0..y1 + 1 {
+          if self.center( (x, y) ).sub_p( &pos ).length() < r {
+            cells.insert( (x, y) );
+          }
+        }
+      }
+    }
+
+    cells
+  }
+
+  /// The walkable cell closest to `c`, searching outwards ring by ring.
+  fn nearest_walkable( &self, c : Cell, avoid : &HashSet<Cell> )
+    -> Option<Cell> {
+    let max_ring = self.width.max( self.height );
+
+    for ring in 0..max_ring {
+      let mut best : Option<(u32, Cell)> = None;
+
+      for x in c.0 - ring..c.0 + ring + 1 {
+        for y in c.1 - ring..c.1 + ring + 1 {
+          let on_ring = ( x - c.0 ).abs() == ring || ( y - c.1 ).abs() == ring;
+          if on_ring && self.walkable( (x, y), avoid ) {
+            let d = octile( c, (x, y) );
+            if best.map( |(bd, _)| d < bd ).unwrap_or( true ) {
+              best = Some( (d, (x, y)) );
+            }
+          }
+        }
+      }
+
+      if let Some( (_, b) ) = best {
+        return Some( b )
+      }
+    }
+
+    None
+  }
+
+  /// Finds a path from `from` to `to` with A*, going around the cells
+  /// blocked by the map and the `avoid` circles. If `to` can't be reached
+  /// the path leads as close as it gets. The path doesn't include `from`,
+  /// and is empty if there's nowhere to go.
+  pub fn find_path( &self, from : Pos2, to : Pos2, avoid : &[(Pos2, f32)] )
+    -> Vec<Pos2> {
+    let start = self.cell( from );
+
+    // Starting off the grid means there's nothing to walk on
+    if !self.in_bounds( start ) {
+      return Vec::new()
+    }
+
+    // Never block ourselves in
+    let mut avoided = self.avoided_cells( avoid );
+    avoided.remove( &start );
+
+    let goal = match self.nearest_walkable( self.cell( to ), &avoided ) {
+      Some( g ) => g,
+      None => return Vec::new()
+    };
+
+    let size = ( self.width * self.height ) as usize;
+    let mut cost = vec![ ::std::u32::MAX; size ];
+    let mut came_from : Vec<Option<Cell>> = vec![ None; size ];
+    let mut open = BinaryHeap::new();
+
+    cost[self.index( start )] = 0;
+    open.push( Open { estimate : octile( start, goal ), cost : 0, cell : start } );
+
+    // Where to head if the goal can't be reached, the visited cell closest
+    // to it
+    let mut closest = ( octile( start, goal ), start );
+
+    while let Some( Open { cost : c, cell, .. } ) = open.pop() {
+      if c > cost[self.index( cell )] {
+        continue
+      }
+
+      let left = octile( cell, goal );
+      if left < closest.0 {
+        closest = ( left, cell );
+      }
+
+      if cell == goal {
+        break
+      }
+
+      for dx in -1..2 {
+        for dy in -1..2 {
+          if dx == 0 && dy == 0 {
+            continue
+          }
+
+          let next = ( cell.0 + dx, cell.1 + dy );
+          if !self.walkable( next, &avoided ) {
+            continue
+          }
+
+          // No cutting corners past blocked cells
+          let diagonal = dx != 0 && dy != 0;
+          if diagonal && ( !self.walkable( ( cell.0 + dx, cell.1 ), &avoided )
+                        || !self.walkable( ( cell.0, cell.1 + dy ), &avoided ) ) {
+            continue
+          }
+
+          let nc = c + if diagonal { DIAGONAL_COST } else { STRAIGHT_COST };
+          let ni = self.index( next );
+
+          if nc < cost[ni] {
+            cost[ni] = nc;
+            came_from[ni] = Some( cell );
+            open.push( Open { estimate : nc + octile( next, goal )
+                            , cost     : nc
+                            , cell     : next } );
+          }
+        }
+      }
+    }
+
+    // Nowhere closer to get to
+    let end = closest.1;
+    if end == start && end != goal {
+      return Vec::new()
+    }
+
+    let mut cells = vec![ end ];
+    let mut at = end;
+    while let Some( prev ) = came_from[self.index( at )] {
+      cells.push( prev );
+      at = prev;
+    }
+    cells.reverse();
+
+    let mut path = cells.into_iter()
+                        .map( |c| self.center( c ) )
+                        .collect::<Vec<_>>();
+
+    // Go to the exact spot asked for when it's in the goal cell
+    if end == goal && self.cell( to ) == goal {
+      *path.last_mut().unwrap() = to;
+    }
+
+    self.smooth( from, path, &avoided )
+  }
+
+  /// Whether a unit can walk straight from `a` to `b`, checked every half
+  /// cell along the way.
+  fn clear_line( &self, a : Pos2, b : Pos2, avoid : &HashSet<Cell> ) -> bool {
+    let d = b.sub_p( &a );
+    let steps = ( d.length() / ( NAV_CELL_SIZE / 2.0 ) ).ceil() as i32;
+
+    ( 0..steps + 1 ).all( |i| {
+      let t = if steps > 0 { i as f32 / steps as f32 } else { 0.0 };
+      let c = self.cell( a.add_v( &d.mul_s( t ) ) );
+      c == self.cell( a ) || self.walkable( c, avoid )
+    } )
+  }
+
+  /// Skips every waypoint that can be walked past in a straight line, so
+  /// paths don't zig-zag along the grid.
+  fn smooth( &self, from : Pos2, path : Vec<Pos2>, avoid : &HashSet<Cell> )
+    -> Vec<Pos2> {
+    let mut smoothed = Vec::new();
+    let mut at = from;
+    let mut i = 0;
+
+    while i < path.len() {
+      // Find the furthest waypoint we can see from here
+      let mut furthest = i;
+      for j in i + 1..path.len() {
+        if self.clear_line( at, path[j], avoid ) {
+          furthest = j;
+        }
+      }
+
+      at = path[furthest];
+      smoothed.push( at );
+      i = furthest + 1;
+    }
+
+    smoothed
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use entity::Pos2;
+  use map::Map;
+  use super::NavGrid;
+
+  fn map( walls : &str ) -> Map {
+    Map::parse( &format!( "name test\n\
+                           size 160 80\n\
+                           spawn blue 10,40\n\
+                           spawn red 150,40\n\
+                           core blue 10,10\n\
+                           core red 150,10\n\
+                           {}", walls ) ).unwrap()
+  }
+
+  #[test]
+  fn goes_around_walls() {
+    // Only open at the bottom
+    let map = map( "wall 70,0 90,0 90,60 70,60" );
+    let grid = NavGrid::build( &map, 2.0 );
+    let (from, to) = ( Pos2::new( 20.0, 20.0 ), Pos2::new( 140.0, 20.0 ) );
+
+    let path = grid.find_path( from, to, &[] );
+
+    assert_eq!( path.last().cloned(), Some( to ) );
+    assert!( path.iter().any( |p| p.y > 60.0 ) );
+    assert!( path.iter().all( |&p| !map.obstructs( p, 1.0 ) ) );
+  }
+
+  #[test]
+  fn straight_when_nothing_is_in_the_way() {
+    let map = map( "" );
+    let grid = NavGrid::build( &map, 2.0 );
+    let to = Pos2::new( 140.0, 20.0 );
+
+    assert_eq!( grid.find_path( Pos2::new( 20.0, 20.0 ), to, &[] )
+              , vec![ to ] );
+  }
+
+  #[test]
+  fn gets_as_close_as_it_can_to_unreachable_goals() {
+    // Cuts the map in two
+    let map = map( "wall 70,0 90,0 90,80 70,80" );
+    let grid = NavGrid::build( &map, 2.0 );
+
+    let path = grid.find_path( Pos2::new( 20.0, 40.0 ), Pos2::new( 140.0, 40.0 )
+                             , &[] );
+
+    let end = *path.last().expect( "should still lead somewhere" );
+    assert!( end.x > 50.0 && end.x < 70.0, "ended up at {:?}", end );
+  }
+
+  #[test]
+  fn nowhere_to_go_from_off_the_map() {
+    let map = map( "" );
+    let grid = NavGrid::build( &map, 2.0 );
+
+    assert!( grid.find_path( Pos2::new( -50.0, -50.0 ), Pos2::new( 20.0, 20.0 )
+                           , &[] ).is_empty() );
+  }
+}
@@ -0,0 +1,193 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, TryRecvError};
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
+use std::thread;
+use std::time::Duration;
+use rand::{Rng, SeedableRng, XorShiftRng, thread_rng};
+use time::precise_time_ns;
+use transport::Transport;
+
+// How much longer than the normal latency a reordered datagram is held back
+const REORDER_HOLD_MS : u32 = 40;
+
+/// How bad the simulated connection should be. Latencies are in
+/// milliseconds, everything else is a probability between 0 and 1.
+#[derive(Clone, Debug)]
+pub struct NetConditions {
+  pub latency   : u32,
+  pub jitter    : u32,
+  pub loss      : f32,
+  pub duplicate : f32,
+  pub reorder   : f32,
+  pub seed      : Option<u32>
+}
+
+impl NetConditions {
+  pub fn is_perfect( &self ) -> bool {
+    self.latency == 0 && self.jitter == 0
+    && self.loss <= 0.0 && self.duplicate <= 0.0 && self.reorder <= 0.0
+  }
+}
+
+impl Default for NetConditions {
+  fn default() -> NetConditions {
+    NetConditions { latency   : 0
+                  , jitter    : 0
+                  , loss      : 0.0
+                  , duplicate : 0.0
+                  , reorder   : 0.0
+                  , seed      : None }
+  }
+}
+
+struct Delayed {
+  deliver_at : u64,
+  order      : u64,
+  data       : Vec<u8>,
+  peer       : SocketAddr
+}
+
+impl PartialEq for Delayed {
+  fn eq( &self, other : &Delayed ) -> bool {
+    self.deliver_at == other.deliver_at && self.order == other.order
+  }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+  fn partial_cmp( &self, other : &Delayed ) -> Option<Ordering> {
+    Some( self.cmp( other ) )
+  }
+}
+
+impl Ord for Delayed {
+  // Reversed so the `BinaryHeap` pops the earliest datagram first
+  fn cmp( &self, other : &Delayed ) -> Ordering {
+    (other.deliver_at, other.order).cmp( &(self.deliver_at, self.order) )
+  }
+}
+
+fn now_ms() -> u64 {
+  precise_time_ns() / 1000000
+}
+
+// Holds on to the delayed datagrams and sends them once they're due
+fn courier_thread( mut out : Box<Transport>
+                 , inbox   : ::std::sync::mpsc::Receiver<Delayed> ) {
+  let mut queue = BinaryHeap::new();
+  let mut closed = false;
+
+  loop {
+    loop {
+      match inbox.try_recv() {
+        Ok( d ) => queue.push( d ),
+        Err( TryRecvError::Empty ) => break,
+        Err( TryRecvError::Disconnected ) => { closed = true; break }
+      }
+    }
+
+    if closed && queue.is_empty() {
+      return
+    }
+
+    let now = now_ms();
+    while queue.peek().map( |d : &Delayed| d.deliver_at <= now )
+                      .unwrap_or( false ) {
+      let d = queue.pop().unwrap();
+      let _ = out.send_to( &d.data, d.peer );
+    }
+
+    thread::sleep( Duration::from_millis( 1 ) );
+  }
+}
+
+/// Wraps a transport and makes its outgoing datagrams suffer the given
+/// `NetConditions`. Everything random comes from one seeded generator, so
+/// the same seed drops, duplicates and delays the same datagrams again.
+pub struct SimulatedTransport {
+  inner      : Box<Transport>,
+  conditions : NetConditions,
+  rng        : Arc<Mutex<XorShiftRng>>,
+  sent       : Arc<Mutex<u64>>,
+  courier    : Sender<Delayed>
+}
+
+impl SimulatedTransport {
+  pub fn new( inner : Box<Transport>, mut conditions : NetConditions )
+    -> io::Result<SimulatedTransport> {
+    // Always end up with a known seed so the run can be reproduced
+    let seed = match conditions.seed {
+      Some( s ) => s,
+      None => thread_rng().gen()
+    };
+    conditions.seed = Some( seed );
+    info!( Net, "Simulating network conditions: {:?}", conditions );
+
+    let rng = XorShiftRng::from_seed( [ seed, seed ^ 0x9e3779b9
+                                      , 0x2545f491, 0x6c078965 ] );
+
+    let out = try!( inner.try_clone() );
+    let (courier, inbox) = channel();
+
+    thread::spawn( move || {
+      courier_thread( out, inbox );
+    } );
+
+    Ok( SimulatedTransport { inner      : inner
+                           , conditions : conditions
+                           , rng        : Arc::new( Mutex::new( rng ) )
+                           , sent       : Arc::new( Mutex::new( 0 ) )
+                           , courier    : courier } )
+  }
+}
+
+impl Transport for SimulatedTransport {
+  fn send_to( &mut self, buf : &[u8], peer : SocketAddr ) -> io::Result<usize> {
+    let mut rng = self.rng.lock().unwrap();
+    let c = &self.conditions;
+
+    if rng.gen::<f32>() < c.loss {
+      return Ok( buf.len() )
+    }
+
+    let copies = if rng.gen::<f32>() < c.duplicate { 2 } else { 1 };
+
+    for _ in 0..copies {
+      let mut delay = c.latency;
+
+      if c.jitter > 0 {
+        delay += rng.gen_range( 0, c.jitter + 1 );
+      }
+
+      if rng.gen::<f32>() < c.reorder {
+        delay += REORDER_HOLD_MS;
+      }
+
+      let mut sent = self.sent.lock().unwrap();
+      *sent += 1;
+
+      let _ = self.courier.send( Delayed { deliver_at : now_ms() + delay as u64
+                                         , order      : *sent
+                                         , data       : buf.to_vec()
+                                         , peer       : peer } );
+    }
+
+    Ok( buf.len() )
+  }
+
+  fn recv_from( &mut self, buf : &mut [u8] ) -> io::Result<(usize, SocketAddr)> {
+    self.inner.recv_from( buf )
+  }
+
+  fn try_clone( &self ) -> io::Result<Box<Transport>> {
+    Ok( Box::new( SimulatedTransport { inner      : try!( self.inner.try_clone() )
+                                     , conditions : self.conditions.clone()
+                                     , rng        : self.rng.clone()
+                                     , sent       : self.sent.clone()
+                                     , courier    : self.courier.clone() } ) )
+  }
+}
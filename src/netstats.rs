@@ -0,0 +1,195 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use time::precise_time_ns;
+use transport::Transport;
+
+/// Running totals of the traffic exchanged with a single peer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PeerTraffic {
+  pub packets_in  : usize,
+  pub bytes_in    : usize,
+  pub packets_out : usize,
+  pub bytes_out   : usize
+}
+
+impl PeerTraffic {
+  /// The traffic between the `earlier` totals and these ones.
+  pub fn since( &self, earlier : &PeerTraffic ) -> PeerTraffic {
+    PeerTraffic { packets_in  : self.packets_in - earlier.packets_in
+                , bytes_in    : self.bytes_in - earlier.bytes_in
+                , packets_out : self.packets_out - earlier.packets_out
+                , bytes_out   : self.bytes_out - earlier.bytes_out }
+  }
+}
+
+pub type TrafficCounters = Arc<Mutex<HashMap<SocketAddr, PeerTraffic>>>;
+
+/// Wraps a transport and keeps count of every datagram going through it.
+pub struct MeteredTransport {
+  inner    : Box<Transport>,
+  counters : TrafficCounters
+}
+
+impl MeteredTransport {
+  pub fn new( inner : Box<Transport>, counters : TrafficCounters )
+    -> MeteredTransport {
+    MeteredTransport { inner : inner, counters : counters }
+  }
+}
+
+impl Transport for MeteredTransport {
+  fn send_to( &mut self, buf : &[u8], peer : SocketAddr ) -> io::Result<usize> {
+    let n = try!( self.inner.send_to( buf, peer ) );
+
+    let mut counters = self.counters.lock().unwrap();
+    let t = counters.entry( peer ).or_insert( Default::default() );
+    t.packets_out += 1;
+    t.bytes_out += n;
+
+    Ok( n )
+  }
+
+  fn recv_from( &mut self, buf : &mut [u8] ) -> io::Result<(usize, SocketAddr)> {
+    let (n, peer) = try!( self.inner.recv_from( buf ) );
+
+    let mut counters = self.counters.lock().unwrap();
+    let t = counters.entry( peer ).or_insert( Default::default() );
+    t.packets_in += 1;
+    t.bytes_in += n;
+
+    Ok( (n, peer) )
+  }
+
+  fn try_clone( &self ) -> io::Result<Box<Transport>> {
+    Ok( Box::new( MeteredTransport { inner    : try!( self.inner.try_clone() )
+                                   , counters : self.counters.clone() } ) )
+  }
+}
+
+/// Whether sequence number `a` comes after `b`, allowing for them wrapping
+/// around.
+fn newer( a : u32, b : u32 ) -> bool {
+  ( a.wrapping_sub( b ) as i32 ) > 0
+}
+
+pub fn now_ms() -> u64 {
+  precise_time_ns() / 1000000
+}
+
+/// What the `NetController` knows about the connection to one peer. The
+/// rates and loss are measured over the last full second.
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+  pub rtt              : Option<u32>,
+  pub rate             : PeerTraffic,
+  pub loss             : f32,
+  pub last_snapshot    : Option<u64>,
+  highest_sequence     : Option<u32>,
+  window_start         : u32,
+  window_received      : u32,
+  last_totals          : PeerTraffic
+}
+
+impl PeerStats {
+  pub fn new() -> PeerStats {
+    PeerStats { rtt              : None
+              , rate             : Default::default()
+              , loss             : 0.0
+              , last_snapshot    : None
+              , highest_sequence : None
+              , window_start     : 0
+              , window_received  : 0
+              , last_totals      : Default::default() }
+  }
+
+  pub fn record_sequence( &mut self, sequence : u32 ) {
+    match self.highest_sequence {
+      None => {
+        self.window_start = sequence;
+        self.highest_sequence = Some( sequence );
+      },
+      Some( h ) if newer( sequence, h ) =>
+        self.highest_sequence = Some( sequence ),
+      _ => {}
+    }
+
+    self.window_received += 1;
+  }
+
+  /// Milliseconds since the last sync snapshot arrived from this peer.
+  pub fn snapshot_age( &self ) -> Option<u64> {
+    self.last_snapshot.map( |t| now_ms() - t )
+  }
+
+  /// Closes the current one second window given the latest traffic totals.
+  pub fn roll_window( &mut self, totals : PeerTraffic ) {
+    self.rate = totals.since( &self.last_totals );
+    self.last_totals = totals;
+
+    match self.highest_sequence {
+      Some( h ) if !newer( self.window_start, h ) => {
+        let expected = h.wrapping_sub( self.window_start ).wrapping_add( 1 );
+        // Duplicates can make us receive more than we expected
+        let received = ::std::cmp::min( self.window_received, expected );
+
+        self.loss = 1.0 - received as f32 / expected as f32;
+        self.window_start = h.wrapping_add( 1 );
+      },
+      // Nothing new arrived, so there's nothing to measure
+      _ => {}
+    }
+
+    self.window_received = 0;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::u32;
+  use super::{PeerStats, PeerTraffic};
+
+  fn traffic( packets_in : usize ) -> PeerTraffic {
+    PeerTraffic { packets_in : packets_in, .. Default::default() }
+  }
+
+  #[test]
+  fn no_loss_across_wraparound() {
+    let mut stats = PeerStats::new();
+    for &s in &[ u32::MAX - 1, u32::MAX, 0, 1 ] {
+      stats.record_sequence( s );
+    }
+
+    stats.roll_window( traffic( 4 ) );
+    assert_eq!( stats.loss, 0.0 );
+    assert_eq!( stats.rate.packets_in, 4 );
+
+    stats.record_sequence( 2 );
+    stats.roll_window( traffic( 5 ) );
+    assert_eq!( stats.loss, 0.0 );
+  }
+
+  #[test]
+  fn loss_across_wraparound() {
+    let mut stats = PeerStats::new();
+    stats.record_sequence( u32::MAX - 1 );
+    stats.record_sequence( 1 );
+
+    stats.roll_window( traffic( 2 ) );
+    assert_eq!( stats.loss, 0.5 );
+  }
+
+  #[test]
+  fn late_packets_dont_count_as_new() {
+    let mut stats = PeerStats::new();
+    stats.record_sequence( 0 );
+    stats.record_sequence( 1 );
+    stats.roll_window( traffic( 2 ) );
+
+    // Arrived after its window was closed, nothing new to measure
+    stats.record_sequence( u32::MAX );
+    stats.roll_window( traffic( 3 ) );
+    assert_eq!( stats.loss, 0.0 );
+  }
+}
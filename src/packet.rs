@@ -1,9 +1,21 @@
 use super::packets_capnp;
 use cgmath::FixedArray;
-use super::entity::{Hero, Entity, Hitbox, Pos2, Vec2};
+use super::entity::{Hero, Minion, Structure, StructureKind, Entity, Hitbox, Health
+                   , LifeState, Attack, Pos2, Vec2};
+use super::ability::{CastTarget, ABILITY_SLOTS};
+use super::projectile::{Projectile, Motion, OnHit};
+use super::world::{Actor, EntityId};
+use super::team::{Team, Player, Roster};
+use super::stats::{MatchStats, PlayerStats};
+use super::matchstate::MatchState;
+use super::lobby::{LobbyPlayer, LobbyChoice};
+use super::archetype::HeroBook;
+use super::item::{ItemBook, Inventory, Item, Bonus, INVENTORY_SLOTS};
+use super::status::{Status, StatusEffect, Statuses, BURN_TICK};
+use std::sync::Arc;
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::net::{TcpListener, TcpStream, UdpSocket, SocketAddr, ToSocketAddrs};
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use capnp::message::Builder;
 use capnp;
 use udpstream::{UdpStream, BufUdpStream};
@@ -16,150 +28,521 @@ fn vec2_from_fixed( v : [f32; 2] ) -> Vec2 {
   Vec2::new( v[0], v[1] )
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
+pub enum Order {
+  Move( Pos2 ),
+  Attack( EntityId ),
+  Cast( usize, CastTarget ),
+  /// Spend an ability point on the ability in the slot
+  Learn( usize ),
+  /// Buy the item with the given ID from the shop
+  BuyItem( u8 ),
+  /// Sell the item in the inventory slot to the shop
+  SellItem( usize ),
+  /// Use the active of the item in the inventory slot
+  UseItem( usize, CastTarget )
+}
+
+#[derive(Clone, Debug)]
 pub struct InstructionPacket {
-  pub hero_id : usize,
-  pub move_to : Option<Pos2>
+  pub hero_id : EntityId,
+  pub order   : Option<Order>
 }
 
 impl InstructionPacket {
-  pub fn new( id : usize ) -> InstructionPacket {
+  pub fn new( id : EntityId ) -> InstructionPacket {
     InstructionPacket { hero_id : id
-                      , move_to : None }
+                      , order   : None }
   }
 }
 
-pub type SyncFrame = Hero;
+pub type SyncFrame = Actor;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct SyncPacket {
-  pub hero_id    : usize,
+  pub id         : EntityId,
   pub sync_frame : SyncFrame
 }
 
 impl SyncPacket {
-  pub fn new( id : usize, sf : SyncFrame ) -> SyncPacket {
-    SyncPacket { hero_id   : id
+  pub fn new( id : EntityId, sf : SyncFrame ) -> SyncPacket {
+    SyncPacket { id        : id
                , sync_frame: sf}
   }
 } 
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
+pub struct SpawnPacket {
+  pub id    : EntityId,
+  pub actor : Actor
+}
+
+/// What the host tells a client when it joins.
+#[derive(Clone, Debug)]
+pub struct Welcome {
+  pub your_id  : EntityId,
+  pub map_name : String,
+  pub map_hash : u64,
+  pub heroes_hash : u64,
+  pub items_hash  : u64
+}
+
+#[derive(Clone, Debug)]
+pub struct DeathPacket {
+  pub id     : EntityId,
+  pub killer : Option<EntityId>
+}
+
+/// Sent by the host when a team's core falls.
+#[derive(Clone, Debug)]
+pub struct MatchEndedPacket {
+  pub winner : Team,
+  pub stats  : MatchStats
+}
+
+#[derive(Clone, Debug)]
 pub enum Packet {
   InstructionPacket( InstructionPacket ),
   SyncPacket( SyncPacket ),
   Ping,
-  YourPing( u32 )
+  Pong,
+  YourPing( u32 ),
+  Death( DeathPacket ),
+  Spawn( SpawnPacket ),
+  Despawn( EntityId ),
+  MatchEnded( MatchEndedPacket ),
+  MatchState( MatchState ),
+  // A client telling the host it's ready to play, with its hero
+  Ready( EntityId ),
+  // A client asking to join the lobby, with its name
+  Join( String ),
+  Welcome( Welcome ),
+  // Everyone in the lobby, sent out by the host
+  Lobby( Vec<LobbyPlayer> ),
+  // A client's picks in the lobby
+  Choice( LobbyChoice ),
+  // The host starting the match with everyone in the lobby
+  Start( Roster ),
+  // The entity went out of sight of the receiver's team, sent by the host
+  Hide( EntityId )
+}
+
+/// A packet along with where it came from and its sequence number.
+#[derive(Clone, Debug)]
+pub struct Incoming {
+  pub sender   : SocketAddr,
+  pub sequence : u32,
+  pub packet   : Packet
 }
 
 type Stream<'a> = BufReader<&'a mut UdpStream>;
 
 impl Packet {
-  pub fn read_connect( stream : &mut UdpStream ) -> capnp::Result<()> {
-    use capnp::serialize_packed;
-    use capnp::message::ReaderOptions;
-
+  /// Reads the next packet, heroes in it are rebuilt from `heroes` and
+  /// their items from `items`.
+  pub fn read_packet( stream : &mut UdpStream, heroes : &HeroBook
+                    , items : &ItemBook )
+    -> capnp::Result<(u32, Packet)> {
     let mut buffered_stream = BufReader::new( stream );
 
-    let message_reader
-      = try!( serialize_packed::read_message( &mut buffered_stream
-                                            , ReaderOptions::new() ) );
-
-    try!( message_reader.get_root::<packets_capnp::connect::Reader>() );
-
-    Ok( () )
+    Packet::read_from( &mut buffered_stream, heroes, items )
   }
 
-  pub fn read_intial_sync( stream : &mut UdpStream )
-    -> capnp::Result<usize> {
-    use capnp::serialize_packed;
-    use capnp::message::ReaderOptions;
-
-    let mut buffered_stream = BufReader::new( stream );
-
-    let message_reader
-      = try!( serialize_packed::read_message( &mut buffered_stream
-                                            , ReaderOptions::new() ) );
-
-    let initial_sync
-      = try!(
-          message_reader.get_root::<packets_capnp::initial_sync::Reader>() );
-
-    Ok( initial_sync.get_your_id() as usize )
+  /// Decodes a packet from a single datagram's worth of bytes.
+  pub fn decode( mut buf : &[u8], heroes : &HeroBook, items : &ItemBook )
+    -> capnp::Result<(u32, Packet)> {
+    Packet::read_from( &mut buf, heroes, items )
   }
 
-  pub fn read_packet( stream : &mut UdpStream ) -> capnp::Result<Packet> {
+  fn read_from<R : BufRead>( stream : &mut R, heroes : &HeroBook
+                           , items : &ItemBook )
+    -> capnp::Result<(u32, Packet)> {
     use capnp::serialize_packed;
     use capnp::message::ReaderOptions;
 
-    let mut buffered_stream = BufReader::new( stream );
-
     let message_reader
-      = try!( serialize_packed::read_message( &mut buffered_stream
-                                            , ReaderOptions::new() ) );
+      = try!( serialize_packed::read_message( stream, ReaderOptions::new() ) );
 
     let rpacket
       = try!( message_reader.get_root::<packets_capnp::packet::Reader>() );
 
-    Ok( match try!( rpacket.which() ) {
+    let packet = match try!( rpacket.which() ) {
 
       packets_capnp::packet::Which::Instruction( inst ) =>
         Packet::InstructionPacket(
             try!( Packet::read_instruction( try!( inst ) ) ) ),
 
       packets_capnp::packet::Which::Sync( sync ) =>
-        Packet::SyncPacket( try!( Packet::read_sync( try!( sync ) ) ) ),
+        Packet::SyncPacket(
+            try!( Packet::read_sync( try!( sync ), heroes, items ) ) ),
 
       packets_capnp::packet::Which::Ping( () ) => Packet::Ping,
 
-      packets_capnp::packet::Which::YourPing( yp ) => Packet::YourPing( yp )
-    } )
+      packets_capnp::packet::Which::Pong( () ) => Packet::Pong,
+
+      packets_capnp::packet::Which::YourPing( yp ) => Packet::YourPing( yp ),
+
+      packets_capnp::packet::Which::Death( death ) =>
+        Packet::Death( try!( Packet::read_death( try!( death ) ) ) ),
+
+      packets_capnp::packet::Which::Spawn( sp ) =>
+        Packet::Spawn(
+            try!( Packet::read_spawn( try!( sp ), heroes, items ) ) ),
+
+      packets_capnp::packet::Which::Despawn( id ) =>
+        Packet::Despawn( EntityId( id ) ),
+
+      packets_capnp::packet::Which::MatchEnded( me ) =>
+        Packet::MatchEnded( try!( Packet::read_match_ended( try!( me ) ) ) ),
+
+      packets_capnp::packet::Which::MatchState( ms ) =>
+        Packet::MatchState( try!( Packet::read_match_state( try!( ms ) ) ) ),
+
+      packets_capnp::packet::Which::Ready( id ) =>
+        Packet::Ready( EntityId( id ) ),
+
+      packets_capnp::packet::Which::Join( name ) =>
+        Packet::Join( try!( name ).to_string() ),
+
+      packets_capnp::packet::Which::Welcome( w ) =>
+        Packet::Welcome( try!( Packet::read_welcome( try!( w ) ) ) ),
+
+      packets_capnp::packet::Which::Lobby( l ) =>
+        Packet::Lobby( try!( Packet::read_lobby( try!( l ) ) ) ),
+
+      packets_capnp::packet::Which::Choice( c ) =>
+        Packet::Choice( try!( Packet::read_choice( try!( c ) ) ) ),
+
+      packets_capnp::packet::Which::Start( r ) =>
+        Packet::Start( try!( Packet::read_roster( try!( r ) ) ) ),
+
+      packets_capnp::packet::Which::Hide( id ) =>
+        Packet::Hide( EntityId( id ) )
+    };
+
+    Ok( (rpacket.get_sequence(), packet) )
 
   } 
 
   pub fn read_instruction( inst : packets_capnp::instruction::Reader )
     -> capnp::Result<InstructionPacket> {
     
-    let move_to = match try!( inst.borrow().get_move_to().which() ) {
-      packets_capnp::instruction::move_to::Nowhere( v ) =>
+    let order = match try!( inst.borrow().get_order().which() ) {
+      packets_capnp::instruction::order::Nowhere( v ) =>
         None,
-      packets_capnp::instruction::move_to::Target( t ) =>
-        Some( pos2_from_fixed( Packet::read_vec2( try!( t ) ) ) )
+      packets_capnp::instruction::order::MoveTo( t ) =>
+        Some( Order::Move(
+          pos2_from_fixed( Packet::read_vec2( try!( t ) ) ) ) ),
+      packets_capnp::instruction::order::Attack( id ) =>
+        Some( Order::Attack( EntityId( id ) ) ),
+      packets_capnp::instruction::order::Cast( c ) => {
+        let (slot, target) = try!( Packet::read_cast( try!( c ) ) );
+        Some( Order::Cast( slot, target ) )
+      },
+      packets_capnp::instruction::order::Learn( slot ) =>
+        Some( Order::Learn( slot as usize ) ),
+      packets_capnp::instruction::order::BuyItem( id ) =>
+        Some( Order::BuyItem( id ) ),
+      packets_capnp::instruction::order::SellItem( slot ) =>
+        Some( Order::SellItem( slot as usize ) ),
+      packets_capnp::instruction::order::UseItem( c ) => {
+        let (slot, target) = try!( Packet::read_cast( try!( c ) ) );
+        Some( Order::UseItem( slot, target ) )
+      }
     };
 
-    Ok( InstructionPacket { hero_id: inst.get_hero_id() as usize
-                          , move_to: move_to } )
+    Ok( InstructionPacket { hero_id: EntityId( inst.get_hero_id() )
+                          , order  : order } )
+  }
+
+  pub fn read_cast( cast : packets_capnp::cast::Reader )
+    -> capnp::Result<(usize, CastTarget)> {
+    let target = match try!( cast.borrow().get_target().which() ) {
+      packets_capnp::cast::target::None( () ) => CastTarget::NoTarget,
+      packets_capnp::cast::target::Point( p ) =>
+        CastTarget::Point( pos2_from_fixed( Packet::read_vec2( try!( p ) ) ) ),
+      packets_capnp::cast::target::Unit( id ) =>
+        CastTarget::Unit( EntityId( id ) ),
+      packets_capnp::cast::target::Direction( d ) =>
+        CastTarget::Direction( vec2_from_fixed( Packet::read_vec2( try!( d ) ) ) )
+    };
+
+    Ok( (cast.get_slot() as usize, target) )
   }
 
   pub fn read_vec2( vec : packets_capnp::vec2::Reader ) -> [f32; 2] {
     [ vec.borrow().get_x(), vec.get_y() ]
   }
 
-  pub fn read_sync( sync : packets_capnp::sync::Reader )
+  pub fn read_sync( sync : packets_capnp::sync::Reader, heroes : &HeroBook
+                  , items : &ItemBook )
     -> capnp::Result<SyncPacket> {
 
     Ok(
-    SyncPacket { hero_id: sync.borrow().get_hero_id() as usize
+    SyncPacket { id        : EntityId( sync.borrow().get_id() )
                , sync_frame:
-                 try!( Packet::read_hero( try!( sync.get_sync_frame() ) ) ) } )
+                 try!( Packet::read_actor( try!( sync.get_sync_frame() )
+                                         , heroes, items ) ) } )
   }
 
-  pub fn read_hero( hero : packets_capnp::hero::Reader )
-    -> capnp::Result<Hero> {
+  pub fn read_spawn( spawn : packets_capnp::spawn::Reader, heroes : &HeroBook
+                   , items : &ItemBook )
+    -> capnp::Result<SpawnPacket> {
+
+    Ok(
+    SpawnPacket { id   : EntityId( spawn.borrow().get_id() )
+                , actor:
+                  try!( Packet::read_actor( try!( spawn.get_actor() )
+                                          , heroes, items ) ) } )
+  }
 
-    let target_pos = match try!( hero.borrow().get_target_pos().which() ) {
-      packets_capnp::hero::target_pos::Nowhere( v ) =>
+  pub fn read_actor( actor : packets_capnp::actor::Reader, heroes : &HeroBook
+                   , items : &ItemBook )
+    -> capnp::Result<Actor> {
+    Ok( match try!( actor.which() ) {
+      packets_capnp::actor::Hero( h ) =>
+        Actor::Hero( try!( Packet::read_hero( try!( h ), heroes, items ) ) ),
+      packets_capnp::actor::Minion( m ) =>
+        Actor::Minion( try!( Packet::read_minion( try!( m ) ) ) ),
+      packets_capnp::actor::Structure( s ) =>
+        Actor::Structure( try!( Packet::read_structure( try!( s ) ) ) ),
+      packets_capnp::actor::Projectile( p ) =>
+        Actor::Projectile( try!( Packet::read_projectile( try!( p ) ) ) )
+    } )
+  }
+
+  pub fn read_minion( minion : packets_capnp::minion::Reader )
+    -> capnp::Result<Minion> {
+    let target_pos = match try!( minion.borrow().get_target_pos().which() ) {
+      packets_capnp::minion::target_pos::Nowhere( () ) => None,
+      packets_capnp::minion::target_pos::Target( t ) =>
+        Some( pos2_from_fixed( Packet::read_vec2( try!( t ) ) ) )
+    };
+
+    Ok( Minion { entity    :
+                 try!( Packet::read_entity( try!( minion.borrow().get_entity() ) ) )
+               , health    :
+                 Packet::read_health( try!( minion.borrow().get_health() ) )
+               , team      :
+                 try!( Packet::read_team( minion.borrow().get_team() ) )
+               , attack    :
+                 try!( Packet::read_attack( try!( minion.borrow().get_attack() ) ) )
+               , statuses  :
+                 try!( Packet::read_statuses( try!( minion.get_statuses() ) ) )
+               , target_pos: target_pos
+               , waypoints : Vec::new() } )
+  }
+
+  pub fn read_structure( structure : packets_capnp::structure::Reader )
+    -> capnp::Result<Structure> {
+    let attack = match try!( structure.borrow().get_attack().which() ) {
+      packets_capnp::structure::attack::Unarmed( () ) => None,
+      packets_capnp::structure::attack::Armed( a ) =>
+        Some( try!( Packet::read_attack( try!( a ) ) ) )
+    };
+
+    let kind = try!( StructureKind::from_index( structure.borrow().get_kind() )
+                       .ok_or( capnp::Error::new_decode_error(
+                                 "Invalid structure kind", None ) ) );
+
+    Ok( Structure { entity :
+                    try!( Packet::read_entity(
+                            try!( structure.borrow().get_entity() ) ) )
+                  , team   :
+                    try!( Packet::read_team( structure.borrow().get_team() ) )
+                  , kind   : kind
+                  , health :
+                    Packet::read_health( try!( structure.borrow().get_health() ) )
+                  , attack : attack } )
+  }
+
+  pub fn read_death( death : packets_capnp::death::Reader )
+    -> capnp::Result<DeathPacket> {
+    let killer = match try!( death.borrow().get_killer().which() ) {
+      packets_capnp::death::killer::Nobody( () ) => None,
+      packets_capnp::death::killer::Entity( e ) => Some( EntityId( e ) )
+    };
+
+    Ok( DeathPacket { id     : EntityId( death.get_id() )
+                    , killer : killer } )
+  }
+
+  /// Rebuilds a hero from its archetype in `heroes` and the state that
+  /// was sent, with its items out of `items`.
+  pub fn read_hero( hero : packets_capnp::hero::Reader, heroes : &HeroBook
+                  , items : &ItemBook )
+    -> capnp::Result<Hero> {
+    let def = try!( heroes.get( hero.borrow().get_archetype() )
+                      .ok_or( capnp::Error::new_decode_error(
+                                "Unknown hero archetype", None ) ) );
+
+    let pos = pos2_from_fixed(
+                Packet::read_vec2( try!( hero.borrow().get_pos() ) ) );
+    let team = try!( Packet::read_team( hero.borrow().get_team() ) );
+    let mut h = Hero::new( def.clone(), pos, team );
+    // Max health and the like follow from the level and the items, which
+    // only the hero's own player gets but everyone gets their bonus
+    h.inventory = try!( Packet::read_inventory(
+                          try!( hero.borrow().get_items() ), items ) );
+    h.bonus = Packet::read_bonus( try!( hero.borrow().get_bonus() ) );
+    h.set_level( hero.borrow().get_level() as u32 );
+
+    h.entity.vel = vec2_from_fixed(
+                     Packet::read_vec2( try!( hero.borrow().get_vel() ) ) );
+
+    h.target_pos = match try!( hero.borrow().get_target_pos().which() ) {
+      packets_capnp::hero::target_pos::Nowhere( () ) =>
         None,
       packets_capnp::hero::target_pos::Target( t ) =>
         Some( pos2_from_fixed( Packet::read_vec2( try!( t ) ) ) )
     };
 
-    Ok(
-    Hero { entity    :
-           try!( Packet::read_entity( try!( hero.borrow().get_entity() ) ) )
-         , color     :
-           Packet::read_color( try!( hero.borrow().get_color() ) )
-         , target_pos: target_pos } )
+    h.health.hp = hero.borrow().get_hp();
+
+    h.life = match try!( hero.borrow().get_life().which() ) {
+      packets_capnp::hero::life::Alive( () ) => LifeState::Alive,
+      packets_capnp::hero::life::Dead( t ) => LifeState::Dead { respawn_in : t }
+    };
+
+    h.attack.timer = hero.borrow().get_attack_timer();
+    h.attack.target = match try!( hero.borrow().get_attack_target().which() ) {
+      packets_capnp::hero::attack_target::None( () ) => None,
+      packets_capnp::hero::attack_target::Entity( e ) => Some( EntityId( e ) )
+    };
+
+    h.xp = hero.borrow().get_xp();
+    h.gold = hero.borrow().get_gold();
+    h.ability_points = hero.borrow().get_ability_points() as u32;
+    h.ranks = Packet::read_ranks( try!( hero.borrow().get_ranks() ) );
+    h.mana = hero.borrow().get_mana();
+    h.cooldowns
+      = Packet::read_cooldowns( try!( hero.borrow().get_cooldowns() ) );
+    h.statuses = try!( Packet::read_statuses( try!( hero.get_statuses() ) ) );
+
+    Ok( h )
+  }
+
+  pub fn read_statuses(
+    list : capnp::struct_list::Reader<packets_capnp::status_effect::Reader> )
+    -> capnp::Result<Statuses> {
+    let mut statuses = Statuses::new();
+
+    for e in list.iter() {
+      let status = match try!( e.borrow().get_status().which() ) {
+        packets_capnp::status_effect::status::Stun( () ) => Status::Stun,
+        packets_capnp::status_effect::status::Root( () ) => Status::Root,
+        packets_capnp::status_effect::status::Slow( f ) => Status::Slow( f ),
+        packets_capnp::status_effect::status::Burn( d ) => Status::Burn( d ),
+        packets_capnp::status_effect::status::Shield( a ) => Status::Shield( a )
+      };
+
+      statuses.effects.push( StatusEffect { status    : status
+                                          , remaining : e.get_remaining()
+                                          , source    : None
+                                          , tick      : BURN_TICK } );
+    }
+
+    Ok( statuses )
+  }
+
+  pub fn read_inventory(
+    list  : capnp::struct_list::Reader<packets_capnp::inventory_slot::Reader>
+  , items : &ItemBook )
+    -> capnp::Result<Inventory> {
+    let mut inventory = Inventory::new();
+
+    for (i, s) in list.iter().take( INVENTORY_SLOTS ).enumerate() {
+      inventory.slots[i] = match try!( s.borrow().get_item().which() ) {
+        packets_capnp::inventory_slot::item::Empty( () ) => None,
+        packets_capnp::inventory_slot::item::Id( id ) => {
+          let def = try!( items.get( id )
+                            .ok_or( capnp::Error::new_decode_error(
+                                      "Unknown item", None ) ) );
+          Some( Item { def : def.clone(), cooldown : s.get_cooldown() } )
+        }
+      };
+    }
+
+    Ok( inventory )
+  }
+
+  pub fn read_bonus( bonus : packets_capnp::bonus::Reader ) -> Bonus {
+    Bonus { hp     : bonus.get_hp()
+          , armor  : bonus.get_armor()
+          , mana   : bonus.get_mana()
+          , damage : bonus.get_damage()
+          , speed  : bonus.get_speed() }
+  }
+
+  pub fn read_ranks( ranks : ::capnp::primitive_list::Reader<u8> )
+    -> [u32; ABILITY_SLOTS] {
+    let mut r = [0; ABILITY_SLOTS];
+
+    for i in 0..::std::cmp::min( ranks.len() as usize, ABILITY_SLOTS ) {
+      r[i] = ranks.get( i as u32 ) as u32;
+    }
+
+    r
+  }
+
+  pub fn read_cooldowns( cds : ::capnp::primitive_list::Reader<f32> )
+    -> [f32; ABILITY_SLOTS] {
+    let mut cooldowns = [0.0; ABILITY_SLOTS];
+
+    for i in 0..::std::cmp::min( cds.len() as usize, ABILITY_SLOTS ) {
+      cooldowns[i] = cds.get( i as u32 );
+    }
+
+    cooldowns
+  }
+
+  pub fn read_attack( attack : packets_capnp::attack::Reader )
+    -> capnp::Result<Attack> {
+    let target = match try!( attack.borrow().get_target().which() ) {
+      packets_capnp::attack::target::None( () ) => None,
+      packets_capnp::attack::target::Entity( e ) => Some( EntityId( e ) )
+    };
+
+    Ok( Attack { range            : attack.get_range()
+               , cooldown         : attack.get_cooldown()
+               , damage           : attack.get_damage()
+               , timer            : attack.get_timer()
+               , target           : target
+               , projectile_speed : attack.get_projectile_speed()
+               , flash            : 0.0 } )
+  }
+
+  pub fn read_projectile( p : packets_capnp::projectile::Reader )
+    -> capnp::Result<Projectile> {
+    let motion = match try!( p.borrow().get_motion().which() ) {
+      packets_capnp::projectile::motion::Linear( () ) => Motion::Linear,
+      packets_capnp::projectile::motion::Homing( t ) =>
+        Motion::Homing( EntityId( t ) )
+    };
+
+    let on_hit = match try!( p.borrow().get_on_hit().which() ) {
+      packets_capnp::projectile::on_hit::Damage( d ) => OnHit::Damage( d ),
+      packets_capnp::projectile::on_hit::Heal( h ) => OnHit::Heal( h )
+    };
+
+    Ok( Projectile { entity   :
+                     try!( Packet::read_entity( try!( p.borrow().get_entity() ) ) )
+                   , owner    : EntityId( p.borrow().get_owner() )
+                   , team     : try!( Packet::read_team( p.borrow().get_team() ) )
+                   , lifetime : p.borrow().get_lifetime()
+                   , speed    : p.borrow().get_speed()
+                   , heading  :
+                     vec2_from_fixed(
+                       Packet::read_vec2( try!( p.get_heading() ) ) )
+                   , motion   : motion
+                   , on_hit   : on_hit
+                   , status   : None } )
+  }
+
+  pub fn read_health( health : packets_capnp::health::Reader ) -> Health {
+    Health { hp     : health.get_hp()
+           , max_hp : health.get_max_hp()
+           , armor  : health.get_armor() }
   }
 
   pub fn read_entity( sync : packets_capnp::entity::Reader )
@@ -179,56 +562,116 @@ impl Packet {
     -> capnp::Result<Hitbox> {
     Ok( match try!( hitbox.which() ) {
       packets_capnp::hitbox::None( () ) => Hitbox::None,
-      packets_capnp::hitbox::Circle( r ) => Hitbox::Circle( r )
+      packets_capnp::hitbox::Circle( r ) => Hitbox::Circle( r ),
+      packets_capnp::hitbox::Aabb( h ) =>
+        Hitbox::Aabb( vec2_from_fixed( Packet::read_vec2( try!( h ) ) ) ),
+      packets_capnp::hitbox::Capsule( c ) => {
+        let c = try!( c );
+        Hitbox::Capsule(
+          vec2_from_fixed( Packet::read_vec2( try!( c.borrow().get_half_length() ) ) )
+          , c.get_radius() )
+      }
     } )
   }
 
-  pub fn read_color( color : packets_capnp::color::Reader ) -> [f32; 4] {
-    [ color.get_r(), color.get_g(), color.get_b(), color.get_a() ]
+  pub fn read_team( team : u8 ) -> capnp::Result<Team> {
+    Team::from_index( team ).ok_or(
+      capnp::Error::new_decode_error( "Invalid team", None ) )
   }
 
-  pub fn write_connect( stream : &mut BufUdpStream ) {
-    use capnp::serialize_packed;
-    use packets_capnp::connect;
+  pub fn read_match_ended( me : packets_capnp::match_ended::Reader )
+    -> capnp::Result<MatchEndedPacket> {
+    let mut players = Vec::new();
+
+    for p in try!( me.borrow().get_stats() ).iter() {
+      players.push(
+        PlayerStats { hero_id         : EntityId( p.get_hero_id() )
+                    , kills           : p.get_kills()
+                    , deaths          : p.get_deaths()
+                    , minion_kills    : p.get_minion_kills()
+                    , structure_kills : p.get_structure_kills()
+                    , damage_dealt    : p.get_damage_dealt() } );
+    }
 
-    let mut message = Builder::new_default();
+    Ok( MatchEndedPacket { winner : try!( Packet::read_team( me.get_winner() ) )
+                         , stats  : MatchStats { players : players } } )
+  }
 
-    {
-      let mut is = message.init_root::<connect::Builder>();
+  pub fn read_match_state( ms : packets_capnp::match_state::Reader )
+    -> capnp::Result<MatchState> {
+    Ok( match try!( ms.which() ) {
+      packets_capnp::match_state::Lobby( () ) => MatchState::Lobby,
+      packets_capnp::match_state::Loading( () ) => MatchState::Loading,
+      packets_capnp::match_state::Countdown( left ) =>
+        MatchState::Countdown( left ),
+      packets_capnp::match_state::InProgress( () ) => MatchState::InProgress,
+      packets_capnp::match_state::Paused( () ) => MatchState::Paused,
+      packets_capnp::match_state::Ended( t ) =>
+        MatchState::Ended( try!( Packet::read_team( t ) ) )
+    } )
+  }
+
+  pub fn read_roster(
+    roster : capnp::struct_list::Reader<packets_capnp::player::Reader> )
+    -> capnp::Result<Roster> {
+    let mut players = Vec::new();
+
+    for p in roster.iter() {
+      players.push( try!( Packet::read_player( p ) ) );
     }
 
-    serialize_packed::write_message( stream, &mut message ).unwrap();
-    stream.flush();
+    Ok( Roster { players : players } )
   }
 
-  pub fn write_initial_sync( id : usize, stream : &mut BufUdpStream ) {
-    use capnp::serialize_packed;
-    use packets_capnp::initial_sync;
+  pub fn read_player( p : packets_capnp::player::Reader )
+    -> capnp::Result<Player> {
+    Ok( Player { hero_id : EntityId( p.borrow().get_hero_id() )
+               , team    : try!( Packet::read_team( p.borrow().get_team() ) )
+               , name    : try!( p.borrow().get_name() ).to_string()
+               , hero    : try!( p.get_hero() ).to_string() } )
+  }
 
-    let mut message = Builder::new_default();
+  pub fn read_welcome( w : packets_capnp::welcome::Reader )
+    -> capnp::Result<Welcome> {
+    Ok( Welcome { your_id  : EntityId( w.borrow().get_your_id() )
+                , map_name : try!( w.borrow().get_map_name() ).to_string()
+                , map_hash : w.borrow().get_map_hash()
+                , heroes_hash : w.borrow().get_heroes_hash()
+                , items_hash  : w.get_items_hash() } )
+  }
 
-    {
-      let mut is = message.init_root::<initial_sync::Builder>();
+  pub fn read_lobby(
+    lobby : capnp::struct_list::Reader<packets_capnp::lobby_player::Reader> )
+    -> capnp::Result<Vec<LobbyPlayer>> {
+    let mut players = Vec::new();
 
-      is.set_your_id( id as u8 );
+    for p in lobby.iter() {
+      players.push(
+        LobbyPlayer { player : try!( Packet::read_player(
+                                       try!( p.borrow().get_player() ) ) )
+                    , ready  : p.get_ready() } );
     }
 
-    serialize_packed::write_message( stream, &mut message ).unwrap();
-    stream.flush();
+    Ok( players )
+  }
+
+  pub fn read_choice( c : packets_capnp::lobby_choice::Reader )
+    -> capnp::Result<LobbyChoice> {
+    Ok( LobbyChoice { team  : try!( Packet::read_team( c.borrow().get_team() ) )
+                    , hero  : try!( c.borrow().get_hero() ).to_string()
+                    , ready : c.get_ready() } )
   }
 
-  pub fn write_packet( self, stream : &mut BufUdpStream ) {
+  pub fn write_packet( self, sequence : u32, stream : &mut BufUdpStream ) {
     use capnp::serialize_packed;
     use packets_capnp::packet;
 
-    println!( "{}", match &self { &Packet::InstructionPacket( .. ) => "inst"
-                                , &Packet::SyncPacket( .. ) => "sync"
-                                , _ => "other" } );
-
     let mut message = Builder::new_default();
     {
       let mut pkt = message.init_root::<packet::Builder>();
 
+      pkt.set_sequence( sequence );
+
       match self {
         Packet::InstructionPacket( ip ) =>
           Packet::write_instruction( ip, pkt.init_instruction() ),
@@ -237,7 +680,28 @@ impl Packet {
           Packet::write_sync( sp, pkt.init_sync() ),
 
         Packet::Ping => pkt.set_ping( () ),
-        Packet::YourPing( yp ) => pkt.set_your_ping( yp )
+        Packet::Pong => pkt.set_pong( () ),
+        Packet::YourPing( yp ) => pkt.set_your_ping( yp ),
+        Packet::Death( dp ) => Packet::write_death( dp, pkt.init_death() ),
+        Packet::Spawn( sp ) => Packet::write_spawn( sp, pkt.init_spawn() ),
+        Packet::Despawn( id ) => pkt.set_despawn( id.0 ),
+        Packet::MatchEnded( me ) =>
+          Packet::write_match_ended( me, pkt.init_match_ended() ),
+        Packet::MatchState( ms ) =>
+          Packet::write_match_state( ms, pkt.init_match_state() ),
+        Packet::Ready( id ) => pkt.set_ready( id.0 ),
+        Packet::Join( name ) => pkt.set_join( &name ),
+        Packet::Welcome( w ) => Packet::write_welcome( w, pkt.init_welcome() ),
+        Packet::Lobby( players ) => {
+          let n = players.len() as u32;
+          Packet::write_lobby( players, pkt.init_lobby( n ) )
+        },
+        Packet::Choice( c ) => Packet::write_choice( c, pkt.init_choice() ),
+        Packet::Start( roster ) => {
+          let n = roster.players.len() as u32;
+          Packet::write_roster( roster, pkt.init_start( n ) )
+        },
+        Packet::Hide( id ) => pkt.set_hide( id.0 )
       }
     }
 
@@ -248,16 +712,39 @@ impl Packet {
 
   pub fn write_instruction( ip   : InstructionPacket
                       , mut inst : packets_capnp::instruction::Builder ) {
-    inst.set_hero_id( ip.hero_id as u8 );
-    let mut move_to = inst.init_move_to();
-
-    match ip.move_to {
-      Some( v ) => Packet::write_vec2( v.into_fixed(), move_to.init_target() ),
-      None => move_to.set_nowhere( () )
+    inst.set_hero_id( ip.hero_id.0 );
+    let mut order = inst.init_order();
+
+    match ip.order {
+      Some( Order::Move( v ) ) =>
+        Packet::write_vec2( v.into_fixed(), order.init_move_to() ),
+      Some( Order::Attack( id ) ) => order.set_attack( id.0 ),
+      Some( Order::Cast( slot, target ) ) =>
+        Packet::write_cast( slot, target, order.init_cast() ),
+      Some( Order::Learn( slot ) ) => order.set_learn( slot as u8 ),
+      Some( Order::BuyItem( id ) ) => order.set_buy_item( id ),
+      Some( Order::SellItem( slot ) ) => order.set_sell_item( slot as u8 ),
+      Some( Order::UseItem( slot, target ) ) =>
+        Packet::write_cast( slot, target, order.init_use_item() ),
+      None => order.set_nowhere( () )
     }
 
   }
 
+  pub fn write_cast( slot : usize, target : CastTarget
+                   , mut cast : packets_capnp::cast::Builder ) {
+    cast.set_slot( slot as u8 );
+    let mut t = cast.init_target();
+
+    match target {
+      CastTarget::NoTarget => t.set_none( () ),
+      CastTarget::Point( p ) => Packet::write_vec2( p.into_fixed(), t.init_point() ),
+      CastTarget::Unit( id ) => t.set_unit( id.0 ),
+      CastTarget::Direction( d ) =>
+        Packet::write_vec2( d.into_fixed(), t.init_direction() )
+    }
+  }
+
   pub fn write_vec2( v : [f32; 2], mut ve : packets_capnp::vec2::Builder ) {
     ve.set_x( v[0] );
     ve.set_y( v[1] );
@@ -265,15 +752,131 @@ impl Packet {
 
   pub fn write_sync( sp   : SyncPacket
                , mut sync : packets_capnp::sync::Builder ) {
-    sync.set_hero_id( sp.hero_id as u8 );
+    sync.set_id( sp.id.0 );
     let mut frame = sync.init_sync_frame();
 
-    Packet::write_hero( sp.sync_frame, frame );
+    Packet::write_actor( sp.sync_frame, frame );
+  }
+
+  pub fn write_spawn( sp : SpawnPacket, mut spawn : packets_capnp::spawn::Builder ) {
+    spawn.set_id( sp.id.0 );
+    Packet::write_actor( sp.actor, spawn.init_actor() );
+  }
+
+  pub fn write_actor( actor : Actor, mut a : packets_capnp::actor::Builder ) {
+    match actor {
+      Actor::Hero( h ) => Packet::write_hero( h, a.init_hero() ),
+      Actor::Minion( m ) => Packet::write_minion( m, a.init_minion() ),
+      Actor::Structure( s ) => Packet::write_structure( s, a.init_structure() ),
+      Actor::Projectile( p ) => Packet::write_projectile( p, a.init_projectile() )
+    }
+  }
+
+  pub fn write_minion( minion : Minion, mut m : packets_capnp::minion::Builder ) {
+    Packet::write_entity( minion.entity, m.borrow().init_entity() );
+    m.borrow().set_team( minion.team.index() );
+    Packet::write_health( minion.health, m.borrow().init_health() );
+    Packet::write_attack( minion.attack, m.borrow().init_attack() );
+    let n = minion.statuses.effects.len() as u32;
+    Packet::write_statuses( minion.statuses, m.borrow().init_statuses( n ) );
+    let mut target_pos = m.init_target_pos();
+
+    match minion.target_pos {
+      Some( v ) => Packet::write_vec2( v.into_fixed(), target_pos.init_target() ),
+      None => target_pos.set_nowhere( () )
+    }
+  }
+
+  pub fn write_structure( structure : Structure
+                        , mut s : packets_capnp::structure::Builder ) {
+    Packet::write_entity( structure.entity, s.borrow().init_entity() );
+    s.borrow().set_team( structure.team.index() );
+    s.borrow().set_kind( structure.kind.index() );
+    Packet::write_health( structure.health, s.borrow().init_health() );
+    let mut attack = s.init_attack();
+
+    match structure.attack {
+      Some( a ) => Packet::write_attack( a, attack.init_armed() ),
+      None => attack.set_unarmed( () )
+    }
+  }
+
+  pub fn write_death( dp : DeathPacket, mut death : packets_capnp::death::Builder ) {
+    death.set_id( dp.id.0 );
+    let mut killer = death.init_killer();
+
+    match dp.killer {
+      Some( k ) => killer.set_entity( k.0 ),
+      None => killer.set_nobody( () )
+    }
   }
 
   pub fn write_hero( hero : Hero, mut frame : packets_capnp::hero::Builder ) {
-    Packet::write_entity( hero.entity, frame.borrow().init_entity() );
-    Packet::write_color( hero.color, frame.borrow().init_color() );
+    frame.borrow().set_archetype( hero.def.id );
+    frame.borrow().set_team( hero.team.index() );
+    Packet::write_vec2( hero.entity.pos.into_fixed()
+                      , frame.borrow().init_pos() );
+    Packet::write_vec2( hero.entity.vel.into_fixed()
+                      , frame.borrow().init_vel() );
+    frame.borrow().set_hp( hero.health.hp );
+    frame.borrow().set_attack_timer( hero.attack.timer );
+    frame.borrow().set_mana( hero.mana );
+    frame.borrow().set_level( hero.level as u8 );
+    frame.borrow().set_xp( hero.xp );
+    frame.borrow().set_gold( hero.gold );
+    frame.borrow().set_ability_points( hero.ability_points as u8 );
+
+    {
+      let mut ranks = frame.borrow().init_ranks( ABILITY_SLOTS as u32 );
+      for (i, r) in hero.ranks.iter().enumerate() {
+        ranks.set( i as u32, *r as u8 );
+      }
+    }
+
+    {
+      let mut items = frame.borrow().init_items( INVENTORY_SLOTS as u32 );
+      for (i, s) in hero.inventory.slots.iter().enumerate() {
+        let mut entry = items.borrow().get( i as u32 );
+        match *s {
+          Some( ref item ) => {
+            entry.borrow().init_item().set_id( item.def.id );
+            entry.set_cooldown( item.cooldown );
+          },
+          None => entry.init_item().set_empty( () )
+        }
+      }
+    }
+
+    Packet::write_bonus( hero.bonus, frame.borrow().init_bonus() );
+
+    let n = hero.statuses.effects.len() as u32;
+    Packet::write_statuses( hero.statuses, frame.borrow().init_statuses( n ) );
+
+    {
+      let mut target = frame.borrow().init_attack_target();
+
+      match hero.attack.target {
+        Some( id ) => target.set_entity( id.0 ),
+        None => target.set_none( () )
+      }
+    }
+
+    {
+      let mut cds = frame.borrow().init_cooldowns( ABILITY_SLOTS as u32 );
+      for (i, cd) in hero.cooldowns.iter().enumerate() {
+        cds.set( i as u32, *cd );
+      }
+    }
+
+    {
+      let mut life = frame.borrow().init_life();
+
+      match hero.life {
+        LifeState::Alive => life.set_alive( () ),
+        LifeState::Dead { respawn_in } => life.set_dead( respawn_in )
+      }
+    }
+
     let mut target_pos = frame.init_target_pos();
 
     match hero.target_pos {
@@ -282,6 +885,78 @@ impl Packet {
     }
   }
 
+  pub fn write_bonus( b : Bonus, mut bonus : packets_capnp::bonus::Builder ) {
+    bonus.set_hp( b.hp );
+    bonus.set_armor( b.armor );
+    bonus.set_mana( b.mana );
+    bonus.set_damage( b.damage );
+    bonus.set_speed( b.speed );
+  }
+
+  pub fn write_statuses(
+      statuses : Statuses
+    , mut list : capnp::struct_list::Builder<packets_capnp::status_effect::Builder> ) {
+    for (i, e) in statuses.effects.into_iter().enumerate() {
+      let mut entry = list.borrow().get( i as u32 );
+      entry.set_remaining( e.remaining );
+      let mut status = entry.init_status();
+
+      match e.status {
+        Status::Stun => status.set_stun( () ),
+        Status::Root => status.set_root( () ),
+        Status::Slow( f ) => status.set_slow( f ),
+        Status::Burn( d ) => status.set_burn( d ),
+        Status::Shield( a ) => status.set_shield( a )
+      }
+    }
+  }
+
+  pub fn write_health( health : Health, mut h : packets_capnp::health::Builder ) {
+    h.set_hp( health.hp );
+    h.set_max_hp( health.max_hp );
+    h.set_armor( health.armor );
+  }
+
+  pub fn write_attack( attack : Attack, mut a : packets_capnp::attack::Builder ) {
+    a.set_range( attack.range );
+    a.set_cooldown( attack.cooldown );
+    a.set_damage( attack.damage );
+    a.set_timer( attack.timer );
+    a.set_projectile_speed( attack.projectile_speed );
+    let mut target = a.init_target();
+
+    match attack.target {
+      Some( id ) => target.set_entity( id.0 ),
+      None => target.set_none( () )
+    }
+  }
+
+  pub fn write_projectile( p : Projectile
+                         , mut proj : packets_capnp::projectile::Builder ) {
+    Packet::write_entity( p.entity, proj.borrow().init_entity() );
+    proj.set_owner( p.owner.0 );
+    proj.set_team( p.team.index() );
+    proj.set_lifetime( p.lifetime );
+    proj.set_speed( p.speed );
+    Packet::write_vec2( p.heading.into_fixed(), proj.borrow().init_heading() );
+
+    {
+      let mut motion = proj.borrow().init_motion();
+
+      match p.motion {
+        Motion::Linear => motion.set_linear( () ),
+        Motion::Homing( t ) => motion.set_homing( t.0 )
+      }
+    }
+
+    let mut on_hit = proj.init_on_hit();
+
+    match p.on_hit {
+      OnHit::Damage( d ) => on_hit.set_damage( d ),
+      OnHit::Heal( h ) => on_hit.set_heal( h )
+    }
+  }
+
   pub fn write_entity( entity : Entity, mut ent : packets_capnp::entity::Builder ) {
     Packet::write_vec2( entity.pos.into_fixed(), ent.borrow().init_pos() );
     Packet::write_vec2( entity.vel.into_fixed(), ent.borrow().init_vel() );
@@ -291,23 +966,104 @@ impl Packet {
   pub fn write_hitbox( hitbox : Hitbox, mut hit : packets_capnp::hitbox::Builder ) {
     match hitbox {
       Hitbox::None => hit.set_none( () ),
-      Hitbox::Circle( r ) => hit.set_circle( r )
+      Hitbox::Circle( r ) => hit.set_circle( r ),
+      Hitbox::Aabb( h ) => Packet::write_vec2( h.into_fixed(), hit.init_aabb() ),
+      Hitbox::Capsule( h, r ) => {
+        let mut c = hit.init_capsule();
+        Packet::write_vec2( h.into_fixed(), c.borrow().init_half_length() );
+        c.set_radius( r );
+      }
+    }
+  }
+
+  pub fn write_match_ended( me : MatchEndedPacket
+                          , mut m : packets_capnp::match_ended::Builder ) {
+    m.set_winner( me.winner.index() );
+    let mut list = m.init_stats( me.stats.players.len() as u32 );
+
+    for (i, p) in me.stats.players.into_iter().enumerate() {
+      let mut entry = list.borrow().get( i as u32 );
+      entry.set_hero_id( p.hero_id.0 );
+      entry.set_kills( p.kills );
+      entry.set_deaths( p.deaths );
+      entry.set_minion_kills( p.minion_kills );
+      entry.set_structure_kills( p.structure_kills );
+      entry.set_damage_dealt( p.damage_dealt );
+    }
+  }
+
+  pub fn write_match_state( state : MatchState
+                          , mut ms : packets_capnp::match_state::Builder ) {
+    match state {
+      MatchState::Lobby => ms.set_lobby( () ),
+      MatchState::Loading => ms.set_loading( () ),
+      MatchState::Countdown( left ) => ms.set_countdown( left ),
+      MatchState::InProgress => ms.set_in_progress( () ),
+      MatchState::Paused => ms.set_paused( () ),
+      MatchState::Ended( t ) => ms.set_ended( t.index() )
+    }
+  }
+
+  pub fn write_roster(
+      roster   : Roster
+    , mut list : capnp::struct_list::Builder<packets_capnp::player::Builder> ) {
+    for (i, p) in roster.players.into_iter().enumerate() {
+      Packet::write_player( p, list.borrow().get( i as u32 ) );
+    }
+  }
+
+  pub fn write_player( p : Player, mut entry : packets_capnp::player::Builder ) {
+    entry.set_hero_id( p.hero_id.0 );
+    entry.set_team( p.team.index() );
+    entry.set_name( &p.name );
+    entry.set_hero( &p.hero );
+  }
+
+  pub fn write_welcome( w : Welcome, mut welcome : packets_capnp::welcome::Builder ) {
+    welcome.set_your_id( w.your_id.0 );
+    welcome.set_map_name( &w.map_name );
+    welcome.set_map_hash( w.map_hash );
+    welcome.set_heroes_hash( w.heroes_hash );
+    welcome.set_items_hash( w.items_hash );
+  }
+
+  pub fn write_lobby(
+      players  : Vec<LobbyPlayer>
+    , mut list : capnp::struct_list::Builder<packets_capnp::lobby_player::Builder> ) {
+    for (i, p) in players.into_iter().enumerate() {
+      let mut entry = list.borrow().get( i as u32 );
+      entry.set_ready( p.ready );
+      Packet::write_player( p.player, entry.init_player() );
     }
   }
 
-  pub fn write_color( color : [f32; 4], mut col : packets_capnp::color::Builder ) {
-    col.set_r( color[0] );
-    col.set_g( color[1] );
-    col.set_b( color[2] );
-    col.set_a( color[3] );
+  pub fn write_choice( c : LobbyChoice, mut choice : packets_capnp::lobby_choice::Builder ) {
+    choice.set_team( c.team.index() );
+    choice.set_hero( &c.hero );
+    choice.set_ready( c.ready );
   }
 
 }
 
 pub fn net_thread( mut stream : UdpStream
-             , outbox     : Sender<Packet>
-             , killer     : Receiver<()> ) {
+             , outbox     : Sender<Incoming>
+             , killer     : Receiver<()>
+             , heroes     : Arc<HeroBook>
+             , items      : Arc<ItemBook> ) {
   loop {
-    outbox.send( Packet::read_packet( &mut stream ).unwrap() );
+    // Anyone can send us a datagram, a broken one shouldn't take us down
+    let (sequence, packet)
+      = match Packet::read_packet( &mut stream, &heroes, &items ) {
+          Ok( p ) => p,
+          Err( e ) => {
+            warn!( Packet, "Dropping undecodable packet from {:?}: {:?}"
+                 , stream.sender, e );
+            continue
+          }
+        };
+
+    outbox.send( Incoming { sender   : stream.sender.unwrap()
+                          , sequence : sequence
+                          , packet   : packet } );
   }
 }
\ No newline at end of file
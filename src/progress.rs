@@ -0,0 +1,44 @@
+use entity::StructureKind;
+use world::Actor;
+
+// Heroes stop leveling up here
+pub const MAX_LEVEL : u32 = 10;
+// Abilities can't be ranked up any further than this
+pub const MAX_ABILITY_RANK : u32 = 4;
+// Heroes of the killer's team this close to a death share its experience
+pub const XP_RANGE : f32 = 250.0;
+
+/// Experience it takes to get from `level` to the next one.
+pub fn xp_to_next( level : u32 ) -> u32 {
+  100 * level
+}
+
+/// How high abilities can be ranked at `level`, every other level allows
+/// one more rank.
+pub fn max_rank( level : u32 ) -> u32 {
+  ::std::cmp::min( MAX_ABILITY_RANK, ( level + 1 ) / 2 )
+}
+
+/// What killing something is worth.
+#[derive(Clone, Copy, Debug)]
+pub struct Bounty {
+  pub xp   : u32,
+  pub gold : u32
+}
+
+pub fn bounty( victim : &Actor ) -> Bounty {
+  let (xp, gold) = match *victim {
+    // Higher level heroes are worth more
+    Actor::Hero( ref h ) => ( 100 + 20 * h.level, 150 + 15 * h.level ),
+    Actor::Minion( _ ) => ( 30, 20 ),
+    Actor::Structure( ref s ) => match s.kind {
+      StructureKind::Tower    => ( 150, 200 ),
+      StructureKind::Barracks => ( 200, 300 ),
+      // Ends the match, nothing left to spend it on
+      StructureKind::Core     => ( 0, 0 )
+    },
+    Actor::Projectile( _ ) => ( 0, 0 )
+  };
+
+  Bounty { xp : xp, gold : gold }
+}
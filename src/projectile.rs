@@ -0,0 +1,107 @@
+use cgmath::{Point, Vector, EuclideanVector};
+use entity::{Entity, Hitbox, Pos2, Vec2};
+use world::EntityId;
+use team::{self, Team};
+use status::StatusDef;
+
+#[derive(Clone, Debug)]
+pub enum Motion {
+  Linear,
+  Homing( EntityId )
+}
+
+/// What happens to the hero a projectile hits.
+#[derive(Clone, Copy, Debug)]
+pub enum OnHit {
+  Damage( f32 ),
+  Heal( f32 )
+}
+
+/// The tunable parts of a projectile, used by abilities and ranged attacks
+/// to describe what they shoot.
+#[derive(Clone, Copy, Debug)]
+pub struct ProjectileDef {
+  pub speed    : f32,
+  pub radius   : f32,
+  pub lifetime : f32,
+  pub on_hit   : OnHit,
+  // Put on whatever it hits, on top of `on_hit`
+  pub status   : Option<StatusDef>
+}
+
+impl OnHit {
+  pub fn scaled( &self, factor : f32 ) -> OnHit {
+    match *self {
+      OnHit::Damage( d ) => OnHit::Damage( d * factor ),
+      OnHit::Heal( h ) => OnHit::Heal( h * factor )
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Projectile {
+  pub entity   : Entity,
+  pub owner    : EntityId,
+  pub team     : Team,
+  pub lifetime : f32,
+  pub speed    : f32,
+  pub heading  : Vec2,
+  pub motion   : Motion,
+  pub on_hit   : OnHit,
+  // Only known to the host, which is the only one applying hits
+  pub status   : Option<StatusDef>
+}
+
+impl Projectile {
+  pub fn new( def : &ProjectileDef, owner : EntityId, team : Team, pos : Pos2
+            , heading : Vec2, motion : Motion ) -> Projectile {
+    Projectile { entity   : Entity { pos    : pos
+                                   , vel    : Vec2::new( 0.0, 0.0 )
+                                   , hitbox : Hitbox::Circle( def.radius ) }
+               , owner    : owner
+               , team     : team
+               , lifetime : def.lifetime
+               , speed    : def.speed
+               , heading  : heading.normalize()
+               , motion   : motion
+               , on_hit   : def.on_hit
+               , status   : def.status }
+  }
+
+  /// Whether the projectile may hit the entity with the given ID and team.
+  /// Homing projectiles only ever hit what they're homing in on, others fly
+  /// past anything they wouldn't affect: allies when they hurt, enemies when
+  /// they heal.
+  pub fn can_hit( &self, id : EntityId, target_team : Option<Team> ) -> bool {
+    let affects = match self.on_hit {
+      OnHit::Damage( _ ) => team::hostile( Some( self.team ), target_team ),
+      OnHit::Heal( _ ) => !team::hostile( Some( self.team ), target_team )
+    };
+
+    affects && match self.motion {
+      Motion::Homing( t ) => id == t,
+      Motion::Linear => id != self.owner
+    }
+  }
+
+  pub fn expired( &self ) -> bool {
+    self.lifetime <= 0.0
+  }
+
+  /// Steps the projectile, `target_pos` is where its homing target is, or
+  /// `None` if it's gone and the projectile should just carry on straight.
+  pub fn update( &mut self, delta_time : f64, target_pos : Option<Pos2> ) {
+    if let Some( t ) = target_pos {
+      let to_target = t.sub_p( &self.entity.pos );
+      if to_target.length() > 0.0 {
+        self.heading = to_target.normalize();
+      }
+    } else {
+      self.motion = Motion::Linear;
+    }
+
+    self.lifetime -= delta_time as f32;
+    self.entity.vel = self.heading.mul_s( self.speed );
+    self.entity.update( delta_time );
+  }
+}
@@ -0,0 +1,45 @@
+use world::EntityId;
+use team::Roster;
+
+/// How a player did over the match, kept by the host and sent to everyone
+/// when it ends.
+#[derive(Clone, Copy, Debug)]
+pub struct PlayerStats {
+  pub hero_id         : EntityId,
+  pub kills           : u32,
+  pub deaths          : u32,
+  pub minion_kills    : u32,
+  pub structure_kills : u32,
+  pub damage_dealt    : f32
+}
+
+impl PlayerStats {
+  pub fn new( hero_id : EntityId ) -> PlayerStats {
+    PlayerStats { hero_id         : hero_id
+                , kills           : 0
+                , deaths          : 0
+                , minion_kills    : 0
+                , structure_kills : 0
+                , damage_dealt    : 0.0 }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct MatchStats {
+  pub players : Vec<PlayerStats>
+}
+
+impl MatchStats {
+  pub fn new( roster : &Roster ) -> MatchStats {
+    MatchStats { players : roster.players.iter()
+                                         .map( |p| PlayerStats::new( p.hero_id ) )
+                                         .collect() }
+  }
+
+  /// The stats of the player controlling `hero_id`, `None` for anything
+  /// that isn't a player's hero.
+  pub fn player_mut( &mut self, hero_id : EntityId )
+    -> Option<&mut PlayerStats> {
+    self.players.iter_mut().find( |p| p.hero_id == hero_id )
+  }
+}
@@ -0,0 +1,271 @@
+use world::EntityId;
+
+// Seconds between the damage ticks of a burn
+pub const BURN_TICK : f32 = 0.5;
+
+/// Something temporarily affecting a unit, good or bad.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+  /// Can't move, attack or cast
+  Stun,
+  /// Can't move, but can still attack and cast what doesn't move it
+  Root,
+  /// Moves slower by this fraction of its speed
+  Slow( f32 ),
+  /// Takes this much damage per second
+  Burn( f32 ),
+  /// Soaks up this much damage before its health is touched
+  Shield( f32 )
+}
+
+impl Status {
+  /// Whether this is something to put on enemies, shields are for allies.
+  pub fn is_harmful( &self ) -> bool {
+    match *self {
+      Status::Shield( _ ) => false,
+      _ => true
+    }
+  }
+
+  /// Whether `other` is the same kind of status, whatever its strength.
+  pub fn same_kind( &self, other : &Status ) -> bool {
+    match (*self, *other) {
+      (Status::Stun, Status::Stun)
+      | (Status::Root, Status::Root)
+      | (Status::Slow( _ ), Status::Slow( _ ))
+      | (Status::Burn( _ ), Status::Burn( _ ))
+      | (Status::Shield( _ ), Status::Shield( _ )) => true,
+      _ => false
+    }
+  }
+}
+
+/// A status to put on something and for how long, what abilities and
+/// projectiles apply.
+#[derive(Clone, Copy, Debug)]
+pub struct StatusDef {
+  pub status   : Status,
+  pub duration : f32
+}
+
+impl StatusDef {
+  /// Burns and shields made `factor` times as strong, the rest are what
+  /// they are.
+  pub fn scaled( &self, factor : f32 ) -> StatusDef {
+    let status = match self.status {
+      Status::Burn( d ) => Status::Burn( d * factor ),
+      Status::Shield( a ) => Status::Shield( a * factor ),
+      s => s
+    };
+
+    StatusDef { status : status, duration : self.duration }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct StatusEffect {
+  pub status    : Status,
+  pub remaining : f32,
+  // Who gets the credit for burn damage, only known to the host
+  pub source    : Option<EntityId>,
+  // Until the next burn tick
+  pub tick      : f32
+}
+
+/// Everything currently affecting a unit. Stuns and roots don't stack, a
+/// new one just makes the current one last longer if it would. Slows stack
+/// but only the strongest one counts, burns and shields simply add up.
+#[derive(Clone, Debug)]
+pub struct Statuses {
+  pub effects : Vec<StatusEffect>
+}
+
+impl Statuses {
+  pub fn new() -> Statuses {
+    Statuses { effects : Vec::new() }
+  }
+
+  pub fn apply( &mut self, def : StatusDef, source : Option<EntityId> ) {
+    if def.duration <= 0.0 {
+      return
+    }
+
+    match def.status {
+      Status::Stun | Status::Root => {
+        let current = self.effects.iter_mut()
+                                  .find( |e| e.status.same_kind( &def.status ) );
+        if let Some( e ) = current {
+          e.remaining = e.remaining.max( def.duration );
+          return
+        }
+      },
+      _ => ()
+    }
+
+    self.effects.push( StatusEffect { status    : def.status
+                                    , remaining : def.duration
+                                    , source    : source
+                                    , tick      : BURN_TICK } );
+  }
+
+  pub fn has( &self, status : Status ) -> bool {
+    self.effects.iter().any( |e| e.status.same_kind( &status ) )
+  }
+
+  pub fn is_stunned( &self ) -> bool {
+    self.has( Status::Stun )
+  }
+
+  pub fn can_move( &self ) -> bool {
+    !self.has( Status::Stun ) && !self.has( Status::Root )
+  }
+
+  /// What movement speed gets multiplied by.
+  pub fn speed_factor( &self ) -> f32 {
+    let slow = self.effects.iter().fold( 0.0, |s : f32, e| match e.status {
+      Status::Slow( f ) => s.max( f ),
+      _ => s
+    } );
+
+    ( 1.0 - slow ).max( 0.0 )
+  }
+
+  /// How much damage the shields can still soak up.
+  pub fn shield( &self ) -> f32 {
+    self.effects.iter().fold( 0.0, |s, e| match e.status {
+      Status::Shield( a ) => s + a,
+      _ => s
+    } )
+  }
+
+  /// Lets the shields soak up what they can of `amount` of damage, the
+  /// ones that run out first go first. Returns what gets through.
+  pub fn absorb( &mut self, amount : f32 ) -> f32 {
+    let mut left = amount;
+
+    {
+      let mut shields = self.effects.iter_mut()
+                                    .filter( |e| match e.status {
+                                      Status::Shield( _ ) => true,
+                                      _ => false
+                                    } )
+                                    .collect::<Vec<_>>();
+      shields.sort_by( |a, b| a.remaining.partial_cmp( &b.remaining )
+                                         .unwrap() );
+
+      for e in shields {
+        if let Status::Shield( ref mut a ) = e.status {
+          let soaked = a.min( left );
+          *a -= soaked;
+          left -= soaked;
+        }
+      }
+    }
+
+    self.effects.retain( |e| match e.status {
+      Status::Shield( a ) => a > 0.0,
+      _ => true
+    } );
+
+    left
+  }
+
+  /// Counts the effects down and drops the ones that wore off. Returns the
+  /// burn damage that ticked and who dealt it.
+  pub fn update( &mut self, delta_time : f64 ) -> Vec<(f32, Option<EntityId>)> {
+    let dt = delta_time as f32;
+    let mut burns = Vec::new();
+
+    for e in self.effects.iter_mut() {
+      if let Status::Burn( dps ) = e.status {
+        e.tick -= dt;
+        while e.tick <= 0.0 && e.remaining > 0.0 {
+          e.tick += BURN_TICK;
+          burns.push( ( dps * BURN_TICK, e.source ) );
+        }
+      }
+
+      e.remaining -= dt;
+    }
+
+    self.effects.retain( |e| e.remaining > 0.0 );
+    burns
+  }
+
+  pub fn clear( &mut self ) {
+    self.effects.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Status, StatusDef, Statuses};
+
+  fn def( status : Status, duration : f32 ) -> StatusDef {
+    StatusDef { status : status, duration : duration }
+  }
+
+  #[test]
+  fn stuns_last_longer_instead_of_stacking() {
+    let mut s = Statuses::new();
+    s.apply( def( Status::Stun, 1.0 ), None );
+    s.apply( def( Status::Stun, 2.0 ), None );
+    s.apply( def( Status::Stun, 0.5 ), None );
+
+    assert_eq!( s.effects.len(), 1 );
+    assert_eq!( s.effects[0].remaining, 2.0 );
+  }
+
+  #[test]
+  fn only_the_strongest_slow_counts() {
+    let mut s = Statuses::new();
+    s.apply( def( Status::Slow( 0.3 ), 2.0 ), None );
+    s.apply( def( Status::Slow( 0.5 ), 1.0 ), None );
+    assert_eq!( s.speed_factor(), 0.5 );
+
+    s.update( 1.5 );
+    assert!( ( s.speed_factor() - 0.7 ).abs() < 1e-6 );
+  }
+
+  #[test]
+  fn effects_wear_off() {
+    let mut s = Statuses::new();
+    s.apply( def( Status::Root, 1.0 ), None );
+    s.apply( def( Status::Stun, 0.0 ), None );
+    assert!( !s.can_move() && !s.is_stunned() );
+
+    s.update( 0.5 );
+    assert!( !s.can_move() );
+
+    s.update( 0.6 );
+    assert!( s.can_move() );
+    assert!( s.effects.is_empty() );
+  }
+
+  #[test]
+  fn burns_tick_until_they_run_out() {
+    let mut s = Statuses::new();
+    s.apply( def( Status::Burn( 10.0 ), 1.0 ), None );
+    s.apply( def( Status::Burn( 4.0 ), 1.0 ), None );
+
+    let total = s.update( 1.0 ).iter().fold( 0.0, |t, &(d, _)| t + d );
+    assert_eq!( total, 14.0 );
+    assert!( s.effects.is_empty() );
+  }
+
+  #[test]
+  fn shields_running_out_first_soak_first() {
+    let mut s = Statuses::new();
+    s.apply( def( Status::Shield( 10.0 ), 5.0 ), None );
+    s.apply( def( Status::Shield( 10.0 ), 1.0 ), None );
+    assert_eq!( s.shield(), 20.0 );
+
+    assert_eq!( s.absorb( 15.0 ), 0.0 );
+    assert_eq!( s.effects.len(), 1 );
+    assert_eq!( s.effects[0].remaining, 5.0 );
+    assert_eq!( s.shield(), 5.0 );
+
+    assert_eq!( s.absorb( 10.0 ), 5.0 );
+    assert!( s.effects.is_empty() );
+  }
+}
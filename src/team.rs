@@ -0,0 +1,86 @@
+use std::str::FromStr;
+use world::EntityId;
+
+/// The two sides of a match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Team {
+  Blue,
+  Red
+}
+
+pub const TEAMS : [Team; 2] = [ Team::Blue, Team::Red ];
+
+impl Team {
+  pub fn index( self ) -> u8 {
+    match self {
+      Team::Blue => 0,
+      Team::Red  => 1
+    }
+  }
+
+  pub fn from_index( i : u8 ) -> Option<Team> {
+    TEAMS.get( i as usize ).cloned()
+  }
+
+  pub fn opponent( self ) -> Team {
+    match self {
+      Team::Blue => Team::Red,
+      Team::Red  => Team::Blue
+    }
+  }
+
+  pub fn color( self ) -> [f32; 4] {
+    match self {
+      Team::Blue => [0.2, 0.4, 0.9, 1.0],
+      Team::Red  => [0.9, 0.2, 0.2, 1.0]
+    }
+  }
+}
+
+impl FromStr for Team {
+  type Err = String;
+
+  fn from_str( s : &str ) -> Result<Team, String> {
+    match s {
+      "blue" => Ok( Team::Blue ),
+      "red"  => Ok( Team::Red ),
+      _ => Err( format!( "Invalid team, expected blue or red: {}", s ) )
+    }
+  }
+}
+
+/// Friendly fire rules: whether something on team `source` may hurt
+/// something on team `target`. Anything without a team can hurt and be
+/// hurt by everyone.
+pub fn hostile( source : Option<Team>, target : Option<Team> ) -> bool {
+  match (source, target) {
+    (Some( a ), Some( b )) => a != b,
+    _ => true
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Player {
+  pub hero_id : EntityId,
+  pub team    : Team,
+  pub name    : String,
+  // Which hero they picked in the lobby
+  pub hero    : String
+}
+
+/// Who's playing and on which team, decided in the lobby and sent to
+/// everyone when the match starts.
+#[derive(Clone, Debug)]
+pub struct Roster {
+  pub players : Vec<Player>
+}
+
+impl Roster {
+  pub fn player( &self, hero_id : EntityId ) -> Option<&Player> {
+    self.players.iter().find( |p| p.hero_id == hero_id )
+  }
+
+  pub fn team_of( &self, hero_id : EntityId ) -> Option<Team> {
+    self.player( hero_id ).map( |p| p.team )
+  }
+}
@@ -0,0 +1,156 @@
+use std::io;
+use std::net::{UdpSocket, SocketAddr, SocketAddrV4, Ipv4Addr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::collections::HashMap;
+
+/// Something that can move datagrams between peers, it's what `UdpStream`
+/// sits on top of so that the game doesn't care whether it's talking over a
+/// real socket or an in-process channel.
+pub trait Transport : Send {
+  /// Sends a single datagram to `peer`.
+  fn send_to( &mut self, buf : &[u8], peer : SocketAddr ) -> io::Result<usize>;
+
+  /// Blocks until a datagram arrives, writes it into `buf` (truncating it
+  /// like UDP would if it doesn't fit) and returns its size and sender.
+  fn recv_from( &mut self, buf : &mut [u8] ) -> io::Result<(usize, SocketAddr)>;
+
+  /// Makes another handle to the same endpoint, used to hand the receiving
+  /// end to the net thread.
+  fn try_clone( &self ) -> io::Result<Box<Transport>>;
+}
+
+pub struct UdpTransport {
+  socket : UdpSocket
+}
+
+impl UdpTransport {
+  pub fn bind<A : ToSocketAddrs>( addr : A ) -> io::Result<UdpTransport> {
+    Ok( UdpTransport { socket : try!( UdpSocket::bind( addr ) ) } )
+  }
+}
+
+impl Transport for UdpTransport {
+  fn send_to( &mut self, buf : &[u8], peer : SocketAddr ) -> io::Result<usize> {
+    self.socket.send_to( buf, peer )
+  }
+
+  fn recv_from( &mut self, buf : &mut [u8] ) -> io::Result<(usize, SocketAddr)> {
+    self.socket.recv_from( buf )
+  }
+
+  fn try_clone( &self ) -> io::Result<Box<Transport>> {
+    Ok( Box::new( UdpTransport { socket : try!( self.socket.try_clone() ) } ) )
+  }
+}
+
+// The channel network runs a host and its clients in one process, which only
+// the tests do so far, hence the `allow( dead_code )`s
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// An in-process stand-in for the network. Every `ChannelTransport` bound on
+/// it gets an address, and datagrams sent to an address nobody is bound on
+/// are silently dropped, just like UDP.
+#[derive(Clone)]
+#[allow(dead_code)]
+pub struct ChannelNetwork {
+  peers : Arc<Mutex<HashMap<SocketAddr, Sender<Datagram>>>>
+}
+
+#[allow(dead_code)]
+impl ChannelNetwork {
+  pub fn new() -> ChannelNetwork {
+    ChannelNetwork { peers : Arc::new( Mutex::new( HashMap::new() ) ) }
+  }
+
+  pub fn bind( &self, addr : SocketAddr ) -> io::Result<ChannelTransport> {
+    let mut peers = self.peers.lock().unwrap();
+
+    if peers.contains_key( &addr ) {
+      return Err( io::Error::new( io::ErrorKind::AddrInUse
+                                , "address already bound on channel network" ) )
+    }
+
+    let (inb, outb) = channel();
+    peers.insert( addr, inb );
+
+    Ok( ChannelTransport { addr    : addr
+                         , network : self.clone()
+                         , inbox   : Arc::new( Mutex::new( outb ) ) } )
+  }
+}
+
+#[allow(dead_code)]
+pub struct ChannelTransport {
+  addr    : SocketAddr,
+  network : ChannelNetwork,
+  inbox   : Arc<Mutex<Receiver<Datagram>>>
+}
+
+#[allow(dead_code)]
+impl ChannelTransport {
+  pub fn local_addr( &self ) -> SocketAddr {
+    self.addr
+  }
+}
+
+impl Transport for ChannelTransport {
+  fn send_to( &mut self, buf : &[u8], peer : SocketAddr ) -> io::Result<usize> {
+    let peers = self.network.peers.lock().unwrap();
+
+    if let Some( p ) = peers.get( &peer ) {
+      // A hung up receiver is the same as nobody listening, so just drop it
+      let _ = p.send( (buf.to_vec(), self.addr) );
+    }
+
+    Ok( buf.len() )
+  }
+
+  fn recv_from( &mut self, buf : &mut [u8] ) -> io::Result<(usize, SocketAddr)> {
+    let inbox = self.inbox.lock().unwrap();
+
+    match inbox.recv() {
+      Ok( (data, sender) ) => {
+        let n = ::std::cmp::min( buf.len(), data.len() );
+        buf[..n].clone_from_slice( &data[..n] );
+        Ok( (n, sender) )
+      },
+      Err( _ ) => Err( io::Error::new( io::ErrorKind::ConnectionAborted
+                                     , "channel network closed" ) )
+    }
+  }
+
+  fn try_clone( &self ) -> io::Result<Box<Transport>> {
+    Ok( Box::new( ChannelTransport { addr    : self.addr
+                                   , network : self.network.clone()
+                                   , inbox   : self.inbox.clone() } ) )
+  }
+}
+
+#[allow(dead_code)]
+pub fn loopback_addr( port : u16 ) -> SocketAddr {
+  SocketAddr::V4( SocketAddrV4::new( Ipv4Addr::new( 127, 0, 0, 1 ), port ) )
+}
+
+/// A host and `clients` client transports on a fresh network, returned as
+/// `(host, clients)`. The host is on `HOST_PORT` and the clients on the
+/// ports from `CLIENT_PORT` up.
+#[allow(dead_code)]
+pub fn channel_hub( clients : u16 )
+  -> (ChannelTransport, Vec<ChannelTransport>) {
+  let network = ChannelNetwork::new();
+
+  let host = network.bind( loopback_addr( super::HOST_PORT ) ).unwrap();
+  let clients = ( 0..clients )
+    .map( |i| network.bind( loopback_addr( super::CLIENT_PORT + i ) ).unwrap() )
+    .collect();
+
+  (host, clients)
+}
+
+/// A host and a single client wired to each other, see `channel_hub`.
+#[allow(dead_code)]
+pub fn channel_pair() -> (ChannelTransport, ChannelTransport) {
+  let (host, mut clients) = channel_hub( 1 );
+  (host, clients.pop().unwrap())
+}
@@ -1,16 +1,17 @@
 use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::{TcpListener, TcpStream, UdpSocket, SocketAddr, ToSocketAddrs};
+use std::net::SocketAddr;
 use std;
+use transport::Transport;
 
 pub struct UdpStream {
-  pub socket : UdpSocket,
-  pub target : Option<SocketAddr>,
-  pub sender : Option<SocketAddr>
+  pub transport : Box<Transport>,
+  pub target    : Option<SocketAddr>,
+  pub sender    : Option<SocketAddr>
 }
 
 impl UdpStream {
-  pub fn new( sock : UdpSocket ) -> UdpStream {
-    UdpStream { socket : sock, target : None, sender : None }
+  pub fn new( transport : Box<Transport> ) -> UdpStream {
+    UdpStream { transport : transport, target : None, sender : None }
   }
 
   pub fn set_target( &mut self, target : SocketAddr ) {
@@ -18,15 +19,15 @@ impl UdpStream {
   }
 
   pub fn try_clone( &self ) -> std::io::Result<UdpStream> {
-    Ok( UdpStream { socket: try!( self.socket.try_clone() )
-                  , target: self.target.clone()
-                  , sender: self.sender.clone()} )
+    Ok( UdpStream { transport: try!( self.transport.try_clone() )
+                  , target   : self.target.clone()
+                  , sender   : self.sender.clone()} )
   }
 }
 
 impl Read for UdpStream {
   fn read( &mut self, buf : &mut [u8] ) -> std::io::Result<usize> {
-    match try!( self.socket.recv_from( buf ) ) {
+    match try!( self.transport.recv_from( buf ) ) {
       (r, a) => {
         self.sender = Some( a );
         Ok( r )
@@ -39,7 +40,7 @@ impl Write for UdpStream {
   fn write( &mut self, buf : &[u8] ) -> std::io::Result<usize> {
     match self.target {
       Some( target ) => {
-        self.socket.send_to( buf, target )
+        self.transport.send_to( buf, target )
       },
       None => panic!( "No target selected for UdpStream::write" )
     }
@@ -1,38 +1,75 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use packet::Packet;
+use transport::Transport;
+use archetype::HeroBook;
+use item::ItemBook;
 
-struct VerboseStream {
-  inner : TcpStream
+/// Wraps a transport and traces every datagram going through it, decoded
+/// into a `Packet` when possible. The tracing can be switched on and off at
+/// any time through the shared flag.
+pub struct VerboseTransport {
+  inner   : Box<Transport>,
+  enabled : Arc<AtomicBool>,
+  // Needed to decode heroes and their items
+  heroes  : Arc<HeroBook>,
+  items   : Arc<ItemBook>
 }
 
-impl Read for VerboseStream {
-  fn read( &mut self, buf : &mut [u8] ) -> std::io::Result<usize> {
-    let r = self.inner.read( buf );
-    match r {
-      Ok( ref a ) => println!("VerboseStream: read {:?} bytes.", a ),
-      Err( ref f ) => println!("VerboseStream: while reading: {:?}", f )
+impl VerboseTransport {
+  pub fn new( inner : Box<Transport>, enabled : Arc<AtomicBool>
+            , heroes : Arc<HeroBook>, items : Arc<ItemBook> )
+    -> VerboseTransport {
+    VerboseTransport { inner   : inner
+                     , enabled : enabled
+                     , heroes  : heroes
+                     , items   : items }
+  }
+
+  fn trace( &self, direction : &str, peer : SocketAddr, data : &[u8] ) {
+    if !self.enabled.load( Ordering::Relaxed ) {
+      return
     }
 
-    r
+    match Packet::decode( data, &self.heroes, &self.items ) {
+      Ok( (sequence, p) ) =>
+        info!( Packet, "{} {} ({} bytes): #{} {:?}"
+             , direction, peer, data.len(), sequence, p ),
+      // Could be garbage, or from a peer with different heroes or items
+      Err( _ ) =>
+        info!( Packet, "{} {} ({} bytes): <not a packet>"
+             , direction, peer, data.len() )
+    }
   }
 }
 
-impl Write for VerboseStream {
-  fn write( &mut self, buf: &[u8] ) -> std::io::Result<usize> {
-    let r = self.inner.write( buf );
+impl Transport for VerboseTransport {
+  fn send_to( &mut self, buf : &[u8], peer : SocketAddr ) -> io::Result<usize> {
+    let r = self.inner.send_to( buf, peer );
     match r {
-      Ok( ref a ) => println!("VerboseStream: wrote {:?} bytes.", a ),
-      Err( ref f ) => println!("VerboseStream: while writing: {:?}", f )
+      Ok( n ) => self.trace( "sent to", peer, &buf[..n] ),
+      Err( ref f ) => warn!( Net, "While sending to {}: {:?}", peer, f )
     }
 
     r
   }
 
-  fn flush( &mut self ) -> std::io::Result<()> {
-    let r = self.inner.flush();
+  fn recv_from( &mut self, buf : &mut [u8] ) -> io::Result<(usize, SocketAddr)> {
+    let r = self.inner.recv_from( buf );
     match r {
-      Ok( _ ) => println!("VerboseStream: flushed." ),
-      Err( ref f ) => println!("VerboseStream: while flushing: {:?}", f )
+      Ok( (n, peer) ) => self.trace( "received from", peer, &buf[..n] ),
+      Err( ref f ) => warn!( Net, "While receiving: {:?}", f )
     }
 
     r
   }
+
+  fn try_clone( &self ) -> io::Result<Box<Transport>> {
+    Ok( Box::new( VerboseTransport { inner   : try!( self.inner.try_clone() )
+                                   , enabled : self.enabled.clone()
+                                   , heroes  : self.heroes.clone()
+                                   , items   : self.items.clone() } ) )
+  }
 }
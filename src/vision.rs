@@ -0,0 +1,168 @@
+use std::collections::HashSet;
+use cgmath::{Point, EuclideanVector};
+use entity::Pos2;
+use map::Map;
+use team::{Team, TEAMS};
+use world::{World, Actor, EntityId};
+
+// How far units see, walls permitting
+pub const HERO_SIGHT : f32 = 220.0;
+pub const MINION_SIGHT : f32 = 160.0;
+pub const STRUCTURE_SIGHT : f32 = 200.0;
+// Size of the squares the fog is made of
+pub const FOG_CELL : f32 = 16.0;
+
+/// How far an actor sees, dead heroes and projectiles don't.
+pub fn sight_radius( actor : &Actor ) -> f32 {
+  match *actor {
+    Actor::Hero( ref h ) if h.is_alive() => HERO_SIGHT,
+    Actor::Minion( _ ) => MINION_SIGHT,
+    Actor::Structure( _ ) => STRUCTURE_SIGHT,
+    Actor::Hero( _ ) | Actor::Projectile( _ ) => 0.0
+  }
+}
+
+/// Where the team's units see from and how far.
+fn observers( world : &World, team : Team ) -> Vec<(Pos2, f32)> {
+  world.iter()
+       .filter( |&(_, a)| a.team() == Some( team ) )
+       .map( |(_, a)| ( a.entity().pos, sight_radius( a ) ) )
+       .filter( |&(_, r)| r > 0.0 )
+       .collect()
+}
+
+fn in_sight( map : &Map, observers : &[(Pos2, f32)], pos : Pos2 ) -> bool {
+  observers.iter().any( |&(o, r)| o.sub_p( &pos ).length() <= r
+                                  && map.line_of_sight( o, pos ) )
+}
+
+/// Whether the team can see the actor given where its units are. Its own
+/// side is always in view and so are structures, they don't go anywhere.
+fn visible( map : &Map, observers : &[(Pos2, f32)], team : Team
+          , actor : &Actor ) -> bool {
+  match *actor {
+    Actor::Structure( _ ) => true,
+    _ if actor.team() == Some( team ) => true,
+    _ => in_sight( map, observers, actor.entity().pos )
+  }
+}
+
+/// Everything the team can see right now.
+pub fn visible_to( world : &World, map : &Map, team : Team )
+  -> HashSet<EntityId> {
+  let observers = observers( world, team );
+
+  world.iter()
+       .filter( |&(_, a)| visible( map, &observers, team, a ) )
+       .map( |(&id, _)| id )
+       .collect()
+}
+
+/// What each team can see, kept by the host to only tell clients about
+/// what their team can see.
+#[derive(Clone, Debug)]
+pub struct Vision {
+  // Indexed by team
+  seen : Vec<HashSet<EntityId>>
+}
+
+impl Vision {
+  /// Every team seeing everything in the world, which is what everyone
+  /// starts out with.
+  pub fn everything( world : &World ) -> Vision {
+    let all = world.ids().into_iter().collect::<HashSet<_>>();
+    Vision { seen : TEAMS.iter().map( |_| all.clone() ).collect() }
+  }
+
+  pub fn compute( world : &World, map : &Map ) -> Vision {
+    Vision { seen : TEAMS.iter()
+                         .map( |&t| visible_to( world, map, t ) )
+                         .collect() }
+  }
+
+  pub fn sees( &self, team : Team, id : EntityId ) -> bool {
+    self.seen[team.index() as usize].contains( &id )
+  }
+
+  /// What the team sees now that it didn't in `before`.
+  pub fn revealed( &self, before : &Vision, team : Team ) -> Vec<EntityId> {
+    let i = team.index() as usize;
+    self.seen[i].difference( &before.seen[i] ).cloned().collect()
+  }
+
+  /// What the team saw in `before` but doesn't anymore.
+  pub fn concealed( &self, before : &Vision, team : Team ) -> Vec<EntityId> {
+    before.revealed( self, team )
+  }
+}
+
+/// The fog over the map for one team. Squares none of its units see right
+/// now are covered, a bit less so once they've been seen.
+pub struct Fog {
+  team     : Team,
+  columns  : usize,
+  rows     : usize,
+  visible  : Vec<bool>,
+  explored : Vec<bool>
+}
+
+impl Fog {
+  pub fn new( map : &Map, team : Team ) -> Fog {
+    let columns = ( map.size.x / FOG_CELL ).ceil() as usize;
+    let rows = ( map.size.y / FOG_CELL ).ceil() as usize;
+
+    Fog { team     : team
+        , columns  : columns
+        , rows     : rows
+        , visible  : vec![ false; columns * rows ]
+        , explored : vec![ false; columns * rows ] }
+  }
+
+  pub fn team( &self ) -> Team {
+    self.team
+  }
+
+  pub fn update( &mut self, world : &World, map : &Map ) {
+    let observers = observers( world, self.team );
+
+    for row in 0..self.rows {
+      for col in 0..self.columns {
+        let i = row * self.columns + col;
+        let center = Pos2::new( ( col as f32 + 0.5 ) * FOG_CELL
+                              , ( row as f32 + 0.5 ) * FOG_CELL );
+
+        self.visible[i] = in_sight( map, &observers, center );
+        self.explored[i] = self.explored[i] || self.visible[i];
+      }
+    }
+  }
+
+  pub fn is_visible( &self, pos : Pos2 ) -> bool {
+    if pos.x < 0.0 || pos.y < 0.0 {
+      return false
+    }
+
+    let (col, row) = ( ( pos.x / FOG_CELL ) as usize
+                     , ( pos.y / FOG_CELL ) as usize );
+    col < self.columns && row < self.rows
+      && self.visible[row * self.columns + col]
+  }
+
+  /// The squares to cover, and whether they've been seen before.
+  pub fn covered( &self ) -> Vec<([f64; 4], bool)> {
+    let size = FOG_CELL as f64;
+    let mut cells = Vec::new();
+
+    for row in 0..self.rows {
+      for col in 0..self.columns {
+        let i = row * self.columns + col;
+        if !self.visible[i] {
+          cells.push( ( [ col as f64 * size, row as f64 * size, size, size ]
+                      , self.explored[i] ) );
+        }
+      }
+    }
+
+    cells
+  }
+}
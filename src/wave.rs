@@ -0,0 +1,72 @@
+use cgmath::{Point, Vector, EuclideanVector};
+use entity::{Minion, Pos2};
+use map::{Map, Lane};
+use team::{Team, TEAMS};
+
+// Seconds between waves, and before the first one of the match
+pub const WAVE_INTERVAL : f64 = 30.0;
+pub const FIRST_WAVE_DELAY : f64 = 5.0;
+// Minions per lane in each wave, and how far apart they walk
+pub const WAVE_SIZE : usize = 3;
+pub const WAVE_SPACING : f32 = 12.0;
+
+/// Counts down to each team's next wave, only the host keeps one.
+pub struct WaveTimer {
+  timers : [f64; 2]
+}
+
+impl WaveTimer {
+  pub fn new() -> WaveTimer {
+    WaveTimer { timers : [FIRST_WAVE_DELAY; 2] }
+  }
+
+  /// Steps the timers, returns the teams whose wave is due.
+  pub fn update( &mut self, delta_time : f64 ) -> Vec<Team> {
+    let mut due = Vec::new();
+
+    for &team in TEAMS.iter() {
+      let t = &mut self.timers[team.index() as usize];
+      *t -= delta_time;
+
+      if *t <= 0.0 {
+        *t += WAVE_INTERVAL;
+        due.push( team );
+      }
+    }
+
+    due
+  }
+}
+
+/// The lane's waypoints in the order the team walks them.
+fn lane_for( lane : &Lane, team : Team ) -> Vec<Pos2> {
+  let mut waypoints = lane.waypoints.clone();
+
+  if team == Team::Red {
+    waypoints.reverse();
+  }
+
+  waypoints
+}
+
+/// A wave of minions for `team`, `size` of them down every lane of the map.
+/// They line up behind the start of the lane so they walk it single file.
+pub fn wave( map : &Map, team : Team, size : usize ) -> Vec<Minion> {
+  let mut minions = Vec::new();
+
+  for lane in &map.lanes {
+    let waypoints = lane_for( lane, team );
+    let start = waypoints[0];
+    let back = start.sub_p( &waypoints[1] ).normalize();
+
+    for i in 0..size {
+      let pos = start.add_v( &back.mul_s( i as f32 * WAVE_SPACING ) );
+      let mut m = Minion::new( pos, team );
+      m.waypoints = waypoints.clone();
+      m.follow_lane();
+      minions.push( m );
+    }
+  }
+
+  minions
+}
@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use cgmath::{Point, EuclideanVector};
+use entity::{Entity, Health, Attack, Hero, Minion, Structure, Pos2};
+use projectile::Projectile;
+use status::Statuses;
+use team::Team;
+
+/// Identifies an entity across the network, assigned by the host and never
+/// reused within a match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EntityId( pub u32 );
+
+#[derive(Clone, Debug)]
+pub enum Actor {
+  Hero( Hero ),
+  Minion( Minion ),
+  Structure( Structure ),
+  Projectile( Projectile )
+}
+
+impl Actor {
+  pub fn entity( &self ) -> &Entity {
+    match *self {
+      Actor::Hero( ref h )       => &h.entity,
+      Actor::Minion( ref m )     => &m.entity,
+      Actor::Structure( ref s )  => &s.entity,
+      Actor::Projectile( ref p ) => &p.entity
+    }
+  }
+
+  pub fn entity_mut( &mut self ) -> &mut Entity {
+    match *self {
+      Actor::Hero( ref mut h )       => &mut h.entity,
+      Actor::Minion( ref mut m )     => &mut m.entity,
+      Actor::Structure( ref mut s )  => &mut s.entity,
+      Actor::Projectile( ref mut p ) => &mut p.entity
+    }
+  }
+
+  pub fn team( &self ) -> Option<Team> {
+    match *self {
+      Actor::Hero( ref h )       => Some( h.team ),
+      Actor::Minion( ref m )     => Some( m.team ),
+      Actor::Structure( ref s )  => Some( s.team ),
+      Actor::Projectile( ref p ) => Some( p.team )
+    }
+  }
+
+  pub fn health( &self ) -> Option<&Health> {
+    match *self {
+      Actor::Hero( ref h )      => Some( &h.health ),
+      Actor::Minion( ref m )    => Some( &m.health ),
+      Actor::Structure( ref s ) => Some( &s.health ),
+      Actor::Projectile( .. )   => None
+    }
+  }
+
+  pub fn health_mut( &mut self ) -> Option<&mut Health> {
+    match *self {
+      Actor::Hero( ref mut h )      => Some( &mut h.health ),
+      Actor::Minion( ref mut m )    => Some( &mut m.health ),
+      Actor::Structure( ref mut s ) => Some( &mut s.health ),
+      Actor::Projectile( .. )       => None
+    }
+  }
+
+  pub fn attack( &self ) -> Option<&Attack> {
+    match *self {
+      Actor::Hero( ref h )      => Some( &h.attack ),
+      Actor::Minion( ref m )    => Some( &m.attack ),
+      Actor::Structure( ref s ) => s.attack.as_ref(),
+      Actor::Projectile( .. )   => None
+    }
+  }
+
+  pub fn attack_mut( &mut self ) -> Option<&mut Attack> {
+    match *self {
+      Actor::Hero( ref mut h )      => Some( &mut h.attack ),
+      Actor::Minion( ref mut m )    => Some( &mut m.attack ),
+      Actor::Structure( ref mut s ) => s.attack.as_mut(),
+      Actor::Projectile( .. )       => None
+    }
+  }
+
+  /// What's affecting the unit, only heroes and minions are affected by
+  /// statuses.
+  pub fn statuses( &self ) -> Option<&Statuses> {
+    match *self {
+      Actor::Hero( ref h )   => Some( &h.statuses ),
+      Actor::Minion( ref m ) => Some( &m.statuses ),
+      _ => None
+    }
+  }
+
+  pub fn statuses_mut( &mut self ) -> Option<&mut Statuses> {
+    match *self {
+      Actor::Hero( ref mut h )   => Some( &mut h.statuses ),
+      Actor::Minion( ref mut m ) => Some( &mut m.statuses ),
+      _ => None
+    }
+  }
+
+  pub fn is_stunned( &self ) -> bool {
+    self.statuses().map( |s| s.is_stunned() ).unwrap_or( false )
+  }
+
+  /// Goes after the attack target at `target_pos`, returning whether it
+  /// attacked, see `Attack::pursue` and `Structure::pursue`.
+  pub fn pursue( &mut self, target_pos : Pos2 ) -> bool {
+    match *self {
+      Actor::Hero( ref mut h )      => h.pursue( target_pos ),
+      Actor::Minion( ref mut m )    => m.pursue( target_pos ),
+      Actor::Structure( ref mut s ) => s.pursue( target_pos ),
+      Actor::Projectile( .. )       => false
+    }
+  }
+
+  /// Whether this can be attacked and hit, i.e. it has health and is alive.
+  pub fn is_targetable( &self ) -> bool {
+    match *self {
+      Actor::Hero( ref h ) => h.is_alive(),
+      _ => self.health().map( |h| !h.is_depleted() ).unwrap_or( false )
+    }
+  }
+}
+
+/// Every entity in the game, keyed by their `EntityId`. Iteration goes in
+/// ID order so that every peer steps the world the same way.
+pub struct World {
+  next_id : u32,
+  actors  : BTreeMap<EntityId, Actor>
+}
+
+impl World {
+  pub fn new() -> World {
+    World { next_id : 0, actors : BTreeMap::new() }
+  }
+
+  /// Adds an actor under a fresh ID.
+  pub fn spawn( &mut self, actor : Actor ) -> EntityId {
+    let id = EntityId( self.next_id );
+    self.next_id += 1;
+    self.actors.insert( id, actor );
+    id
+  }
+
+  /// Adds or replaces an actor under an ID someone else picked, used for
+  /// the spawns the host tells us about.
+  pub fn insert( &mut self, id : EntityId, actor : Actor ) {
+    if id.0 >= self.next_id {
+      self.next_id = id.0.saturating_add( 1 );
+    }
+
+    self.actors.insert( id, actor );
+  }
+
+  pub fn despawn( &mut self, id : EntityId ) -> Option<Actor> {
+    self.actors.remove( &id )
+  }
+
+  pub fn get( &self, id : EntityId ) -> Option<&Actor> {
+    self.actors.get( &id )
+  }
+
+  pub fn get_mut( &mut self, id : EntityId ) -> Option<&mut Actor> {
+    self.actors.get_mut( &id )
+  }
+
+  pub fn hero( &self, id : EntityId ) -> Option<&Hero> {
+    match self.actors.get( &id ) {
+      Some( &Actor::Hero( ref h ) ) => Some( h ),
+      _ => None
+    }
+  }
+
+  pub fn hero_mut( &mut self, id : EntityId ) -> Option<&mut Hero> {
+    match self.actors.get_mut( &id ) {
+      Some( &mut Actor::Hero( ref mut h ) ) => Some( h ),
+      _ => None
+    }
+  }
+
+  pub fn projectile_mut( &mut self, id : EntityId ) -> Option<&mut Projectile> {
+    match self.actors.get_mut( &id ) {
+      Some( &mut Actor::Projectile( ref mut p ) ) => Some( p ),
+      _ => None
+    }
+  }
+
+  pub fn pos( &self, id : EntityId ) -> Option<Pos2> {
+    self.actors.get( &id ).map( |a| a.entity().pos )
+  }
+
+  /// A snapshot of the IDs, so the world can be changed while going
+  /// through them.
+  pub fn ids( &self ) -> Vec<EntityId> {
+    self.actors.keys().cloned().collect()
+  }
+
+  pub fn iter( &self ) -> btree_map::Iter<EntityId, Actor> {
+    self.actors.iter()
+  }
+
+  pub fn iter_mut( &mut self ) -> btree_map::IterMut<EntityId, Actor> {
+    self.actors.iter_mut()
+  }
+
+  /// The first targetable actor within `radius` of `pos`.
+  pub fn targetable_at( &self, pos : Pos2, radius : f32 ) -> Option<EntityId> {
+    self.actors.iter()
+               .find( |&(_, a)| a.is_targetable()
+                             && a.entity().pos.sub_p( &pos ).length() < radius )
+               .map( |(id, _)| *id )
+  }
+}